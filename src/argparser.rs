@@ -1,90 +1,440 @@
-#[derive(Debug, PartialEq)]
+// 引数を解析する
+// ./port_snippet [GLOBAL OPTIONS] <COMMAND> [COMMAND OPTIONS]
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum LaunchType {
-    Man,     // 直接人間が起動させた場合
-    Daemon,  // cron処理でpcが起動させた場合
-    Stop,    // PortSnippetを停止する
-    Restart, // PortSnippetを再起動する
-    Help,    // help
-}
-
-struct Params {
-    man: String,
-    daemon: String,
-    stop: String,
-    restart: String,
-    help: String,
-}
-
-const AUTO_LAUNCH_PARAM: &str = "AUTO_LAUNCH";
-const MAN_PARAM: &str = "man";
-const STOP_PARAM: &str = "stop";
-const RESTART_PARAM: &str = "restart";
-const HELP_PARAM: &str = "help";
-
-// パラメータ(引数)からLaunchTypeを特定する
-
-#[cfg(debug_assertions)] // デバッグ用
-pub fn detect_type(_args: Vec<String>) -> LaunchType {
-    return LaunchType::Daemon;
-}
-
-#[cfg(not(debug_assertions))] // リリース用
-pub fn detect_type(args: Vec<String>) -> LaunchType {
-    if args.len() != 2 {
-        return LaunchType::Man;
-    }
-    if args[1] == AUTO_LAUNCH_PARAM {
-        return LaunchType::Daemon;
-    }
-
-    for i in 0..2 {
-        let short = i == 0;
-        let params = get_params(short);
-        let man = params.man.as_str();
-        let stop = params.stop.as_str();
-        let restart = params.restart.as_str();
-        let help = params.help.as_str();
-
-        if &args[1] == man {
-            return LaunchType::Daemon;
-        } else if &args[1] == stop {
-            return LaunchType::Stop;
-        } else if &args[1] == restart {
-            return LaunchType::Restart;
-        } else if &args[1] == help {
-            return LaunchType::Help;
+    Install,                 // デーモンとして登録して起動する (引数なしで起動した場合)
+    Uninstall,               // デーモンの登録を解除する
+    Daemon,                  // フォアグラウンドで監視する (AUTO_LAUNCHもこれ)
+    Start,                   // 登録済みのデーモンを起動する
+    Stop,                    // PortSnippetを停止する
+    Restart,                 // PortSnippetを再起動する
+    Status { json: bool },   // 起動状態を表示する
+    Build,                   // すべてのスニペットを生成して終了する
+    Diff,                    // buildで何が変わるかを表示する (何も書き込まない)
+    Check { json: bool },    // メタタグを検査する
+    List { json: bool },     // 生成済みのスニペットを一覧表示する
+    Prefixes { json: bool }, // prefixが被っているスニペットを表示する
+    Reload,                  // 起動中のPortSnippetにconfigを読み直させる
+    Rescan,                  // 起動中のPortSnippetに再走査させる
+    Pause,                   // 起動中のPortSnippetを一時停止する
+    Resume,                  // 一時停止を解除する
+    Help(Option<String>),    // help (コマンド名があればそのコマンドのhelp)
+    // <lang>.jsonをバックアップから戻す (langがなければ一覧表示, indexは1が最新)
+    Restore { lang: Option<String>, index: usize },
+}
+
+// どのコマンドでも使えるオプション
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Options {
+    pub config: Option<String>,       // --config <path>
+    pub snippets_dir: Option<String>, // --snippets-dir <dir>
+    pub verbose: bool,                // -v, --verbose
+    pub dry_run: bool,                // --dry-run
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Args {
+    pub launch_type: LaunchType,
+    pub options: Options,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    UnknownCommand(String),
+    UnknownFlag { command: String, flag: String },
+    MissingValue(String),
+    UnexpectedArgument { command: String, arg: String },
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        return match self {
+            ParseError::UnknownCommand(command) => write!(f, "unknown command '{}'", command),
+            ParseError::UnknownFlag { command, flag } => {
+                write!(f, "unknown option '{}' for '{}'", flag, command)
+            }
+            ParseError::MissingValue(flag) => write!(f, "'{}' needs a value", flag),
+            ParseError::UnexpectedArgument { command, arg } => {
+                write!(f, "unexpected argument '{}' for '{}'", arg, command)
+            }
+        };
+    }
+}
+
+pub const AUTO_LAUNCH_PARAM: &str = "AUTO_LAUNCH"; // launchd / systemd / scから起動するときの引数
+
+const CONFIG_FLAG: &str = "--config";
+const SNIPPETS_DIR_FLAG: &str = "--snippets-dir";
+const JSON_FLAG: &str = "--json";
+const DRY_RUN_FLAG: &str = "--dry-run";
+
+// コマンドの定義 (helpもここから生成する)
+struct CommandSpec {
+    name: &'static str,
+    aliases: &'static [&'static str],
+    usage: &'static str,
+    summary: &'static str,
+    flags: &'static [(&'static str, &'static str)],
+}
+
+const JSON_FLAGS: &[(&str, &str)] = &[(JSON_FLAG, "print as JSON.")];
+const DRY_RUN_FLAGS: &[(&str, &str)] = &[(DRY_RUN_FLAG, "don't write any file.")];
+
+const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "install",
+        aliases: &[],
+        usage: "",
+        summary: "register portsnippet as a daemon and start it. (default)",
+        flags: &[],
+    },
+    CommandSpec {
+        name: "uninstall",
+        aliases: &[],
+        usage: "",
+        summary: "stop the daemon and unregister it.",
+        flags: &[],
+    },
+    CommandSpec {
+        name: "run",
+        aliases: &["foreground", "man", "-m"],
+        usage: "",
+        summary: "run portsnippet as a foreground process.",
+        flags: &[],
+    },
+    CommandSpec {
+        name: "start",
+        aliases: &[],
+        usage: "",
+        summary: "start the registered daemon.",
+        flags: &[],
+    },
+    CommandSpec {
+        name: "stop",
+        aliases: &["-s"],
+        usage: "",
+        summary: "stop a background portsnippet's process.",
+        flags: &[],
+    },
+    CommandSpec {
+        name: "restart",
+        aliases: &["-r"],
+        usage: "",
+        summary: "restart the daemon process, e.g. after an upgrade (reload only rereads config).",
+        flags: &[],
+    },
+    CommandSpec {
+        name: "status",
+        aliases: &[],
+        usage: "[--json]",
+        summary: "print whether portsnippet is running, watched paths and last sync results.",
+        flags: JSON_FLAGS,
+    },
+    CommandSpec {
+        name: "build",
+        aliases: &[],
+        usage: "[--dry-run]",
+        summary: "generate all snippets once and exit.",
+        flags: DRY_RUN_FLAGS,
+    },
+    CommandSpec {
+        name: "diff",
+        aliases: &[],
+        usage: "",
+        summary: "show what build would change, without writing any file. (= build --dry-run)",
+        flags: DRY_RUN_FLAGS,
+    },
+    CommandSpec {
+        name: "check",
+        aliases: &[],
+        usage: "[--json]",
+        summary: "check meta tags in all watched files.",
+        flags: JSON_FLAGS,
+    },
+    CommandSpec {
+        name: "list",
+        aliases: &[],
+        usage: "[--json]",
+        summary: "list generated snippets.",
+        flags: JSON_FLAGS,
+    },
+    CommandSpec {
+        name: "prefixes",
+        aliases: &[],
+        usage: "[--json]",
+        summary: "report snippets whose prefixes collide or shadow each other.",
+        flags: JSON_FLAGS,
+    },
+    CommandSpec {
+        name: "reload",
+        aliases: &[],
+        usage: "",
+        summary: "make a running portsnippet reload config.json.",
+        flags: &[],
+    },
+    CommandSpec {
+        name: "rescan",
+        aliases: &[],
+        usage: "",
+        summary: "make a running portsnippet scan all files again.",
+        flags: &[],
+    },
+    CommandSpec {
+        name: "pause",
+        aliases: &[],
+        usage: "",
+        summary: "pause generating snippets.",
+        flags: &[],
+    },
+    CommandSpec {
+        name: "resume",
+        aliases: &[],
+        usage: "",
+        summary: "resume generating snippets.",
+        flags: &[],
+    },
+    CommandSpec {
+        name: "restore",
+        aliases: &[],
+        usage: "[LANG [N]] [--dry-run]",
+        summary: "restore <LANG>.json from the N-th newest backup, or list backups.",
+        flags: DRY_RUN_FLAGS,
+    },
+    CommandSpec {
+        name: "help",
+        aliases: &["-h", "--help"],
+        usage: "[COMMAND]",
+        summary: "print this help messages, or the help of COMMAND.",
+        flags: &[],
+    },
+];
+
+const GLOBAL_FLAGS: &[(&str, &str)] = &[
+    (
+        "--config <path>",
+        "use this config.json instead of the one next to the exe.",
+    ),
+    (
+        "--snippets-dir <dir>",
+        "override \"snippets_dir\" in config.json.",
+    ),
+    ("-v, --verbose", "print debug logs."),
+    ("-h, --help", "print help."),
+];
+
+fn find_command(name: &str) -> Option<&'static CommandSpec> {
+    return COMMANDS
+        .iter()
+        .find(|spec| spec.name == name || spec.aliases.contains(&name));
+}
+
+fn to_launch_type(spec: &CommandSpec, json: bool) -> LaunchType {
+    return match spec.name {
+        "install" => LaunchType::Install,
+        "uninstall" => LaunchType::Uninstall,
+        "run" => LaunchType::Daemon,
+        "start" => LaunchType::Start,
+        "stop" => LaunchType::Stop,
+        "restart" => LaunchType::Restart,
+        "status" => LaunchType::Status { json: json },
+        "build" => LaunchType::Build,
+        "diff" => LaunchType::Diff,
+        "check" => LaunchType::Check { json: json },
+        "list" => LaunchType::List { json: json },
+        "prefixes" => LaunchType::Prefixes { json: json },
+        "reload" => LaunchType::Reload,
+        "rescan" => LaunchType::Rescan,
+        "pause" => LaunchType::Pause,
+        "resume" => LaunchType::Resume,
+        _ => LaunchType::Help(None),
+    };
+}
+
+// パラメータ(引数)を解析する
+pub fn parse(args: Vec<String>) -> Result<Args, ParseError> {
+    let mut options = Options::default();
+    let mut command: Option<&CommandSpec> = None;
+    let mut positionals: Vec<String> = vec![];
+    let mut json = false;
+    let mut help = false;
+
+    let mut iter = args.into_iter().skip(1); // 先頭はexeのパス
+    while let Some(arg) = iter.next() {
+        // --config=path のような形式
+        if let Some((flag, value)) = split_flag_value(&arg) {
+            match flag {
+                CONFIG_FLAG => options.config = Some(value.to_string()),
+                SNIPPETS_DIR_FLAG => options.snippets_dir = Some(value.to_string()),
+                _ => {
+                    return Err(ParseError::UnknownFlag {
+                        command: command_name(command),
+                        flag: arg.clone(),
+                    });
+                }
+            }
+            continue;
+        }
+
+        match arg.as_str() {
+            CONFIG_FLAG | SNIPPETS_DIR_FLAG => {
+                let value = match iter.next() {
+                    Some(value) => value,
+                    None => return Err(ParseError::MissingValue(arg)),
+                };
+                if arg == CONFIG_FLAG {
+                    options.config = Some(value);
+                } else {
+                    options.snippets_dir = Some(value);
+                }
+            }
+            "-v" | "--verbose" => options.verbose = true,
+            DRY_RUN_FLAG => options.dry_run = true,
+            JSON_FLAG => json = true,
+            AUTO_LAUNCH_PARAM if command.is_none() => command = find_command("run"),
+            "-h" | "--help" if command.is_some() => help = true,
+            _ => {
+                if command.is_none() {
+                    match find_command(&arg) {
+                        Some(spec) => command = Some(spec),
+                        None if arg.starts_with('-') => {
+                            return Err(ParseError::UnknownFlag {
+                                command: command_name(command),
+                                flag: arg,
+                            });
+                        }
+                        None => return Err(ParseError::UnknownCommand(arg)),
+                    }
+                } else if arg.starts_with('-') {
+                    return Err(ParseError::UnknownFlag {
+                        command: command_name(command),
+                        flag: arg,
+                    });
+                } else {
+                    positionals.push(arg);
+                }
+            }
+        }
+    }
+
+    // 引数なしの場合はデーモンとして登録する
+    let spec = match command {
+        Some(spec) => spec,
+        None => find_command("install").unwrap(),
+    };
+
+    // コマンドごとのフラグ
+    for &(used, name) in [(json, JSON_FLAG), (options.dry_run, DRY_RUN_FLAG)].iter() {
+        if used && !spec.flags.iter().any(|(flag, _)| *flag == name) {
+            return Err(ParseError::UnknownFlag {
+                command: spec.name.to_string(),
+                flag: name.to_string(),
+            });
+        }
+    }
+
+    let launch_type = if spec.name == "help" {
+        if positionals.len() > 1 {
+            return Err(ParseError::UnexpectedArgument {
+                command: spec.name.to_string(),
+                arg: positionals[1].clone(),
+            });
+        }
+        match positionals.first() {
+            Some(name) => match find_command(name) {
+                Some(target) => LaunchType::Help(Some(target.name.to_string())),
+                None => return Err(ParseError::UnknownCommand(name.clone())),
+            },
+            None => LaunchType::Help(None),
+        }
+    } else if spec.name == "restore" && !help {
+        to_restore(spec, &positionals)?
+    } else {
+        if let Some(arg) = positionals.first() {
+            return Err(ParseError::UnexpectedArgument {
+                command: spec.name.to_string(),
+                arg: arg.clone(),
+            });
+        }
+        if help {
+            LaunchType::Help(Some(spec.name.to_string()))
+        } else {
+            to_launch_type(spec, json)
         }
+    };
+
+    return Ok(Args {
+        launch_type: launch_type,
+        options: options,
+    });
+}
+
+// restore [LANG [N]]
+fn to_restore(spec: &CommandSpec, positionals: &[String]) -> Result<LaunchType, ParseError> {
+    let unexpected = |arg: &String| ParseError::UnexpectedArgument {
+        command: spec.name.to_string(),
+        arg: arg.clone(),
+    };
+    if positionals.len() > 2 {
+        return Err(unexpected(&positionals[2]));
     }
 
-    return LaunchType::Man;
+    let index = match positionals.get(1) {
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(index) if index > 0 => index,
+            _ => return Err(unexpected(arg)),
+        },
+        None => 1,
+    };
+    return Ok(LaunchType::Restore {
+        lang: positionals.first().cloned(),
+        index: index,
+    });
+}
+
+fn command_name(command: Option<&CommandSpec>) -> String {
+    return match command {
+        Some(spec) => spec.name.to_string(),
+        None => String::from("port_snippet"),
+    };
 }
 
-// パラメータ一覧を取得
-fn get_params(short: bool) -> Params {
-    let mut man = MAN_PARAM.to_string();
-    let mut daemon = AUTO_LAUNCH_PARAM.to_string();
-    let mut stop = STOP_PARAM.to_string();
-    let mut restart = RESTART_PARAM.to_string();
-    let mut help = HELP_PARAM.to_string();
+fn split_flag_value(arg: &str) -> Option<(&str, &str)> {
+    if !arg.starts_with("--") {
+        return None;
+    }
+    let index = arg.find('=')?;
+    return Some((&arg[..index], &arg[index + 1..]));
+}
 
-    if short {
-        man = format!("-{}", man.chars().take(1).collect::<String>());
-        daemon = format!("-{}", daemon.chars().take(1).collect::<String>());
-        stop = format!("-{}", stop.chars().take(1).collect::<String>());
-        restart = format!("-{}", restart.chars().take(1).collect::<String>());
-        help = format!("-{}", help.chars().take(1).collect::<String>());
+// デーモンとして起動するときに引き継ぐ引数 (AUTO_LAUNCHの後ろに付ける)
+pub fn get_daemon_args(options: &Options) -> Vec<String> {
+    let mut args = vec![];
+    if let Some(config) = &options.config {
+        args.push(CONFIG_FLAG.to_string());
+        args.push(to_absolute(config));
+    }
+    if let Some(snippets_dir) = &options.snippets_dir {
+        args.push(SNIPPETS_DIR_FLAG.to_string());
+        args.push(to_absolute(snippets_dir));
     }
+    if options.verbose {
+        args.push(String::from("--verbose"));
+    }
+    return args;
+}
 
-    return Params {
-        man: man,
-        daemon: daemon,
-        stop: stop,
-        restart: restart,
-        help: help,
+// デーモンはカレントディレクトリが変わるので絶対パスにしておく
+fn to_absolute(path: &str) -> String {
+    return match std::fs::canonicalize(path) {
+        Ok(path) => path.to_string_lossy().to_string(),
+        Err(_) => path.to_string(),
     };
 }
 
-const HELP_MESSAGE: &str = r#"
+///// Help
+
+const BANNER: &str = r#"
 /$$$$$$$                       /$$      /$$$$$$            /$$                                 /$$    
 | $$__  $$                     | $$     /$$__  $$          |__/                                | $$    
 | $$  \ $$ /$$$$$$   /$$$$$$  /$$$$$$  | $$  \__/ /$$$$$$$  /$$  /$$$$$$   /$$$$$$   /$$$$$$  /$$$$$$  
@@ -97,20 +447,19 @@ const HELP_MESSAGE: &str = r#"
                                                                | $$      | $$                          
                                                                |__/      |__/                          
 
-> Dynamic Snippet for VSCode. 
+"#;
+
+const TAGLINE: &str = r#"> Dynamic Snippet for VSCode.
 > https://github.com/YuigaWada/PortSnippet
 > Dev: @YuigaWada
 
-usage: ./port_snippet [OPTION] ...
-
-OPTION: 
-    -m, man: run portsnippet as a foreground process.
-    -s, stop: stop a background portsnippet's processs.
-    -r, restart: restart a background portsnippet's processs.
-    -h, help: print this help messages.
-
+"#;
 
-config: You need put a config file on the same dir as the exe binary of portsnippet.
+// 設定ファイルとメタタグの書き方 (コマンド一覧の後に表示する)
+const GUIDE: &str = r#"
+CONFIG:
+    You need put a config file (config.json) on the same dir as the exe binary of portsnippet.
+    (or pass it with --config <path>)
 
     {
         "snippets_dir": "",
@@ -122,97 +471,431 @@ config: You need put a config file on the same dir as the exe binary of portsnip
         ]
     }
 
-meta tags: Put meta tags between your code that you want to save as a snippet!
+META TAGS:
+    Put meta tags between your code that you want to save as a snippet!
 
     // #PORT#
     // name: ""
     // prefix: ""
     // description: ""
-    // #PORT_END 
 
     ... Some Codes ...
 
-    // #PORT_END 
-
+    // #PORT_END#
 "#;
 
-// ヘルプを表示
-pub fn print_help() {
-    println!("{}",HELP_MESSAGE);
+// コマンド一覧のhelp
+pub fn get_help() -> String {
+    let mut text = String::from(BANNER);
+    text.push_str(TAGLINE);
+    text.push_str("USAGE:\n    ./port_snippet [OPTIONS] [COMMAND]\n\n");
+
+    text.push_str("COMMANDS:\n");
+    for spec in COMMANDS.iter() {
+        text.push_str(&format_row(&format_names(spec), spec.summary));
+    }
+
+    text.push_str("\nOPTIONS:\n");
+    for (flag, summary) in GLOBAL_FLAGS.iter() {
+        text.push_str(&format_row(flag, summary));
+    }
+    text.push_str("\nRun './port_snippet help <COMMAND>' for more information on a command.\n");
+    text.push_str(GUIDE);
+    return text;
+}
+
+// コマンドごとのhelp
+pub fn get_command_help(name: &str) -> Option<String> {
+    let spec = find_command(name)?;
+    let mut text = format!("{}\n\n", spec.summary);
+    text.push_str(&format!(
+        "USAGE:\n    ./port_snippet [OPTIONS] {} {}\n",
+        spec.name, spec.usage
+    ));
+
+    if !spec.aliases.is_empty() {
+        text.push_str(&format!("\nALIASES:\n    {}\n", spec.aliases.join(", ")));
+    }
+
+    if !spec.flags.is_empty() {
+        text.push_str("\nFLAGS:\n");
+        for (flag, summary) in spec.flags.iter() {
+            text.push_str(&format_row(flag, summary));
+        }
+    }
+
+    text.push_str("\nOPTIONS:\n");
+    for (flag, summary) in GLOBAL_FLAGS.iter() {
+        text.push_str(&format_row(flag, summary));
+    }
+    return Some(text);
+}
+
+fn format_names(spec: &CommandSpec) -> String {
+    let mut names = vec![spec.name];
+    names.extend(spec.aliases.iter());
+    return names.join(", ");
+}
+
+fn format_row(name: &str, summary: &str) -> String {
+    return format!("    {:<26}{}\n", name, summary);
+}
+
+pub fn print_help(command: Option<&str>) {
+    let help = match command {
+        Some(name) => get_command_help(name),
+        None => None,
+    };
+    match help {
+        Some(help) => print!("{}", help),
+        None => print!("{}", get_help()),
+    }
 }
 
+///// Unit Test
+
 #[cfg(test)]
-#[cfg(not(debug_assertions))]
 mod tests {
     use crate::argparser::*;
 
     // util
 
-    fn gen_mock_args(arg: &str) -> Vec<String> {
-        return vec!["MOCK_EXE_PATH".to_string(), arg.to_string()];
+    fn gen_mock_args(args: &[&str]) -> Vec<String> {
+        let mut mock_args = vec!["MOCK_EXE_PATH".to_string()];
+        mock_args.extend(args.iter().map(|arg| arg.to_string()));
+        return mock_args;
+    }
+
+    fn parse_type(args: &[&str]) -> LaunchType {
+        return parse(gen_mock_args(args)).unwrap().launch_type;
+    }
+
+    // commands
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parse_noArgs_install() {
+        assert_eq!(parse_type(&[]), LaunchType::Install);
+        assert_eq!(parse_type(&["install"]), LaunchType::Install);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parse_autoLaunch_daemon() {
+        assert_eq!(parse_type(&["AUTO_LAUNCH"]), LaunchType::Daemon);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parse_runAliases_daemon() {
+        assert_eq!(parse_type(&["run"]), LaunchType::Daemon);
+        assert_eq!(parse_type(&["foreground"]), LaunchType::Daemon);
+        assert_eq!(parse_type(&["man"]), LaunchType::Daemon);
+        assert_eq!(parse_type(&["-m"]), LaunchType::Daemon);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parse_service_valid() {
+        assert_eq!(parse_type(&["uninstall"]), LaunchType::Uninstall);
+        assert_eq!(parse_type(&["start"]), LaunchType::Start);
+        assert_eq!(parse_type(&["stop"]), LaunchType::Stop);
+        assert_eq!(parse_type(&["-s"]), LaunchType::Stop);
+        assert_eq!(parse_type(&["restart"]), LaunchType::Restart);
+        assert_eq!(parse_type(&["-r"]), LaunchType::Restart);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parse_control_valid() {
+        assert_eq!(parse_type(&["reload"]), LaunchType::Reload);
+        assert_eq!(parse_type(&["rescan"]), LaunchType::Rescan);
+        assert_eq!(parse_type(&["pause"]), LaunchType::Pause);
+        assert_eq!(parse_type(&["resume"]), LaunchType::Resume);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parse_oneShot_valid() {
+        assert_eq!(parse_type(&["build"]), LaunchType::Build);
+        assert_eq!(parse_type(&["diff"]), LaunchType::Diff);
+        assert_eq!(parse_type(&["check"]), LaunchType::Check { json: false });
+        assert_eq!(parse_type(&["list"]), LaunchType::List { json: false });
+        assert_eq!(
+            parse_type(&["prefixes", "--json"]),
+            LaunchType::Prefixes { json: true }
+        );
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parse_json_valid() {
+        assert_eq!(parse_type(&["status"]), LaunchType::Status { json: false });
+        assert_eq!(
+            parse_type(&["status", "--json"]),
+            LaunchType::Status { json: true }
+        );
+        assert_eq!(
+            parse_type(&["--json", "check"]),
+            LaunchType::Check { json: true }
+        );
+        assert_eq!(
+            parse_type(&["list", "--json"]),
+            LaunchType::List { json: true }
+        );
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parse_dryRun_onlyForWritingCommands() {
+        for command in ["build", "diff", "restore"].iter() {
+            let args = parse(gen_mock_args(&[*command, "--dry-run"])).unwrap();
+            assert!(args.options.dry_run);
+        }
+        let args = parse(gen_mock_args(&["--dry-run", "restore", "cpp", "2"])).unwrap();
+        assert!(args.options.dry_run);
+
+        for command in ["start", "stop", "run", "status", "install"].iter() {
+            let result = parse(gen_mock_args(&[*command, "--dry-run"]));
+            assert_eq!(
+                result,
+                Err(ParseError::UnknownFlag {
+                    command: command.to_string(),
+                    flag: String::from("--dry-run"),
+                })
+            );
+        }
+        // コマンドなし = install
+        assert!(parse(gen_mock_args(&["--dry-run"])).is_err());
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parse_jsonForBuild_invalid() {
+        let result = parse(gen_mock_args(&["build", "--json"]));
+        assert_eq!(
+            result,
+            Err(ParseError::UnknownFlag {
+                command: String::from("build"),
+                flag: String::from("--json"),
+            })
+        );
+    }
+
+    // help
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parse_help_valid() {
+        assert_eq!(parse_type(&["help"]), LaunchType::Help(None));
+        assert_eq!(parse_type(&["-h"]), LaunchType::Help(None));
+        assert_eq!(parse_type(&["--help"]), LaunchType::Help(None));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parse_commandHelp_valid() {
+        let status = LaunchType::Help(Some(String::from("status")));
+        assert_eq!(parse_type(&["help", "status"]), status);
+        assert_eq!(parse_type(&["status", "--help"]), status);
+        assert_eq!(parse_type(&["status", "-h"]), status);
+
+        // エイリアスは本来の名前になる
+        let run = LaunchType::Help(Some(String::from("run")));
+        assert_eq!(parse_type(&["help", "foreground"]), run);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parse_helpUnknownCommand_invalid() {
+        let result = parse(gen_mock_args(&["help", "explode"]));
+        assert_eq!(
+            result,
+            Err(ParseError::UnknownCommand(String::from("explode")))
+        );
     }
 
-    // shorts
+    #[test]
+    #[allow(non_snake_case)]
+    fn getCommandHelp_allCommands_valid() {
+        for spec in COMMANDS.iter() {
+            let help = get_command_help(spec.name).unwrap();
+            assert!(help.contains(spec.summary));
+            assert!(help.contains("--config <path>"));
+            assert!(get_help().contains(spec.summary));
+        }
+        assert!(get_command_help("status").unwrap().contains("--json"));
+        assert_eq!(get_command_help("explode"), None);
+    }
 
     #[test]
-    fn daemon_valid() {
-        let launch_type = detect_type(gen_mock_args("AUTO_LAUNCH"));
-        assert_eq!(launch_type, LaunchType::Daemon);
+    #[allow(non_snake_case)]
+    fn getHelp_configAndMetaTags_valid() {
+        let help = get_help();
+        assert!(help.contains("https://github.com/YuigaWada/PortSnippet"));
+        assert!(help.contains("\"snippets_dir\""));
+        assert!(help.contains("// #PORT#"));
+        assert!(help.contains("// prefix: \"\""));
+        assert!(help.contains("// #PORT_END#"));
     }
 
+    // options
+
     #[test]
-    fn short_daemon_invalid() {
-        let launch_type = detect_type(gen_mock_args("-A"));
-        assert_ne!(launch_type, LaunchType::Daemon); // not!
+    #[allow(non_snake_case)]
+    fn parse_globalOptions_valid() {
+        let args = parse(gen_mock_args(&[
+            "--config",
+            "/tmp/config.json",
+            "build",
+            "--snippets-dir=/tmp/snippets",
+            "-v",
+            "--dry-run",
+        ]))
+        .unwrap();
+
+        assert_eq!(args.launch_type, LaunchType::Build);
+        assert_eq!(
+            args.options,
+            Options {
+                config: Some(String::from("/tmp/config.json")),
+                snippets_dir: Some(String::from("/tmp/snippets")),
+                verbose: true,
+                dry_run: true,
+            }
+        );
     }
 
     #[test]
-    fn short_man_valid() {
-        let launch_type = detect_type(gen_mock_args("-m"));
-        assert_eq!(launch_type, LaunchType::Daemon);
+    #[allow(non_snake_case)]
+    fn parse_optionsWithoutCommand_install() {
+        let args = parse(gen_mock_args(&["--config=/tmp/config.json", "--verbose"])).unwrap();
+        assert_eq!(args.launch_type, LaunchType::Install);
+        assert_eq!(args.options.config, Some(String::from("/tmp/config.json")));
+        assert!(args.options.verbose);
     }
 
     #[test]
-    fn short_stop_valid() {
-        let launch_type = detect_type(gen_mock_args("-s"));
-        assert_eq!(launch_type, LaunchType::Stop);
+    #[allow(non_snake_case)]
+    fn parse_autoLaunchWithOptions_daemon() {
+        let args = parse(gen_mock_args(&[
+            "AUTO_LAUNCH",
+            "--config",
+            "/tmp/config.json",
+        ]))
+        .unwrap();
+        assert_eq!(args.launch_type, LaunchType::Daemon);
+        assert_eq!(args.options.config, Some(String::from("/tmp/config.json")));
     }
 
     #[test]
-    fn short_restart_valid() {
-        let launch_type = detect_type(gen_mock_args("-r"));
-        assert_eq!(launch_type, LaunchType::Restart);
+    #[allow(non_snake_case)]
+    fn parse_missingValue_invalid() {
+        let result = parse(gen_mock_args(&["status", "--config"]));
+        assert_eq!(
+            result,
+            Err(ParseError::MissingValue(String::from("--config")))
+        );
     }
 
+    // errors
+
     #[test]
-    fn short_help_valid() {
-        let launch_type = detect_type(gen_mock_args("-h"));
-        assert_eq!(launch_type, LaunchType::Help);
+    #[allow(non_snake_case)]
+    fn parse_unknownCommand_invalid() {
+        let result = parse(gen_mock_args(&["explode"]));
+        assert_eq!(
+            result,
+            Err(ParseError::UnknownCommand(String::from("explode")))
+        );
     }
 
-    // long
+    #[test]
+    #[allow(non_snake_case)]
+    fn parse_unknownFlag_invalid() {
+        let result = parse(gen_mock_args(&["-A"]));
+        assert_eq!(
+            result,
+            Err(ParseError::UnknownFlag {
+                command: String::from("port_snippet"),
+                flag: String::from("-A"),
+            })
+        );
+
+        let result = parse(gen_mock_args(&["stop", "--now"]));
+        assert_eq!(
+            result,
+            Err(ParseError::UnknownFlag {
+                command: String::from("stop"),
+                flag: String::from("--now"),
+            })
+        );
+    }
 
     #[test]
-    fn long_man_valid() {
-        let launch_type = detect_type(gen_mock_args("man"));
-        assert_eq!(launch_type, LaunchType::Daemon);
+    #[allow(non_snake_case)]
+    fn parse_unexpectedArgument_invalid() {
+        let result = parse(gen_mock_args(&["stop", "status"]));
+        assert_eq!(
+            result,
+            Err(ParseError::UnexpectedArgument {
+                command: String::from("stop"),
+                arg: String::from("status"),
+            })
+        );
     }
 
     #[test]
-    fn long_stop_valid() {
-        let launch_type = detect_type(gen_mock_args("stop"));
-        assert_eq!(launch_type, LaunchType::Stop);
+    #[allow(non_snake_case)]
+    fn parse_restore_valid() {
+        assert_eq!(
+            parse_type(&["restore"]),
+            LaunchType::Restore {
+                lang: None,
+                index: 1
+            }
+        );
+        assert_eq!(
+            parse_type(&["restore", "cpp", "3"]),
+            LaunchType::Restore {
+                lang: Some(String::from("cpp")),
+                index: 3
+            }
+        );
+        assert_eq!(
+            parse_type(&["restore", "--help"]),
+            LaunchType::Help(Some(String::from("restore")))
+        );
     }
 
     #[test]
-    fn long_restart_valid() {
-        let launch_type = detect_type(gen_mock_args("restart"));
-        assert_eq!(launch_type, LaunchType::Restart);
+    #[allow(non_snake_case)]
+    fn parse_restoreInvalidIndex_invalid() {
+        for index in ["0", "latest"].iter() {
+            let result = parse(gen_mock_args(&["restore", "cpp", index]));
+            assert_eq!(
+                result,
+                Err(ParseError::UnexpectedArgument {
+                    command: String::from("restore"),
+                    arg: index.to_string(),
+                })
+            );
+        }
     }
 
     #[test]
-    fn long_help_valid() {
-        let launch_type = detect_type(gen_mock_args("help"));
-        assert_eq!(launch_type, LaunchType::Help);
+    #[allow(non_snake_case)]
+    fn getDaemonArgs_valid() {
+        let options = Options {
+            config: Some(String::from("/not/found/config.json")),
+            snippets_dir: None,
+            verbose: true,
+            dry_run: true,
+        };
+        assert_eq!(
+            get_daemon_args(&options),
+            vec!["--config", "/not/found/config.json", "--verbose"]
+        );
+        assert!(get_daemon_args(&Options::default()).is_empty());
     }
 }
@@ -0,0 +1,191 @@
+use super::file;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// 書き換える前の<lang>.jsonを snippets_dir/.port_snippet/backups に残しておく
+// 言語ごとに新しいものからMAX_BACKUPS個まで保存する
+
+const BACKUP_DIRNAME: &str = "backups";
+const MAX_BACKUPS: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backup {
+    pub lang: String,
+    pub created_at: u64, // unix time (ms)
+    pub path: PathBuf,
+}
+
+pub fn get_backup_dir(meta_dir: &Path) -> PathBuf {
+    return meta_dir.join(BACKUP_DIRNAME);
+}
+
+fn now_millis() -> u64 {
+    return match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_millis() as u64,
+        Err(_) => 0,
+    };
+}
+
+// <lang>-<unix time(ms)>.json (ファイル名の順番 = 作成順になるように0埋めする)
+fn get_backup_filename(lang: &str, created_at: u64) -> String {
+    return format!("{}-{:015}.json", lang, created_at);
+}
+
+fn parse_backup_filename(filename: &str) -> Option<(String, u64)> {
+    let stem = filename.strip_suffix(".json")?;
+    let (lang, created_at) = stem.rsplit_once('-')?; // identifierに'-'が含まれることがある
+    let created_at = created_at.parse::<u64>().ok()?;
+    return Some((lang.to_string(), created_at));
+}
+
+// textをバックアップして、古いバックアップを削除する
+pub fn save(meta_dir: &Path, lang: &str, text: &str) -> std::io::Result<PathBuf> {
+    let backup_dir = get_backup_dir(meta_dir);
+    std::fs::create_dir_all(&backup_dir)?;
+
+    // 同じミリ秒に保存された場合はずらす
+    let mut created_at = now_millis();
+    let mut backup_filepath = backup_dir.join(get_backup_filename(lang, created_at));
+    while backup_filepath.exists() {
+        created_at += 1;
+        backup_filepath = backup_dir.join(get_backup_filename(lang, created_at));
+    }
+
+    file::write_file(&backup_filepath, text.to_string())?;
+    rotate(meta_dir, lang, MAX_BACKUPS);
+    return Ok(backup_filepath);
+}
+
+// バックアップを言語ごとに新しい順で返す (langがNoneなら全言語)
+pub fn list(meta_dir: &Path, lang: Option<&str>) -> Vec<Backup> {
+    let entries = match std::fs::read_dir(get_backup_dir(meta_dir)) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut backups = vec![];
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let filename = entry.file_name().to_string_lossy().to_string();
+        let (backup_lang, created_at) = match parse_backup_filename(&filename) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        if lang.is_some_and(|lang| lang != backup_lang) {
+            continue;
+        }
+        backups.push(Backup {
+            lang: backup_lang,
+            created_at: created_at,
+            path: entry.path(),
+        });
+    }
+
+    backups.sort_by(|a, b| {
+        a.lang
+            .cmp(&b.lang)
+            .then_with(|| b.created_at.cmp(&a.created_at))
+    });
+    return backups;
+}
+
+// keep個より古いバックアップを削除する
+fn rotate(meta_dir: &Path, lang: &str, keep: usize) {
+    for backup in list(meta_dir, Some(lang)).iter().skip(keep) {
+        if let Err(e) = std::fs::remove_file(&backup.path) {
+            println!("cannot remove {}: {}", backup.path.display(), e);
+        }
+    }
+}
+
+// バックアップをsnippet_json_filepathに書き戻す
+// 戻す前の内容もバックアップしておくので、restoreはやり直せる
+pub fn restore(
+    meta_dir: &Path,
+    backup: &Backup,
+    snippet_json_filepath: &PathBuf,
+) -> std::io::Result<()> {
+    let text = std::fs::read_to_string(&backup.path)?;
+    if let Ok(current) = std::fs::read_to_string(snippet_json_filepath) {
+        if current == text {
+            return Ok(());
+        }
+        save(meta_dir, &backup.lang, &current)?;
+    }
+    return file::write_file(snippet_json_filepath, text);
+}
+
+///// Unit Test
+
+#[cfg(test)]
+mod tests {
+    use crate::backup::*;
+
+    fn prepare_meta_dir(name: &str) -> PathBuf {
+        let mut meta_dir = std::env::temp_dir();
+        meta_dir.push(format!(
+            "port_snippet_backup_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&meta_dir);
+        std::fs::create_dir_all(&meta_dir).unwrap();
+        return meta_dir;
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parseBackupFilename_valid() {
+        let filename = get_backup_filename("objective-c", 42);
+        assert_eq!(filename, "objective-c-000000000000042.json");
+        assert_eq!(
+            parse_backup_filename(&filename),
+            Some((String::from("objective-c"), 42))
+        );
+        assert_eq!(parse_backup_filename("cpp.json"), None);
+        assert_eq!(parse_backup_filename("cpp-latest.json"), None);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn save_rotate_newestFirst() {
+        let meta_dir = prepare_meta_dir("rotate");
+        for i in 0..MAX_BACKUPS + 2 {
+            save(&meta_dir, "cpp", &format!("{}", i)).unwrap();
+        }
+        save(&meta_dir, "rust", "rust").unwrap();
+
+        let backups = list(&meta_dir, Some("cpp"));
+        assert_eq!(backups.len(), MAX_BACKUPS);
+        let newest = std::fs::read_to_string(&backups[0].path).unwrap();
+        assert_eq!(newest, format!("{}", MAX_BACKUPS + 1));
+        let oldest = std::fs::read_to_string(&backups[MAX_BACKUPS - 1].path).unwrap();
+        assert_eq!(oldest, "2");
+
+        assert_eq!(list(&meta_dir, None).len(), MAX_BACKUPS + 1);
+
+        std::fs::remove_dir_all(&meta_dir).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn restore_backsUpCurrent_valid() {
+        let meta_dir = prepare_meta_dir("restore");
+        let snippet_json_filepath = meta_dir.join("cpp.json");
+        save(&meta_dir, "cpp", "old").unwrap();
+        std::fs::write(&snippet_json_filepath, "broken").unwrap();
+
+        let backup = list(&meta_dir, Some("cpp"))[0].clone();
+        restore(&meta_dir, &backup, &snippet_json_filepath).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&snippet_json_filepath).unwrap(),
+            "old"
+        );
+
+        // 戻す前の内容が最新のバックアップになる
+        let backups = list(&meta_dir, Some("cpp"));
+        assert_eq!(backups.len(), 2);
+        assert_eq!(std::fs::read_to_string(&backups[0].path).unwrap(), "broken");
+
+        std::fs::remove_dir_all(&meta_dir).unwrap();
+    }
+}
@@ -0,0 +1,443 @@
+use super::state::State;
+use interprocess::local_socket::{LocalSocketListener, LocalSocketStream};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+// 起動中のデーモンとCLIがやり取りするためのソケット
+// Unixでは snippets_dir/.port_snippet/control.sock, Windowsでは名前付きパイプを使う
+// (パスがsun_pathに収まらないときは、一時フォルダに snippets_dirごとの名前で置く)
+// 1行に1つのjsonを送り、1行のjsonが返ってくる
+//
+// > {"command":"status"}
+// < {"ok":true,"message":"running","paused":false,"state":{...}}
+
+#[cfg(unix)]
+const SOCKET_FILENAME: &str = "control.sock";
+#[cfg(unix)]
+const SUN_PATH_MAX: usize = 104; // sockaddr_un.sun_pathの長さ (macOSが104, Linuxが108。終端の\0を含む)
+
+const SEND_TIMEOUT: u64 = 5_000; // ms
+const SCAN_TIMEOUT: u64 = 300_000; // ms (reload / rescanは走査が終わってから応答する)
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    ReloadConfig, // config.jsonを読み直す
+    Rescan,       // すべての対象ファイルを走査し直す
+    Status,       // 起動状態を返す
+    Pause,        // スニペットの生成を一時停止する
+    Resume,       // 一時停止を解除する
+    Shutdown,     // デーモンを終了する
+}
+
+impl Request {
+    // 応答を待つ時間
+    fn timeout(&self) -> Duration {
+        return match self {
+            Request::ReloadConfig | Request::Rescan => Duration::from_millis(SCAN_TIMEOUT),
+            _ => Duration::from_millis(SEND_TIMEOUT),
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Response {
+    pub ok: bool,
+    pub message: String,
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(default)]
+    pub state: Option<State>,
+}
+
+impl Response {
+    pub fn ok(message: &str) -> Self {
+        return Response {
+            ok: true,
+            message: message.to_string(),
+            paused: false,
+            state: None,
+        };
+    }
+
+    pub fn error(message: &str) -> Self {
+        return Response {
+            ok: false,
+            message: message.to_string(),
+            paused: false,
+            state: None,
+        };
+    }
+}
+
+// snippets_dirごとに変わる名前
+fn get_hashed_name(meta_dir: &Path) -> String {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    meta_dir.hash(&mut hasher);
+    return format!("port_snippet-{:016x}", hasher.finish());
+}
+
+// ソケットファイルのパス
+// meta_dirが深くてsun_pathに収まらないときは、一時フォルダに置く
+#[cfg(unix)]
+fn get_socket_filepath(meta_dir: &Path) -> PathBuf {
+    let socket_filepath = meta_dir.join(SOCKET_FILENAME);
+    if socket_filepath.as_os_str().len() < SUN_PATH_MAX {
+        return socket_filepath;
+    }
+    return std::env::temp_dir().join(format!("{}.sock", get_hashed_name(meta_dir)));
+}
+
+// ソケットの名前
+#[cfg(unix)]
+fn get_socket_name(meta_dir: &Path) -> std::ffi::OsString {
+    return get_socket_filepath(meta_dir).into_os_string(); // OsStringなら"@"が名前空間扱いにならない
+}
+
+#[cfg(windows)]
+fn get_socket_name(meta_dir: &Path) -> String {
+    // 名前付きパイプはファイルシステムに置けないので、snippets_dirごとに名前を変える
+    return format!("@{}", get_hashed_name(meta_dir));
+}
+
+pub struct Server {
+    listener: LocalSocketListener,
+    meta_dir: PathBuf,
+    #[cfg(unix)]
+    socket_filepath: PathBuf,
+}
+
+#[cfg(unix)]
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.socket_filepath);
+    }
+}
+
+impl Server {
+    // ソケットを開く
+    // 同じsnippets_dirのデーモンは1つしか起動しない(pidfile::acquire済み)ので、残っているソケットは削除してよい
+    pub fn bind(meta_dir: &Path) -> std::io::Result<Server> {
+        #[cfg(unix)]
+        let _ = std::fs::remove_file(get_socket_filepath(meta_dir));
+
+        let listener = LocalSocketListener::bind(get_socket_name(meta_dir))?;
+        return Ok(Server {
+            listener: listener,
+            meta_dir: meta_dir.to_path_buf(),
+            #[cfg(unix)]
+            socket_filepath: get_socket_filepath(meta_dir),
+        });
+    }
+
+    // 接続ごとにスレッドを立てて処理する (何も送ってこない接続があっても、他の接続を待たせない)
+    // shutdownに応答した後はreturnする
+    pub fn serve<F>(&self, f: F)
+    where
+        F: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        for stream in self.listener.incoming() {
+            if shutdown.load(Ordering::SeqCst) {
+                return; // 起こすための接続
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    println!("control socket error: {:?}", e);
+                    continue;
+                }
+            };
+
+            let f = f.clone();
+            let shutdown = shutdown.clone();
+            let meta_dir = self.meta_dir.clone();
+            std::thread::spawn(move || {
+                if handle_connection(stream, &*f) {
+                    // 接続を待っているserveを起こして終わらせる
+                    shutdown.store(true, Ordering::SeqCst);
+                    let _ = LocalSocketStream::connect(get_socket_name(&meta_dir));
+                }
+            });
+        }
+    }
+}
+
+// 1つの接続を処理する (shutdownを受け取ったらtrueを返す)
+fn handle_connection<F: Fn(Request) -> Response>(stream: LocalSocketStream, f: &F) -> bool {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => return false, // EOF
+            Ok(_) => {}
+            Err(_) => return false,
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (response, shutdown) = match serde_json::from_str::<Request>(line.trim()) {
+            Ok(request) => (f(request), request == Request::Shutdown),
+            Err(e) => (Response::error(&format!("invalid request: {}", e)), false),
+        };
+
+        if let Ok(mut json) = serde_json::to_string(&response) {
+            json.push('\n');
+            if reader.get_mut().write_all(json.as_bytes()).is_err() {
+                return shutdown;
+            }
+            let _ = reader.get_mut().flush();
+        }
+
+        if shutdown {
+            return true;
+        }
+    }
+}
+
+// 起動中のデーモンにリクエストを送る
+// デーモンが起動していない場合はErrが返る (応答がなければErrorKind::TimedOut)
+pub fn send(meta_dir: &Path, request: Request) -> std::io::Result<Response> {
+    return send_with_timeout(meta_dir, request, request.timeout());
+}
+
+// LocalSocketStreamにはタイムアウトを設定できないので、別スレッドでやり取りして待つ
+// (応答しないデーモンとやり取りしているスレッドは残るが、CLIはすぐに終了する)
+fn send_with_timeout(
+    meta_dir: &Path,
+    request: Request,
+    timeout: Duration,
+) -> std::io::Result<Response> {
+    let socket_name = get_socket_name(meta_dir);
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(exchange(socket_name, request));
+    });
+    return match rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "PortSnippet did not respond",
+        )),
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            Err(std::io::Error::other("control socket thread panicked"))
+        }
+    };
+}
+
+// 1つのリクエストを送って、応答を読む
+fn exchange<'a, N: interprocess::local_socket::ToLocalSocketName<'a>>(
+    socket_name: N,
+    request: Request,
+) -> std::io::Result<Response> {
+    let mut stream = LocalSocketStream::connect(socket_name)?;
+
+    let mut json = serde_json::to_string(&request)?;
+    json.push('\n');
+    stream.write_all(json.as_bytes())?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    let response = serde_json::from_str::<Response>(line.trim())?;
+    return Ok(response);
+}
+
+///// Unit Test
+
+#[cfg(test)]
+mod tests {
+    use crate::control::*;
+    use std::sync::Mutex;
+
+    fn prepare_meta_dir(name: &str) -> PathBuf {
+        let mut meta_dir = std::env::temp_dir();
+        meta_dir.push(format!("port_snippet_ctl_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&meta_dir);
+        std::fs::create_dir_all(&meta_dir).unwrap();
+        return meta_dir;
+    }
+
+    // 受け取ったリクエストを記録するだけのサーバーを立てる
+    fn spawn_server(meta_dir: &Path) -> mpsc::Receiver<Request> {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let server = Server::bind(meta_dir).unwrap();
+        std::thread::spawn(move || {
+            let paused = AtomicBool::new(false);
+            server.serve(move |request| {
+                tx.lock().unwrap().send(request).unwrap();
+                match request {
+                    Request::Pause => paused.store(true, Ordering::SeqCst),
+                    Request::Resume => paused.store(false, Ordering::SeqCst),
+                    _ => {}
+                }
+                let mut response = Response::ok("done");
+                response.paused = paused.load(Ordering::SeqCst);
+                return response;
+            });
+        });
+        return rx;
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn request_serialize_kebabCase() {
+        let json = serde_json::to_string(&Request::ReloadConfig).unwrap();
+        assert_eq!(json, r#"{"command":"reload-config"}"#);
+
+        let request = serde_json::from_str::<Request>(r#"{"command":"shutdown"}"#).unwrap();
+        assert_eq!(request, Request::Shutdown);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn send_allRequests_valid() {
+        let meta_dir = prepare_meta_dir("all");
+        let rx = spawn_server(&meta_dir);
+
+        let requests = [
+            Request::ReloadConfig,
+            Request::Rescan,
+            Request::Status,
+            Request::Pause,
+            Request::Resume,
+        ];
+        for request in requests.iter() {
+            let response = send(&meta_dir, *request).unwrap();
+            assert!(response.ok);
+            assert_eq!(rx.recv().unwrap(), *request);
+        }
+
+        std::fs::remove_dir_all(&meta_dir).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn send_pauseThenStatus_paused() {
+        let meta_dir = prepare_meta_dir("pause");
+        let _rx = spawn_server(&meta_dir);
+
+        assert!(send(&meta_dir, Request::Pause).unwrap().paused);
+        assert!(send(&meta_dir, Request::Status).unwrap().paused);
+        assert!(!send(&meta_dir, Request::Resume).unwrap().paused);
+
+        std::fs::remove_dir_all(&meta_dir).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn send_invalidLine_error() {
+        let meta_dir = prepare_meta_dir("invalid");
+        let _rx = spawn_server(&meta_dir);
+
+        let mut stream = LocalSocketStream::connect(get_socket_name(&meta_dir)).unwrap();
+        stream.write_all(b"{\"command\":\"explode\"}\n").unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+
+        let response = serde_json::from_str::<Response>(line.trim()).unwrap();
+        assert!(!response.ok);
+        assert!(response.message.starts_with("invalid request"));
+
+        std::fs::remove_dir_all(&meta_dir).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn send_shutdown_stopsServing() {
+        let meta_dir = prepare_meta_dir("shutdown");
+        let server = Server::bind(&meta_dir).unwrap();
+        let handle = std::thread::spawn(move || {
+            server.serve(|_| Response::ok("bye"));
+        });
+
+        let response = send(&meta_dir, Request::Shutdown).unwrap();
+        assert!(response.ok);
+        handle.join().unwrap();
+
+        assert!(send(&meta_dir, Request::Status).is_err()); // もう誰も待っていない
+
+        std::fs::remove_dir_all(&meta_dir).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn send_idleClient_notBlocking() {
+        let meta_dir = prepare_meta_dir("idle");
+        let server = Server::bind(&meta_dir).unwrap();
+        let handle = std::thread::spawn(move || {
+            server.serve(|_| Response::ok("done"));
+        });
+
+        // 何も送らない接続があっても、他の接続には応答する
+        let _idle = LocalSocketStream::connect(get_socket_name(&meta_dir)).unwrap();
+        assert!(send(&meta_dir, Request::Status).unwrap().ok);
+        assert!(send(&meta_dir, Request::Shutdown).unwrap().ok);
+        handle.join().unwrap();
+
+        std::fs::remove_dir_all(&meta_dir).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn send_noResponse_timedOut() {
+        let meta_dir = prepare_meta_dir("timeout");
+        let server = Server::bind(&meta_dir).unwrap();
+        let (tx, rx) = mpsc::channel::<()>();
+        let rx = Mutex::new(rx);
+        std::thread::spawn(move || {
+            // 応答しないデーモン
+            server.serve(move |_| {
+                let _ = rx.lock().unwrap().recv();
+                return Response::ok("late");
+            });
+        });
+
+        let e =
+            send_with_timeout(&meta_dir, Request::Status, Duration::from_millis(200)).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+
+        drop(tx);
+        std::fs::remove_dir_all(&meta_dir).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    #[cfg(unix)]
+    fn send_longMetaDir_valid() {
+        let base_dir = prepare_meta_dir("long");
+        let meta_dir = base_dir.join("a".repeat(SUN_PATH_MAX));
+        std::fs::create_dir_all(&meta_dir).unwrap();
+
+        let socket_filepath = get_socket_filepath(&meta_dir);
+        assert!(socket_filepath.starts_with(std::env::temp_dir()));
+        assert!(socket_filepath.as_os_str().len() < SUN_PATH_MAX);
+
+        let _rx = spawn_server(&meta_dir);
+        assert!(send(&meta_dir, Request::Status).unwrap().ok);
+        assert!(send(&meta_dir, Request::Shutdown).unwrap().ok);
+
+        std::fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn send_notRunning_error() {
+        let meta_dir = prepare_meta_dir("none");
+        assert!(send(&meta_dir, Request::Status).is_err());
+
+        std::fs::remove_dir_all(&meta_dir).unwrap();
+    }
+}
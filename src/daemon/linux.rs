@@ -0,0 +1,301 @@
+use super::super::file;
+use super::{get_log_path, LogPaths};
+use std::path::PathBuf;
+use std::process::Command;
+
+const EXE_VARIABLE: &str = "{{EXE_PATH}}";
+const LOG_VARIABLE: &str = "{{LOG_FILE_PATH}}";
+const ERROR_LOG_VARIABLE: &str = "{{ERROR_LOG_FILE_PATH}}";
+const ARGS_VARIABLE: &str = "{{ARGS}}";
+
+const UNIT_NAME: &str = "port-snippet.service";
+const UNIT_DIR: &str = "systemd/user";
+const UNIT_TEMPLATE: &str = r#"[Unit]
+Description=PortSnippet - Dynamic Snippet for VSCode
+
+[Service]
+Type=simple
+ExecStart="{{EXE_PATH}}" AUTO_LAUNCH{{ARGS}}
+Restart=on-failure
+StandardOutput=append:{{LOG_FILE_PATH}}
+StandardError=append:{{ERROR_LOG_FILE_PATH}}
+
+[Install]
+WantedBy=default.target
+"#;
+
+// ~/.config/systemd/user/port-snippet.service のパスを返す
+fn get_unit_filepath() -> PathBuf {
+    let mut config_dir = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let home_dir = std::env::var("HOME").expect("cannot get $HOME");
+            let mut config_dir = PathBuf::from(home_dir);
+            config_dir.push(".config");
+            config_dir
+        }
+    };
+
+    config_dir.push(UNIT_DIR);
+    config_dir.push(UNIT_NAME);
+    return config_dir;
+}
+
+// unitファイルの値では % が指定子(%hなど)として展開されるので、%% にする
+fn escape_specifiers(value: &str) -> String {
+    return value.replace('%', "%%");
+}
+
+// ExecStartの "..." の中に書く値 (\ と " はC言語風にエスケープして、% は %% にする)
+fn escape_quoted(value: &str) -> String {
+    let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+    return escape_specifiers(&escaped);
+}
+
+// unitファイルに変数を注入する
+fn inject_variables(
+    mut unit: String,
+    exe_path: &str,
+    args: &[String],
+    log_paths: &LogPaths,
+) -> String {
+    // --config などの引数はスペースを含むかもしれないのでクォートする
+    let args_string: String = args
+        .iter()
+        .map(|arg| format!(" \"{}\"", escape_quoted(arg)))
+        .collect();
+
+    unit = unit.replace(EXE_VARIABLE, &escape_quoted(exe_path));
+    unit = unit.replace(ARGS_VARIABLE, &args_string);
+    unit = unit.replace(
+        LOG_VARIABLE,
+        &escape_specifiers(
+            log_paths
+                .standard
+                .to_str()
+                .expect("something went wrong: cannot convert PathBuf → str"),
+        ),
+    );
+    unit = unit.replace(
+        ERROR_LOG_VARIABLE,
+        &escape_specifiers(
+            log_paths
+                .error
+                .to_str()
+                .expect("something went wrong: cannot convert PathBuf → str"),
+        ),
+    );
+
+    return unit;
+}
+
+// systemctl --user を操作する
+fn operate_systemctl(args: &[&str]) {
+    let systemctl = Command::new("systemctl")
+        .arg("--user")
+        .args(args)
+        .output()
+        .expect("cannot run systemctl");
+    let result_message = if systemctl.status.success() {
+        systemctl.stdout
+    } else {
+        systemctl.stderr
+    };
+    println!("{}\n...\n", String::from_utf8_lossy(&result_message));
+}
+
+// systemdのユーザーセッションが使えるかどうか (コンテナなどでは使えない)
+pub fn is_available() -> bool {
+    return match Command::new("systemctl")
+        .arg("--user")
+        .arg("show-environment")
+        .output()
+    {
+        Ok(output) => output.status.success(),
+        Err(_) => false,
+    };
+}
+
+// daemonを登録
+// argsはAUTO_LAUNCHの後ろに付ける引数 (--configなど)
+pub fn register(need_run: bool, args: &[String]) {
+    let exe_path = std::env::current_exe().expect("cannot get current_exe");
+    let log_paths = get_log_path(&exe_path);
+
+    // systemdはログファイルを作れてもフォルダまでは作ってくれない
+    if let Some(log_dir) = log_paths.standard.parent() {
+        let _ = std::fs::create_dir_all(log_dir);
+    }
+
+    let exe_path = std::fs::canonicalize(exe_path).expect("cannot get current_exe");
+    let exe_path_string = exe_path
+        .into_os_string()
+        .into_string()
+        .expect("cannot get current_exe");
+
+    let unit = inject_variables(
+        UNIT_TEMPLATE.to_string(),
+        &exe_path_string,
+        args,
+        &log_paths,
+    );
+
+    let unit_filepath = get_unit_filepath();
+    if let Some(unit_dir) = unit_filepath.parent() {
+        let _ = std::fs::create_dir_all(unit_dir);
+    }
+    if let Err(e) = file::write_file(&unit_filepath, unit) {
+        panic!("cannot write {}: {}", unit_filepath.display(), e);
+    }
+
+    println!("> systemctl --user enable {}", UNIT_NAME);
+    operate_systemctl(&["daemon-reload"]);
+    operate_systemctl(&["enable", UNIT_NAME]);
+    if need_run {
+        run();
+    }
+}
+
+// 完了メッセージ
+pub fn get_complete_messages() -> String {
+    return format!(
+        "{}\n{}\n\nA unit file is saved as \"{}\"!\n",
+        "Daemon setup is now completed!",
+        "When you log in, PortSnippet process will be started by systemd.",
+        get_unit_filepath().display()
+    );
+}
+
+// PortSnippetをsystemd経由で起動する
+pub fn run() {
+    operate_systemctl(&["daemon-reload"]);
+    operate_systemctl(&["restart", UNIT_NAME]);
+}
+
+// PortSnippetを停止する
+pub fn stop() {
+    operate_systemctl(&["stop", UNIT_NAME]);
+}
+
+// daemonの登録を解除する
+pub fn unregister() {
+    operate_systemctl(&["disable", "--now", UNIT_NAME]);
+
+    let unit_filepath = get_unit_filepath();
+    if let Err(e) = std::fs::remove_file(&unit_filepath) {
+        println!("cannot remove {}: {}", unit_filepath.display(), e);
+    }
+    operate_systemctl(&["daemon-reload"]);
+}
+
+///// Unit Test
+
+#[cfg(test)]
+mod tests {
+    use crate::daemon::linux::*;
+
+    fn mock_log_paths() -> LogPaths {
+        return LogPaths {
+            standard: PathBuf::from("/opt/port_snippet/.log/standard.log"),
+            error: PathBuf::from("/opt/port_snippet/.log/error.log"),
+        };
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn injectVariables_template_valid() {
+        let unit = inject_variables(
+            UNIT_TEMPLATE.to_string(),
+            "/opt/port_snippet/port_snippet",
+            &[],
+            &mock_log_paths(),
+        );
+
+        assert!(unit.contains("ExecStart=\"/opt/port_snippet/port_snippet\" AUTO_LAUNCH\n"));
+        assert!(unit.contains("StandardOutput=append:/opt/port_snippet/.log/standard.log\n"));
+        assert!(unit.contains("StandardError=append:/opt/port_snippet/.log/error.log\n"));
+        assert!(unit.contains("WantedBy=default.target\n"));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn injectVariables_template_noVariableLeft() {
+        let unit = inject_variables(
+            UNIT_TEMPLATE.to_string(),
+            "/opt/port_snippet/port_snippet",
+            &[],
+            &mock_log_paths(),
+        );
+
+        assert!(!unit.contains("{{"));
+        assert!(!unit.contains("}}"));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn injectVariables_spacedPath_quoted() {
+        let unit = inject_variables(
+            UNIT_TEMPLATE.to_string(),
+            "/home/user/my tools/port_snippet",
+            &[],
+            &mock_log_paths(),
+        );
+
+        assert!(unit.contains("ExecStart=\"/home/user/my tools/port_snippet\" AUTO_LAUNCH\n"));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn injectVariables_args_quoted() {
+        let unit = inject_variables(
+            UNIT_TEMPLATE.to_string(),
+            "/opt/port_snippet/port_snippet",
+            &[
+                String::from("--config"),
+                String::from("/home/user/my config.json"),
+            ],
+            &mock_log_paths(),
+        );
+
+        assert!(unit.contains(
+            "ExecStart=\"/opt/port_snippet/port_snippet\" AUTO_LAUNCH \"--config\" \"/home/user/my config.json\"\n"
+        ));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn injectVariables_percent_escaped() {
+        let unit = inject_variables(
+            UNIT_TEMPLATE.to_string(),
+            "/opt/100%/port_snippet",
+            &[
+                String::from("--config"),
+                String::from("/home/%u/config.json"),
+            ],
+            &mock_log_paths(),
+        );
+
+        assert!(unit.contains(
+            "ExecStart=\"/opt/100%%/port_snippet\" AUTO_LAUNCH \"--config\" \"/home/%%u/config.json\"\n"
+        ));
+        assert!(!unit.contains("After="));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn injectVariables_quoteAndBackslash_escaped() {
+        let unit = inject_variables(
+            UNIT_TEMPLATE.to_string(),
+            "/opt/my\\tools/port_snippet",
+            &[
+                String::from("--config"),
+                String::from("/home/user/\"100%\".json"),
+            ],
+            &mock_log_paths(),
+        );
+
+        assert!(unit.contains(
+            "ExecStart=\"/opt/my\\\\tools/port_snippet\" AUTO_LAUNCH \"--config\" \"/home/user/\\\"100%%\\\".json\"\n"
+        ));
+    }
+}
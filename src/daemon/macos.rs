@@ -1,10 +1,12 @@
 use super::super::file;
+use super::get_log_path;
 use std::path::PathBuf;
 use std::process::Command;
 
 const EXE_VARIABLE: &str = "{{EXE_PATH}}";
 const LOG_VARIABLE: &str = "{{LOG_FILE_PATH}}";
 const ERROR_LOG_VARIABLE: &str = "{{ERROR_LOG_FILE_PATH}}";
+const ARGS_VARIABLE: &str = "{{ARGS}}";
 
 const PLIST_FILENAME: &str = "launch-port-snippet";
 const PLIST_FILEPATH: &str = "~/Library/LaunchAgents/launch-port-snippet.plist";
@@ -18,7 +20,7 @@ const PLIST_TEMPLATE: &str = r#"
     <key>ProgramArguments</key>
     <array>
         <string>{{EXE_PATH}}</string>
-        <string>AUTO_LAUNCH</string>
+        <string>AUTO_LAUNCH</string>{{ARGS}}
     </array>
     <key>RunAtLoad</key>
     <true/>
@@ -30,31 +32,8 @@ const PLIST_TEMPLATE: &str = r#"
 </plist>
 "#;
 
-struct LogPaths {
-    standard: PathBuf,
-    error: PathBuf,
-}
-
-// ログファイルのパスを取得する
-fn get_log_path(exe_path: &PathBuf) -> LogPaths {
-    let mut log_dir = exe_path.clone();
-    log_dir.pop();
-    log_dir.push(".log");
-
-    let mut log_path = log_dir.clone();
-    log_path.push("standard.log"); // > ./(exe)/.log/standard.log
-
-    let mut error_log_path = log_dir.clone();
-    error_log_path.push("error.log"); // > ./(exe)/.log/error.log
-
-    return LogPaths {
-        standard: log_path,
-        error: error_log_path,
-    };
-}
-
 // plistに変数を注入する
-fn inject_variables(mut plist: String, exe_path: PathBuf) -> String {
+fn inject_variables(mut plist: String, exe_path: PathBuf, args: &[String]) -> String {
     let log_paths = get_log_path(&exe_path);
     
     let exe_path = std::fs::canonicalize(exe_path).expect("cannot get current_exe");
@@ -64,6 +43,11 @@ fn inject_variables(mut plist: String, exe_path: PathBuf) -> String {
         .expect("cannot get current_exe");
 
     plist = plist.replace(EXE_VARIABLE, &exe_path_string);
+    let args_string: String = args
+        .iter()
+        .map(|arg| format!("\n        <string>{}</string>", escape_xml(arg)))
+        .collect();
+    plist = plist.replace(ARGS_VARIABLE, &args_string);
     plist = plist.replace(
         LOG_VARIABLE,
         &log_paths
@@ -82,6 +66,14 @@ fn inject_variables(mut plist: String, exe_path: PathBuf) -> String {
     return plist;
 }
 
+// plistに書けるようにエスケープする
+fn escape_xml(text: &str) -> String {
+    return text
+        .replace("&", "&amp;")
+        .replace("<", "&lt;")
+        .replace(">", "&gt;");
+}
+
 // launchdを操作する
 fn operate_launchd(mode: &str, arg: &str) {
     let launchctl = Command::new("launchctl")
@@ -93,17 +85,25 @@ fn operate_launchd(mode: &str, arg: &str) {
     println!("{}\n...\n", std::str::from_utf8(&result_message).unwrap());
 }
 
+// launchdは常に使える
+pub fn is_available() -> bool {
+    return true;
+}
+
 // daemonを登録
-pub fn register(need_run: bool) {
+// argsはAUTO_LAUNCHの後ろに付ける引数 (--configなど)
+pub fn register(need_run: bool, args: &[String]) {
     let home_dir = std::env::var("HOME").unwrap();
     let exe_path = std::env::current_exe().expect("cannot get current_exe");
 
     let mut plist = PLIST_TEMPLATE.to_string();
-    plist = inject_variables(plist, exe_path);
+    plist = inject_variables(plist, exe_path, args);
 
     let plist_filepath_string = PLIST_FILEPATH.replace("~", &home_dir);
     let plist_filepath = PathBuf::from(&plist_filepath_string);
-    file::write_file(&plist_filepath, plist);
+    if let Err(e) = file::write_file(&plist_filepath, plist) {
+        panic!("cannot write {}: {}", plist_filepath_string, e);
+    }
 
     println!("> launchctl load {}", &plist_filepath_string);
     if need_run {
@@ -133,3 +133,14 @@ pub fn run() {
 pub fn stop() {
     operate_launchd("stop", PLIST_FILENAME);
 }
+
+// daemonの登録を解除する
+pub fn unregister() {
+    let home_dir = std::env::var("HOME").unwrap();
+    let plist_filepath_string = PLIST_FILEPATH.replace("~", &home_dir);
+    operate_launchd("unload", plist_filepath_string.as_str());
+
+    if let Err(e) = std::fs::remove_file(&plist_filepath_string) {
+        println!("cannot remove {}: {}", plist_filepath_string, e);
+    }
+}
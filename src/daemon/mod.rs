@@ -1,3 +1,7 @@
+use std::path::PathBuf;
+
+pub mod pidfile; // init systemを使わない場合
+
 #[cfg(target_os = "macos")]
 pub mod macos;
 
@@ -8,4 +12,55 @@ pub use self::macos::*;
 pub mod windows;
 
 #[cfg(target_os = "windows")]
-pub use self::windows::*;
\ No newline at end of file
+pub use self::windows::*;
+
+#[cfg(target_os = "linux")]
+pub mod linux;
+
+#[cfg(target_os = "linux")]
+pub use self::linux::*;
+
+pub struct LogPaths {
+    pub standard: PathBuf,
+    pub error: PathBuf,
+}
+
+// ログファイルのパスを取得する
+pub fn get_log_path(exe_path: &PathBuf) -> LogPaths {
+    let mut log_dir = exe_path.clone();
+    log_dir.pop();
+    log_dir.push(".log");
+
+    let mut log_path = log_dir.clone();
+    log_path.push("standard.log"); // > ./(exe)/.log/standard.log
+
+    let mut error_log_path = log_dir.clone();
+    error_log_path.push("error.log"); // > ./(exe)/.log/error.log
+
+    return LogPaths {
+        standard: log_path,
+        error: error_log_path,
+    };
+}
+
+///// Unit Test
+
+#[cfg(test)]
+mod tests {
+    use crate::daemon::*;
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn getLogPath_exePath_valid() {
+        let log_paths = get_log_path(&PathBuf::from("/opt/port_snippet/port_snippet"));
+
+        assert_eq!(
+            log_paths.standard,
+            PathBuf::from("/opt/port_snippet/.log/standard.log")
+        );
+        assert_eq!(
+            log_paths.error,
+            PathBuf::from("/opt/port_snippet/.log/error.log")
+        );
+    }
+}
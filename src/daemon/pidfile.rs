@@ -0,0 +1,361 @@
+use super::super::argparser::AUTO_LAUNCH_PARAM;
+use super::get_log_path;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+// init system(launchd / systemd / Windows Service)を使わずにデーモンを管理する
+// snippets_dir/.port_snippet にPIDファイルとロックファイルを置く
+
+const PID_FILENAME: &str = "port_snippet.pid";
+const LOCK_FILENAME: &str = "port_snippet.lock";
+const STOP_TIMEOUT: u64 = 5_000; // ms
+const START_TIMEOUT: u64 = 3_000; // ms
+const ACQUIRE_TIMEOUT: u64 = 500; // ms
+
+#[derive(Debug)]
+pub enum LockError {
+    AlreadyRunning(Option<u32>), // 同じconfigのwatcherがすでに起動している
+    Io(std::io::Error),
+}
+
+// 起動中はロックを握り続ける
+// dropされるとPIDファイルを削除してロックを解放する
+pub struct PidLock {
+    _lock_file: File,
+    pid_filepath: PathBuf,
+}
+
+impl Drop for PidLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.pid_filepath);
+    }
+}
+
+fn get_pid_filepath(meta_dir: &Path) -> PathBuf {
+    return meta_dir.join(PID_FILENAME);
+}
+
+fn get_lock_filepath(meta_dir: &Path) -> PathBuf {
+    return meta_dir.join(LOCK_FILENAME);
+}
+
+fn open_lock_file(meta_dir: &Path) -> std::io::Result<File> {
+    return OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(get_lock_filepath(meta_dir));
+}
+
+// ロックを取得してPIDファイルを書き込む
+// すでに別のプロセスがロックを握っている場合はErrを返す
+// (is_lockedがロックを一瞬だけ取ることがあるので、ACQUIRE_TIMEOUTの間は取り直す)
+pub fn acquire(meta_dir: &Path) -> Result<PidLock, LockError> {
+    let lock_file = open_lock_file(meta_dir).map_err(LockError::Io)?;
+    let started = Instant::now();
+    loop {
+        match lock_file.try_lock() {
+            Ok(()) => break,
+            Err(TryLockError::WouldBlock) => {
+                if started.elapsed() >= Duration::from_millis(ACQUIRE_TIMEOUT) {
+                    return Err(LockError::AlreadyRunning(read_pid(meta_dir)));
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Err(TryLockError::Error(e)) => return Err(LockError::Io(e)),
+        }
+    }
+
+    let pid_filepath = get_pid_filepath(meta_dir);
+    std::fs::write(&pid_filepath, format!("{}\n", std::process::id())).map_err(LockError::Io)?;
+
+    return Ok(PidLock {
+        _lock_file: lock_file,
+        pid_filepath: pid_filepath,
+    });
+}
+
+// ロックが握られているかどうか(= watcherが起動しているかどうか)
+// ロックファイルがなければ、作らずにfalseを返す
+pub fn is_locked(meta_dir: &Path) -> bool {
+    if !get_lock_filepath(meta_dir).exists() {
+        return false;
+    }
+    if let Ok(lock_file) = open_lock_file(meta_dir) {
+        return match lock_file.try_lock() {
+            Ok(()) => false, // lock_fileがdropされると解放される
+            Err(TryLockError::WouldBlock) => true,
+            Err(TryLockError::Error(_)) => false,
+        };
+    }
+    return false;
+}
+
+// PIDファイルを読む
+pub fn read_pid(meta_dir: &Path) -> Option<u32> {
+    let contents = std::fs::read_to_string(get_pid_filepath(meta_dir)).ok()?;
+    return contents.trim().parse::<u32>().ok();
+}
+
+// 表示用にPIDを整形する
+pub fn format_pid(pid: Option<u32>) -> String {
+    return match pid {
+        Some(pid) => pid.to_string(),
+        None => String::from("unknown"),
+    };
+}
+
+// 自分自身をバックグラウンドで起動し直す
+// 標準出力・標準エラーは .log/standard.log / .log/error.log に流す
+// 起動したプロセスがロックを取るまで待つ (二重起動かどうかは起動したプロセスがロックを取って判定する)
+pub fn spawn(meta_dir: &Path, args: &[String]) -> Result<u32, LockError> {
+    let exe_path = std::env::current_exe().map_err(LockError::Io)?;
+    let log_paths = get_log_path(&exe_path);
+    if let Some(log_dir) = log_paths.standard.parent() {
+        std::fs::create_dir_all(log_dir).map_err(LockError::Io)?;
+    }
+
+    let open_log = |path: &PathBuf| {
+        return OpenOptions::new().create(true).append(true).open(path);
+    };
+    let stdout = open_log(&log_paths.standard).map_err(LockError::Io)?;
+    let stderr = open_log(&log_paths.error).map_err(LockError::Io)?;
+
+    let mut command = Command::new(exe_path);
+    command
+        .arg(AUTO_LAUNCH_PARAM)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(stdout)
+        .stderr(stderr);
+    detach(&mut command);
+
+    let mut child = command.spawn().map_err(LockError::Io)?;
+    return wait_started(meta_dir, &mut child, &log_paths.error);
+}
+
+// 起動したプロセスがPIDファイルを書き込む(= ロックを取る)か、終了するまで待つ
+// START_TIMEOUTを過ぎても動いていれば、起動したものとみなす
+fn wait_started(meta_dir: &Path, child: &mut Child, error_log: &Path) -> Result<u32, LockError> {
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_millis(START_TIMEOUT) {
+        // is_lockedはロックを一瞬取るので、起動中のプロセスのロックと被らないようにPIDファイルを見る
+        if read_pid(meta_dir) == Some(child.id()) {
+            return Ok(child.id());
+        }
+        if let Some(status) = child.try_wait().map_err(LockError::Io)? {
+            if is_locked(meta_dir) {
+                return Err(LockError::AlreadyRunning(read_pid(meta_dir)));
+            }
+            return Err(LockError::Io(std::io::Error::other(format!(
+                "exited right after starting ({}). see {}",
+                status,
+                error_log.display()
+            ))));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    return Ok(child.id());
+}
+
+// PIDファイルのプロセスを停止する
+// 停止できた(もしくは起動していなかった)場合はtrueを返す
+pub fn stop(meta_dir: &Path) -> bool {
+    if !is_locked(meta_dir) {
+        let _ = std::fs::remove_file(get_pid_filepath(meta_dir)); // 古いPIDファイルを掃除しておく
+        return true;
+    }
+
+    let pid = match read_pid(meta_dir) {
+        Some(pid) => pid,
+        None => return false,
+    };
+
+    terminate(pid);
+
+    // ロックが解放されるまで待つ
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_millis(STOP_TIMEOUT) {
+        if !is_locked(meta_dir) {
+            let _ = std::fs::remove_file(get_pid_filepath(meta_dir));
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    return false;
+}
+
+#[cfg(unix)]
+fn detach(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0); // 端末のプロセスグループから切り離す
+}
+
+#[cfg(windows)]
+fn detach(command: &mut Command) {
+    use std::os::windows::process::CommandExt;
+    const DETACHED_PROCESS: u32 = 0x00000008;
+    const CREATE_NEW_PROCESS_GROUP: u32 = 0x00000200;
+    command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+}
+
+#[cfg(unix)]
+fn terminate(pid: u32) {
+    let _ = Command::new("kill")
+        .arg("-TERM")
+        .arg(pid.to_string())
+        .output();
+}
+
+#[cfg(windows)]
+fn terminate(pid: u32) {
+    let _ = Command::new("taskkill")
+        .arg("/PID")
+        .arg(pid.to_string())
+        .arg("/F")
+        .output();
+}
+
+///// Unit Test
+
+#[cfg(test)]
+mod tests {
+    use crate::daemon::pidfile::*;
+
+    // テストごとに空のメタデータフォルダを用意する
+    fn prepare_meta_dir(name: &str) -> PathBuf {
+        let mut meta_dir = std::env::temp_dir();
+        meta_dir.push(format!("port_snippet_test_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&meta_dir);
+        std::fs::create_dir_all(&meta_dir).unwrap();
+        return meta_dir;
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn acquire_emptyDir_valid() {
+        let meta_dir = prepare_meta_dir("acquire");
+        let lock = acquire(&meta_dir);
+
+        assert!(lock.is_ok());
+        assert_eq!(read_pid(&meta_dir), Some(std::process::id()));
+        assert!(is_locked(&meta_dir));
+
+        drop(lock);
+        std::fs::remove_dir_all(&meta_dir).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn acquire_twice_invalid() {
+        let meta_dir = prepare_meta_dir("twice");
+        let lock = acquire(&meta_dir).unwrap();
+
+        match acquire(&meta_dir) {
+            Ok(_) => panic!("failed"),
+            Err(LockError::AlreadyRunning(pid)) => {
+                assert_eq!(pid, Some(std::process::id()));
+            }
+            Err(LockError::Io(e)) => panic!("{}", e),
+        }
+
+        drop(lock);
+        std::fs::remove_dir_all(&meta_dir).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn acquire_whileProbed_valid() {
+        let meta_dir = prepare_meta_dir("probed");
+
+        // is_lockedのように、別のプロセスが一瞬だけロックを取っている間に起動する
+        let probe = open_lock_file(&meta_dir).unwrap();
+        probe.try_lock().unwrap();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            drop(probe);
+        });
+
+        let lock = acquire(&meta_dir);
+        assert!(lock.is_ok());
+        assert_eq!(read_pid(&meta_dir), Some(std::process::id()));
+
+        handle.join().unwrap();
+        drop(lock);
+        std::fs::remove_dir_all(&meta_dir).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn acquire_afterDrop_valid() {
+        let meta_dir = prepare_meta_dir("drop");
+        let lock = acquire(&meta_dir).unwrap();
+        drop(lock);
+
+        assert!(!is_locked(&meta_dir));
+        assert_eq!(read_pid(&meta_dir), None);
+        assert!(acquire(&meta_dir).is_ok());
+
+        std::fs::remove_dir_all(&meta_dir).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn stop_notRunning_valid() {
+        let meta_dir = prepare_meta_dir("stop");
+        std::fs::write(meta_dir.join(PID_FILENAME), "99999999\n").unwrap(); // 古いPIDファイル
+
+        assert!(stop(&meta_dir));
+        assert_eq!(read_pid(&meta_dir), None);
+
+        std::fs::remove_dir_all(&meta_dir).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    #[cfg(unix)]
+    fn waitStarted_lockTaken_valid() {
+        let meta_dir = prepare_meta_dir("started");
+        let pid_filepath = meta_dir.join(PID_FILENAME);
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(format!("echo $$ > '{}'; sleep 5", pid_filepath.display()))
+            .spawn()
+            .unwrap();
+
+        let result = wait_started(&meta_dir, &mut child, Path::new("error.log"));
+        assert_eq!(result.ok(), Some(child.id()));
+
+        let _ = child.kill();
+        let _ = child.wait();
+        std::fs::remove_dir_all(&meta_dir).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    #[cfg(unix)]
+    fn waitStarted_exited_invalid() {
+        let meta_dir = prepare_meta_dir("exited");
+        let mut child = Command::new("sh").arg("-c").arg("exit 3").spawn().unwrap();
+
+        match wait_started(&meta_dir, &mut child, Path::new("error.log")) {
+            Err(LockError::Io(e)) => assert!(e.to_string().contains("error.log")),
+            _ => panic!("failed"),
+        }
+
+        // 他のプロセスがロックを握っていたなら、起動済みとして報告する
+        let lock = acquire(&meta_dir).unwrap();
+        let mut child = Command::new("sh").arg("-c").arg("exit 1").spawn().unwrap();
+        match wait_started(&meta_dir, &mut child, Path::new("error.log")) {
+            Err(LockError::AlreadyRunning(pid)) => assert_eq!(pid, Some(std::process::id())),
+            _ => panic!("failed"),
+        }
+
+        drop(lock);
+        std::fs::remove_dir_all(&meta_dir).unwrap();
+    }
+}
@@ -2,11 +2,12 @@ use std::ffi::OsStr;
 use std::process::Command;
 
 const EXE_VARIABLE: &str = "{{EXE_PATH}}";
+const ARGS_VARIABLE: &str = "{{ARGS}}";
 const REGISTER_ARGS: &'static [&'static str] = &[
     "create",
     "PortSnippet",
     "binPath=",
-    r#"cmd /c "{{EXE_PATH}}" AUTO_LAUNCH"#,
+    r#"cmd /c "{{EXE_PATH}}" AUTO_LAUNCH{{ARGS}}"#,
     "DisplayName=",
     r#""PortSnippet""#,
     "start=",
@@ -14,6 +15,7 @@ const REGISTER_ARGS: &'static [&'static str] = &[
 ];
 const UNREGISTER_ARGS: &'static [&'static str] = &["delete", "PortSnippet"];
 const START_ARGS: &'static [&'static str] = &["/c", "sc", "start", "PortSnippet"];
+const STOP_ARGS: &'static [&'static str] = &["stop", "PortSnippet"];
 
 // Commandに引数渡す
 fn take_args(command: &mut Command, args: Vec<&str>) {
@@ -23,12 +25,19 @@ fn take_args(command: &mut Command, args: Vec<&str>) {
     }
 }
 
-// EXE_VARIABLEを注入
-fn inject_exe_variable(args: Vec<&str>, value: String) -> Vec<String> {
+// EXE_VARIABLE / ARGS_VARIABLEを注入
+fn inject_exe_variable(args: Vec<&str>, value: String, extra_args: &[String]) -> Vec<String> {
+    let extra_args_string: String = extra_args
+        .iter()
+        .map(|arg| format!(" \"{}\"", arg))
+        .collect();
     return args
         .clone()
         .iter()
-        .map(|s| s.replace(EXE_VARIABLE, &value))
+        .map(|s| {
+            s.replace(EXE_VARIABLE, &value)
+                .replace(ARGS_VARIABLE, &extra_args_string)
+        })
         .collect::<Vec<String>>();
 }
 
@@ -39,8 +48,14 @@ fn start_process() {
     let _ = command.output();
 }
 
+// scは常に使える
+pub fn is_available() -> bool {
+    return true;
+}
+
 // Serviceとして登録する
-pub fn register(need_run: bool) {
+// argsはAUTO_LAUNCHの後ろに付ける引数 (--configなど)
+pub fn register(need_run: bool, args: &[String]) {
     if need_run {
         unregister();
     }
@@ -51,7 +66,7 @@ pub fn register(need_run: bool) {
         .into_string()
         .expect("cannot get current_exe");
 
-    let register_args = inject_exe_variable(REGISTER_ARGS.to_vec(), exe_path_string);
+    let register_args = inject_exe_variable(REGISTER_ARGS.to_vec(), exe_path_string, args);
     let register_args = register_args
         .iter()
         .map(|s| s.as_str())
@@ -76,7 +91,9 @@ pub fn register(need_run: bool) {
 }
 
 // Serviceを解除する
-fn unregister() {
+pub fn unregister() {
+    stop();
+
     let mut command = Command::new("sc");
     take_args(&mut command, UNREGISTER_ARGS.to_vec());
     let result = command.output();
@@ -92,15 +109,16 @@ fn unregister() {
     }
 }
 
-// Serviceとして登録した後にプロセスを起動する
+// 登録済みのServiceを起動する
 pub fn run() {
-    register(false);
     start_process();
 }
 
 // PortSnippetを停止する
 pub fn stop() {
-    unregister();
+    let mut command = Command::new("sc");
+    take_args(&mut command, STOP_ARGS.to_vec());
+    let _ = command.output();
 }
 
 // 完了メッセージ
@@ -1,65 +1,326 @@
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::time::{Duration, Instant};
 
-pub type SafeDebouncer = Arc<Mutex<Debouncer>>; // 非同期でdebounce
-pub fn get_safe_debouncer(interval: Duration) -> SafeDebouncer {
-    return Arc::new(Mutex::new(Debouncer::new(interval)));
+// ファイルごとに、最後の変更からquiet_period何も起きなかったら処理する (trailing edge)
+// 変更が続いても、そのファイルの最初の変更からmax_waitたったら処理する
+// 同じときに処理する時刻になったファイルはまとめて取り出す
+// (git checkoutなどで一度に変わったファイルを、言語ごとに1回の書き込みにまとめられるようにする)
+
+// 時刻の取得 (テストでは差し替える)
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        return Instant::now();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timing {
+    pub quiet_period: Duration,
+    pub max_wait: Duration,
+}
+
+// まだ処理していない変更
+struct Pending {
+    first: Instant, // 最初の変更
+    last: Instant,  // 最後の変更
+}
+
+impl Pending {
+    // 処理する時刻
+    fn deadline(&self, timing: &Timing) -> Instant {
+        let quiet = self.last + timing.quiet_period;
+        let limit = self.first + timing.max_wait;
+        return quiet.min(limit);
+    }
 }
 
-// ファイルごとにdebounce
-pub struct SafeFileDebouncer {
-    debounce_interval: u64,
-    debouncers: HashMap<String, SafeDebouncer>,
+// 変更されたファイルを溜めておき、処理する時刻になったものを取り出す
+pub struct Scheduler {
+    timing: Timing,
+    pending: HashMap<PathBuf, Pending>,
 }
 
-impl SafeFileDebouncer {
-    pub fn new(debounce_interval: u64) -> SafeFileDebouncer {
-        return SafeFileDebouncer {
-            debounce_interval: debounce_interval,
-            debouncers: HashMap::new(),
+impl Scheduler {
+    pub fn new(timing: Timing) -> Scheduler {
+        return Scheduler {
+            timing: timing,
+            pending: HashMap::new(),
         };
     }
 
-    pub fn get(&mut self, filepath: &str) -> &SafeDebouncer {
-        if !self.debouncers.contains_key(filepath) {
-            let debounce_interval = std::time::Duration::from_millis(self.debounce_interval);
-            let debouncer = get_safe_debouncer(debounce_interval);
-            self.debouncers.insert(String::from(filepath), debouncer);
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+    }
+
+    // 変更を記録する (まだ処理していないファイルなら1つにまとめる)
+    pub fn notify(&mut self, path: PathBuf, now: Instant) {
+        self.pending
+            .entry(path)
+            .and_modify(|pending| pending.last = now)
+            .or_insert(Pending {
+                first: now,
+                last: now,
+            });
+    }
+
+    // 処理する時刻になったファイルをまとめて取り出す (パス順)
+    // 変更が続いているファイルは残しておく
+    pub fn take_due(&mut self, now: Instant) -> Vec<PathBuf> {
+        let timing = self.timing;
+        let mut due: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline(&timing) <= now)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in due.iter() {
+            self.pending.remove(path);
         }
+        due.sort();
+        return due;
+    }
 
-        return &self.debouncers[filepath];
+    // 次に処理する時刻 (何も溜まっていなければNone)
+    pub fn next_deadline(&self) -> Option<Instant> {
+        return self
+            .pending
+            .values()
+            .map(|pending| pending.deadline(&self.timing))
+            .min();
     }
 }
 
-// 普通にdebounce
-pub struct Debouncer {
-    interval: Duration,
-    last_run: Option<Instant>,
+// 監視スレッドから変更を受け取り、1つのworkerスレッドでまとめて処理する
+#[derive(Clone)]
+pub struct Queue {
+    shared: Arc<(Mutex<Scheduler>, Condvar)>,
+    clock: Arc<dyn Clock>,
+    closed: Arc<AtomicBool>, // trueならworkerスレッドを終わらせる
+}
+
+impl Queue {
+    pub fn new(timing: Timing, clock: Arc<dyn Clock>) -> Queue {
+        return Queue {
+            shared: Arc::new((Mutex::new(Scheduler::new(timing)), Condvar::new())),
+            clock: clock,
+            closed: Arc::new(AtomicBool::new(false)),
+        };
+    }
+
+    pub fn push(&self, path: PathBuf) {
+        let (scheduler, condvar) = &*self.shared;
+        scheduler.lock().unwrap().notify(path, self.clock.now());
+        condvar.notify_one();
+    }
+
+    pub fn set_timing(&self, timing: Timing) {
+        let (scheduler, condvar) = &*self.shared;
+        scheduler.lock().unwrap().set_timing(timing);
+        condvar.notify_one();
+    }
+
+    // 待っているworkerスレッドを終わらせる (処理中のファイルは最後まで処理する)
+    pub fn close(&self) {
+        let (scheduler, condvar) = &*self.shared;
+        let _scheduler = scheduler.lock().unwrap();
+        self.closed.store(true, Ordering::SeqCst);
+        condvar.notify_all();
+    }
+
+    // 処理する時刻になったファイルを待って取り出す (closeされたらNone)
+    pub fn wait(&self) -> Option<Vec<PathBuf>> {
+        let (scheduler, condvar) = &*self.shared;
+        let mut scheduler = scheduler.lock().unwrap();
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                return None;
+            }
+            let now = self.clock.now();
+            let due = scheduler.take_due(now);
+            if !due.is_empty() {
+                return Some(due);
+            }
+            scheduler = match scheduler.next_deadline() {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(now);
+                    condvar.wait_timeout(scheduler, timeout).unwrap().0
+                }
+                None => condvar.wait(scheduler).unwrap(),
+            };
+        }
+    }
+
+    // workerスレッドを立てて、取り出したファイルをfで処理し続ける
+    pub fn spawn_worker<F>(&self, mut f: F) -> std::thread::JoinHandle<()>
+    where
+        F: FnMut(Vec<PathBuf>) + Send + 'static,
+    {
+        let queue = self.clone();
+        return std::thread::spawn(move || {
+            while let Some(due) = queue.wait() {
+                f(due);
+            }
+        });
+    }
 }
 
-impl Debouncer {
-    pub fn new(interval: Duration) -> Self {
-        return Debouncer {
-            interval: interval,
-            last_run: None,
+///// Unit Test
+
+#[cfg(test)]
+mod tests {
+    use crate::debounce::*;
+
+    struct MockClock {
+        now: Mutex<Instant>,
+    }
+
+    impl MockClock {
+        fn advance(&self, ms: u64) {
+            *self.now.lock().unwrap() += Duration::from_millis(ms);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            return *self.now.lock().unwrap();
+        }
+    }
+
+    fn timing() -> Timing {
+        return Timing {
+            quiet_period: Duration::from_millis(100),
+            max_wait: Duration::from_millis(1000),
+        };
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn scheduler_trailingEdge_valid() {
+        let clock = MockClock {
+            now: Mutex::new(Instant::now()),
         };
+        let mut scheduler = Scheduler::new(timing());
+        let a = PathBuf::from("/src/a.cpp");
+        let b = PathBuf::from("/src/b.cpp");
+
+        scheduler.notify(b.clone(), clock.now());
+        scheduler.notify(a.clone(), clock.now());
+        clock.advance(50);
+        assert!(scheduler.take_due(clock.now()).is_empty());
+
+        // 同じときに変更されたファイルはまとめて取り出す
+        clock.advance(50);
+        assert_eq!(scheduler.take_due(clock.now()), vec![a.clone(), b.clone()]);
+        assert_eq!(scheduler.next_deadline(), None);
+
+        scheduler.notify(a.clone(), clock.now());
+        clock.advance(50);
+        scheduler.notify(a.clone(), clock.now()); // 待ち直す
+        clock.advance(50);
+        assert!(scheduler.take_due(clock.now()).is_empty());
+        assert_eq!(
+            scheduler.next_deadline(),
+            Some(clock.now() + Duration::from_millis(50))
+        );
+
+        clock.advance(50);
+        assert_eq!(scheduler.take_due(clock.now()), vec![a.clone()]);
+        assert_eq!(scheduler.next_deadline(), None);
     }
 
-    pub fn debounce(&mut self, f: impl Fn()) -> bool {
-        if self.last_run.is_none() {
-            self.last_run = Some(Instant::now());
-            f();
-            return true;
+    #[test]
+    #[allow(non_snake_case)]
+    fn scheduler_busyFile_quietFileNotDelayed() {
+        let clock = MockClock {
+            now: Mutex::new(Instant::now()),
+        };
+        let mut scheduler = Scheduler::new(timing());
+        let busy = PathBuf::from("/src/busy.cpp");
+        let quiet = PathBuf::from("/src/quiet.cpp");
+
+        scheduler.notify(busy.clone(), clock.now());
+        scheduler.notify(quiet.clone(), clock.now());
+
+        // busyが変更され続けていても、quietは自分のquiet_periodで取り出す
+        clock.advance(50);
+        scheduler.notify(busy.clone(), clock.now());
+        assert!(scheduler.take_due(clock.now()).is_empty());
+        clock.advance(50);
+        scheduler.notify(busy.clone(), clock.now());
+        assert_eq!(scheduler.take_due(clock.now()), vec![quiet.clone()]);
+
+        // busyはmax_waitまで待たされる
+        for _ in 0..17 {
+            clock.advance(50);
+            scheduler.notify(busy.clone(), clock.now());
+            assert!(scheduler.take_due(clock.now()).is_empty());
         }
-        let then = self.last_run.unwrap();
-        let now = Instant::now();
+        clock.advance(50);
+        assert_eq!(scheduler.take_due(clock.now()), vec![busy.clone()]);
+        assert_eq!(scheduler.next_deadline(), None);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn scheduler_maxWait_valid() {
+        let clock = MockClock {
+            now: Mutex::new(Instant::now()),
+        };
+        let mut scheduler = Scheduler::new(timing());
+        let a = PathBuf::from("/src/a.cpp");
 
-        if now.duration_since(then) > self.interval {
-            self.last_run = Some(Instant::now());
-            f();
-            return true;
+        // quiet_periodより短い間隔で変更し続けても、max_waitで処理する
+        for _ in 0..11 {
+            scheduler.notify(a.clone(), clock.now());
+            clock.advance(90);
+            assert!(scheduler.take_due(clock.now()).is_empty());
         }
-        return false;
+        scheduler.notify(a.clone(), clock.now());
+        clock.advance(90);
+        assert_eq!(scheduler.take_due(clock.now()), vec![a.clone()]);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn queue_coalesced_valid() {
+        let clock = Arc::new(MockClock {
+            now: Mutex::new(Instant::now()),
+        });
+        let queue = Queue::new(timing(), clock.clone());
+        let a = PathBuf::from("/src/a.cpp");
+
+        queue.push(a.clone());
+        queue.push(a.clone());
+        clock.advance(100);
+        assert_eq!(queue.wait(), Some(vec![a.clone()]));
+
+        queue.push(a.clone());
+        clock.advance(100);
+        assert_eq!(queue.wait(), Some(vec![a]));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn queue_close_stopsWorker() {
+        let queue = Queue::new(timing(), Arc::new(SystemClock));
+        let (tx, rx) = std::sync::mpsc::channel();
+        let worker = queue.spawn_worker(move |due| tx.send(due).unwrap());
+
+        let a = PathBuf::from("/src/a.cpp");
+        queue.push(a.clone());
+        assert_eq!(rx.recv().unwrap(), vec![a]);
+
+        queue.close();
+        worker.join().unwrap();
+        assert!(queue.wait().is_none());
     }
 }
@@ -0,0 +1,156 @@
+// メタタグの問題を、ファイル・行・列つきで表す
+// checkコマンドやbuildコマンドのエラー表示に使う
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,   // スニペットを生成できない
+    Warning, // 生成はできるが、おそらく意図した結果にならない
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        return match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        };
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub path: String,
+    pub line: usize,   // 1始まり
+    pub column: usize, // 1始まり (文字単位)
+    pub tag: String,   // 問題のあるタグ・キー (#PORT#, name など)
+    pub message: String,
+    #[serde(skip)]
+    pub width: usize, // 印を付ける幅 (文字単位)
+    #[serde(skip)]
+    pub source: String, // 該当行 (表示用)
+}
+
+impl Diagnostic {
+    pub fn is_error(&self) -> bool {
+        return self.severity == Severity::Error;
+    }
+}
+
+// checkコマンドの出力 (--json)
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub files: usize,
+    pub errors: usize,
+    pub warnings: usize,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Report {
+    pub fn new(files: usize, diagnostics: Vec<Diagnostic>) -> Self {
+        let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+        return Report {
+            files: files,
+            errors: errors,
+            warnings: diagnostics.len() - errors,
+            diagnostics: diagnostics,
+        };
+    }
+}
+
+// "path:line:column: message" の1行に整形する (ログ・status用)
+pub fn format_short(diagnostic: &Diagnostic) -> String {
+    return format!(
+        "{}:{}:{}: {}",
+        diagnostic.path, diagnostic.line, diagnostic.column, diagnostic.message
+    );
+}
+
+// 該当行を添えて整形する
+//
+// error: `#PORT#` block is not closed
+//  --> /src/seg.cpp:3:4
+//   |
+// 3 | // #PORT#
+//   |    ^^^^^^
+pub fn format(diagnostic: &Diagnostic) -> String {
+    let line_number = diagnostic.line.to_string();
+    let gutter = " ".repeat(line_number.len());
+    let source = diagnostic.source.trim_end();
+    let marker_width = std::cmp::max(diagnostic.width, 1);
+
+    let mut text = format!("{}: {}\n", diagnostic.severity, diagnostic.message);
+    text.push_str(&format!(
+        "{}--> {}:{}:{}\n",
+        gutter, diagnostic.path, diagnostic.line, diagnostic.column
+    ));
+    text.push_str(&format!("{} |\n", gutter));
+    text.push_str(&format!("{} | {}\n", line_number, source));
+    text.push_str(&format!(
+        "{} | {}{}\n",
+        gutter,
+        " ".repeat(diagnostic.column.saturating_sub(1)),
+        "^".repeat(marker_width)
+    ));
+    return text;
+}
+
+///// Unit Test
+
+#[cfg(test)]
+mod tests {
+    use crate::diagnostic::*;
+
+    fn mock_diagnostic(severity: Severity) -> Diagnostic {
+        return Diagnostic {
+            severity: severity,
+            path: String::from("/src/seg.cpp"),
+            line: 3,
+            column: 4,
+            tag: String::from("#PORT#"),
+            message: String::from("`#PORT#` block is not closed"),
+            width: 6,
+            source: String::from("// #PORT#\n"),
+        };
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn format_error_excerpt() {
+        let text = format(&mock_diagnostic(Severity::Error));
+        let expected = "error: `#PORT#` block is not closed
+ --> /src/seg.cpp:3:4
+  |
+3 | // #PORT#
+  |    ^^^^^^
+";
+        assert_eq!(text, expected);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn formatShort_valid() {
+        let text = format_short(&mock_diagnostic(Severity::Warning));
+        assert_eq!(text, "/src/seg.cpp:3:4: `#PORT#` block is not closed");
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn report_json_noSource() {
+        let report = Report::new(
+            2,
+            vec![
+                mock_diagnostic(Severity::Error),
+                mock_diagnostic(Severity::Warning),
+            ],
+        );
+        assert_eq!(report.errors, 1);
+        assert_eq!(report.warnings, 1);
+
+        let json = serde_json::to_string(&report.diagnostics[1]).unwrap();
+        assert_eq!(
+            json,
+            r##"{"severity":"warning","path":"/src/seg.cpp","line":3,"column":4,"tag":"#PORT#","message":"`#PORT#` block is not closed"}"##
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use super::filter::FileFilter;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufReader;
@@ -37,6 +38,27 @@ impl Reader for FileReader {
     }
 }
 
+// 読み込み済みの文字列を読むReader (ファイルを作らずに済ませたいとき用)
+pub struct TextReader {
+    text: String,
+}
+
+impl TextReader {
+    pub fn new(text: String) -> TextReader {
+        return TextReader { text: text };
+    }
+}
+
+impl Reader for TextReader {
+    fn lines(&self) -> Vec<String> {
+        return self.text.lines().map(|line| line.to_string()).collect();
+    }
+
+    fn all(&mut self) -> String {
+        return self.text.clone();
+    }
+}
+
 pub fn open_file(path: &std::path::PathBuf, create: bool, should_panic: bool) -> Option<File> {
     let file = match std::fs::OpenOptions::new()
         .create(create)
@@ -47,7 +69,7 @@ pub fn open_file(path: &std::path::PathBuf, create: bool, should_panic: bool) ->
         Ok(f) => f,
         Err(e) => {
             if should_panic {
-                panic!(format!("something went wrong: {}", e));
+                panic!("something went wrong: {}", e);
             } else {
                 return None;
             }
@@ -57,34 +79,112 @@ pub fn open_file(path: &std::path::PathBuf, create: bool, should_panic: bool) ->
     return Some(file);
 }
 
-pub fn read_file(path: &std::path::PathBuf) -> String {
-    let mut contents = String::new();
-    let mut file = match File::open(path) {
-        Ok(f) => f,
-        Err(_) => {
-            panic!(format!("file not found. Check {}", path.to_str().unwrap()));
-        }
-    };
+// ファイルを丸ごと書き換える
+// 同じフォルダの一時ファイルに書いてfsyncしてからrenameするので、途中で落ちても半端なファイルは残らない
+// シンボリックリンクならリンク先を書き換え、元のファイルのパーミッションを引き継ぐ
+pub fn write_file(path: &std::path::PathBuf, text: String) -> std::io::Result<()> {
+    let target = std::fs::canonicalize(path).unwrap_or_else(|_| path.clone()); // まだなければそのまま
+    let permissions = std::fs::metadata(&target).ok().map(|m| m.permissions());
+    let temp_filepath = get_temp_filepath(&target);
+    let result = write_temp_file(&temp_filepath, &text)
+        .and_then(|_| match permissions {
+            Some(permissions) => std::fs::set_permissions(&temp_filepath, permissions),
+            None => Ok(()),
+        })
+        .and_then(|_| std::fs::rename(&temp_filepath, &target));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_filepath);
+    }
+    return result;
+}
 
-    file.read_to_string(&mut contents)
-        .expect("something went wrong reading the config file");
-    return contents;
+// 書き込み途中の一時ファイル (.cpp.json.<pid>.tmp)
+fn get_temp_filepath(path: &std::path::Path) -> std::path::PathBuf {
+    let filename = match path.file_name() {
+        Some(filename) => filename.to_string_lossy().to_string(),
+        None => String::from("port_snippet"),
+    };
+    return path.with_file_name(format!(".{}.{}.tmp", filename, std::process::id()));
 }
 
-pub fn write_file(path: &std::path::PathBuf, text: String) {
-    let file = match std::fs::OpenOptions::new()
+fn write_temp_file(temp_filepath: &std::path::Path, text: &str) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
         .create(true)
         .truncate(true)
         .write(true)
-        .open(path)
-    {
-        Ok(f) => f,
-        Err(_) => {
-            panic!(format!("file not found. Check {}", path.to_str().unwrap()));
-        }
+        .open(temp_filepath)?;
+    file.write_all(text.as_bytes())?;
+    file.sync_all()?;
+    return Ok(());
+}
+
+// 他のPortSnippetと共有するアドバイザリロック
+// dropされるとロックを解放する
+pub struct FileLock {
+    _file: File,
+}
+
+// ロックファイルを排他ロックする (他のプロセスが握っている間は待つ)
+pub fn lock_file(lock_filepath: &std::path::Path) -> std::io::Result<FileLock> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_filepath)?;
+    file.lock()?;
+    return Ok(FileLock { _file: file });
+}
+
+// dirs / files に含まれるファイルを列挙する (フォルダは再帰的に辿る)
+// filesに指定したファイルがdirsの下にもある場合などは、最初の1つだけを残す
+pub fn collect_files(paths: &[String], filter: &FileFilter) -> Vec<std::path::PathBuf> {
+    let mut files = vec![];
+    for path in paths {
+        collect_files_in(std::path::Path::new(path), filter, &mut files);
+    }
+    let mut seen = std::collections::HashSet::new();
+    files.retain(|file| seen.insert(file.clone()));
+    return files;
+}
+
+fn collect_files_in(
+    path: &std::path::Path,
+    filter: &FileFilter,
+    files: &mut Vec<std::path::PathBuf>,
+) {
+    if !filter.is_allowed(path) {
+        return; // 対象外のフォルダの下は辿らない
+    }
+    if path.is_file() {
+        files.push(path.to_path_buf());
+        return;
+    }
+
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return,
     };
+    let mut children: Vec<std::path::PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .collect();
+    children.sort(); // 毎回同じ順番にする
+    for child in children {
+        collect_files_in(&child, filter, files);
+    }
+}
 
-    write!(&file, "{}", text);
+// 監視対象のパスの書き方 (configに書かれたまま・絶対パス・シンボリックリンクを辿ったもの)
+// イベントのパスは絶対パスで、macOSではシンボリックリンクも辿られているので、どれかと比べる
+pub fn path_forms(path: &str) -> Vec<std::path::PathBuf> {
+    let mut forms = vec![std::path::PathBuf::from(path)];
+    let resolved = vec![std::path::absolute(path), std::fs::canonicalize(path)];
+    for form in resolved.into_iter().flatten() {
+        if !forms.contains(&form) {
+            forms.push(form);
+        }
+    }
+    return forms;
 }
 
 pub fn get_extension(path: &std::path::PathBuf) -> Option<String> {
@@ -106,3 +206,80 @@ pub fn get_extension(path: &std::path::PathBuf) -> Option<String> {
 
     return None;
 }
+
+///// Unit Test
+
+#[cfg(test)]
+mod tests {
+    use crate::file::*;
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn collectFiles_sortedAndFiltered() {
+        let mut root = std::env::temp_dir();
+        root.push(format!("port_snippet_collect_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        for dir in ["b", "a/c", "target"].iter() {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in ["b/x.cpp", "a/c/z.cpp", "a/y.cpp", "target/w.cpp"].iter() {
+            std::fs::write(root.join(file), "").unwrap();
+        }
+
+        let root_string = root.to_string_lossy().to_string();
+        let file_string = root.join("b/x.cpp").to_string_lossy().to_string();
+        let paths = vec![root_string.clone(), file_string.clone()];
+        let filter = FileFilter::new(
+            &[root_string],
+            &[file_string],
+            &[],
+            &[String::from("target")],
+            false,
+            None,
+        )
+        .unwrap();
+
+        let files = collect_files(&paths, &filter);
+        let expected: Vec<std::path::PathBuf> = ["a/c/z.cpp", "a/y.cpp", "b/x.cpp"]
+            .iter()
+            .map(|file| root.join(file))
+            .collect();
+        assert_eq!(files, expected);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    #[cfg(unix)]
+    fn writeFile_symlink_keepsLinkAndPermissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut root = std::env::temp_dir();
+        root.push(format!("port_snippet_write_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("dotfiles")).unwrap();
+        std::fs::create_dir_all(root.join("snippets")).unwrap();
+
+        // snippets/cpp.json -> dotfiles/cpp.json
+        let target = root.join("dotfiles/cpp.json");
+        let link = root.join("snippets/cpp.json");
+        std::fs::write(&target, "{}").unwrap();
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o600)).unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        write_file(&link, String::from("{\"a\": 1}")).unwrap();
+        assert!(std::fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "{\"a\": 1}");
+        let mode = std::fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // 一時ファイルはリンク先の隣に作って、残さない
+        assert_eq!(std::fs::read_dir(root.join("dotfiles")).unwrap().count(), 1);
+        assert_eq!(std::fs::read_dir(root.join("snippets")).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,353 @@
+use super::file;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// 監視しているフォルダのうち、どのファイルを対象にするかを決める
+// include / exclude のglobはdirsのフォルダからの相対パスで判定する
+//   "/" を含まないパターン (*.swp, target など) はどの深さのファイル名・フォルダ名にも一致する
+//   フォルダに一致した場合は、その下のファイルすべてに一致したものとして扱う
+// filesで指定したファイルは、ファイルの大きさだけを見る
+// .git はexcludeに書かなくても対象にしない
+
+const IGNORE_FILENAMES: &[&str] = &[".gitignore", ".ignore"]; // 後のものほど優先する
+const BUILTIN_EXCLUDES: &[&str] = &[".git"];
+
+pub struct FileFilter {
+    dirs: Vec<PathBuf>,       // dirsのそれぞれの書き方 (file::path_forms)
+    files: Vec<PathBuf>,      // filesのそれぞれの書き方
+    include: Option<GlobSet>, // Noneならすべて対象にする
+    exclude: GlobSet,
+    use_gitignore: bool,
+    max_file_size: Option<u64>,                          // byte
+    ignores: Mutex<HashMap<PathBuf, Option<Gitignore>>>, // フォルダごとの .gitignore / .ignore (なければNone)
+}
+
+impl FileFilter {
+    pub fn new(
+        dirs: &[String],
+        files: &[String],
+        include: &[String],
+        exclude: &[String],
+        use_gitignore: bool,
+        max_file_size: Option<u64>,
+    ) -> Result<FileFilter, String> {
+        let include = if include.is_empty() {
+            None
+        } else {
+            Some(build_globset(include)?)
+        };
+        let exclude: Vec<String> = BUILTIN_EXCLUDES
+            .iter()
+            .map(|pattern| pattern.to_string())
+            .chain(exclude.iter().cloned())
+            .collect();
+        return Ok(FileFilter {
+            dirs: dirs.iter().flat_map(|dir| file::path_forms(dir)).collect(),
+            files: files.iter().flat_map(|f| file::path_forms(f)).collect(),
+            include: include,
+            exclude: build_globset(&exclude)?,
+            use_gitignore: use_gitignore,
+            max_file_size: max_file_size,
+            ignores: Mutex::new(HashMap::new()),
+        });
+    }
+
+    // .gitignore / .ignore が変更されたら、次に使うときに読み直す
+    pub fn forget_ignore(&self, path: &Path) {
+        let is_ignore_file = path
+            .file_name()
+            .is_some_and(|name| IGNORE_FILENAMES.iter().any(|filename| name == *filename));
+        if let (true, Some(dir)) = (is_ignore_file, path.parent()) {
+            self.ignores.lock().unwrap().remove(dir);
+        }
+    }
+
+    // pathを対象にするか (フォルダなら、その下を辿るか)
+    pub fn is_allowed(&self, path: &Path) -> bool {
+        if self.files.iter().any(|file| file == path) {
+            return self.is_small_enough(path);
+        }
+
+        let root = match self.dirs.iter().find(|dir| path.starts_with(dir)) {
+            Some(root) => root,
+            None => return self.is_small_enough(path), // 監視対象外 (呼び出し元で弾く)
+        };
+        let relative = match path.strip_prefix(root) {
+            Ok(relative) if relative.as_os_str().is_empty() => return true, // dirsのフォルダそのもの
+            Ok(relative) => relative,
+            Err(_) => return false,
+        };
+
+        let is_dir = path.is_dir();
+        if matches_any_ancestor(&self.exclude, relative) {
+            verbose!("skip {} (excluded)", path.display());
+            return false;
+        }
+        if let Some(include) = &self.include {
+            // フォルダは、その下に一致するファイルがあるかもしれないので辿る
+            if !is_dir && !matches_any_ancestor(include, relative) {
+                verbose!("skip {} (not included)", path.display());
+                return false;
+            }
+        }
+        if self.use_gitignore && self.is_ignored(root, path, is_dir) {
+            verbose!("skip {} (ignored)", path.display());
+            return false;
+        }
+        return is_dir || self.is_small_enough(path);
+    }
+
+    // rootからpathまでのフォルダにある .gitignore / .ignore で無視されているか
+    // 深いフォルダのものほど優先する (読み込んだものはフォルダごとに使い回す)
+    fn is_ignored(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        let parent = match path.parent() {
+            Some(parent) => parent,
+            None => return false,
+        };
+        let mut ignores = self.ignores.lock().unwrap();
+        for dir in parent.ancestors().take_while(|dir| dir.starts_with(root)) {
+            let gitignore = ignores
+                .entry(dir.to_path_buf())
+                .or_insert_with(|| load_ignore(dir));
+            let gitignore = match gitignore {
+                Some(gitignore) => gitignore,
+                None => continue,
+            };
+            let matched = gitignore.matched_path_or_any_parents(path, is_dir);
+            if matched.is_ignore() {
+                return true;
+            }
+            if matched.is_whitelist() {
+                return false;
+            }
+        }
+        return false;
+    }
+
+    fn is_small_enough(&self, path: &Path) -> bool {
+        let max_file_size = match self.max_file_size {
+            Some(max_file_size) => max_file_size,
+            None => return true,
+        };
+        let size = match std::fs::metadata(path) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return true, // 読み込むときに失敗する
+        };
+        if size > max_file_size {
+            verbose!(
+                "skip {} ({} bytes > max_file_size {})",
+                path.display(),
+                size,
+                max_file_size
+            );
+            return false;
+        }
+        return true;
+    }
+}
+
+fn build_globset(patterns: &[String]) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let trimmed = pattern.trim_end_matches('/');
+        let normalized = match trimmed.strip_prefix('/') {
+            Some(anchored) => anchored.to_string(),
+            None if !trimmed.contains('/') => format!("**/{}", trimmed),
+            None => trimmed.to_string(),
+        };
+        let glob = GlobBuilder::new(&normalized)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| format!("invalid pattern \"{}\": {}", pattern, e))?;
+        builder.add(glob);
+    }
+    return builder
+        .build()
+        .map_err(|e| format!("invalid patterns: {}", e));
+}
+
+// relativeか、その親フォルダのどれかが一致するか
+fn matches_any_ancestor(globset: &GlobSet, relative: &Path) -> bool {
+    return relative
+        .ancestors()
+        .filter(|ancestor| !ancestor.as_os_str().is_empty())
+        .any(|ancestor| globset.is_match(ancestor));
+}
+
+// dirにある .gitignore / .ignore を読み込む (どちらもなければNone)
+fn load_ignore(dir: &Path) -> Option<Gitignore> {
+    let mut builder = GitignoreBuilder::new(dir);
+    let mut found = false;
+    for filename in IGNORE_FILENAMES {
+        let ignore_filepath = dir.join(filename);
+        if ignore_filepath.is_file() {
+            found = true;
+            if let Some(e) = builder.add(&ignore_filepath) {
+                println!("cannot read {}: {}", ignore_filepath.display(), e);
+            }
+        }
+    }
+    if !found {
+        return None;
+    }
+    return builder.build().ok();
+}
+
+///// Unit Test
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::*;
+
+    // root/src/a.cpp, root/src/a.cpp.swp, root/target/b.cpp, root/big.cpp
+    fn prepare_root(name: &str) -> PathBuf {
+        let mut root = std::env::temp_dir();
+        root.push(format!(
+            "port_snippet_filter_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir_all(root.join("target")).unwrap();
+        std::fs::write(root.join("src/a.cpp"), "a").unwrap();
+        std::fs::write(root.join("src/a.cpp.swp"), "a").unwrap();
+        std::fs::write(root.join("target/b.cpp"), "b").unwrap();
+        std::fs::write(root.join("big.cpp"), "0123456789").unwrap();
+        return root;
+    }
+
+    fn new_filter(root: &Path, include: &[&str], exclude: &[&str], gitignore: bool) -> FileFilter {
+        let to_strings = |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect();
+        let include: Vec<String> = to_strings(include);
+        let exclude: Vec<String> = to_strings(exclude);
+        return FileFilter::new(
+            &[root.to_string_lossy().to_string()],
+            &[],
+            &include,
+            &exclude,
+            gitignore,
+            Some(5),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn isAllowed_globs_valid() {
+        let root = prepare_root("globs");
+        let filter = new_filter(&root, &["*.cpp"], &["target", "*.swp"], false);
+
+        assert!(filter.is_allowed(&root.join("src")));
+        assert!(filter.is_allowed(&root.join("src/a.cpp")));
+        assert!(!filter.is_allowed(&root.join("src/a.cpp.swp")));
+        assert!(!filter.is_allowed(&root.join("target")));
+        assert!(!filter.is_allowed(&root.join("target/b.cpp")));
+        assert!(!filter.is_allowed(&root.join("big.cpp"))); // max_file_size
+
+        let filter = new_filter(&root, &["/src"], &[], false);
+        assert!(filter.is_allowed(&root.join("src/a.cpp")));
+        assert!(!filter.is_allowed(&root.join("target/b.cpp")));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn isAllowed_gitignore_valid() {
+        let root = prepare_root("gitignore");
+        std::fs::write(root.join(".gitignore"), "target/\n*.swp\n").unwrap();
+        std::fs::write(root.join("src/.ignore"), "a.cpp\n").unwrap();
+
+        let filter = new_filter(&root, &[], &[], false);
+        assert!(filter.is_allowed(&root.join("target/b.cpp")));
+
+        let filter = new_filter(&root, &[], &[], true);
+        assert!(!filter.is_allowed(&root.join("target/b.cpp")));
+        assert!(!filter.is_allowed(&root.join("src/a.cpp.swp")));
+        assert!(!filter.is_allowed(&root.join("src/a.cpp")));
+
+        // 深いフォルダで打ち消す (変更を知らされるまでは、読み込んだものを使う)
+        std::fs::write(root.join("src/.ignore"), "!*.swp\n").unwrap();
+        assert!(!filter.is_allowed(&root.join("src/a.cpp.swp")));
+        filter.forget_ignore(&root.join("src/.ignore"));
+        assert!(filter.is_allowed(&root.join("src/a.cpp.swp")));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn isAllowed_gitDir_excluded() {
+        let root = prepare_root("gitdir");
+        std::fs::create_dir_all(root.join(".git/objects")).unwrap();
+        std::fs::write(root.join(".git/objects/c.cpp"), "c").unwrap();
+
+        let filter = new_filter(&root, &[], &[], false);
+        assert!(!filter.is_allowed(&root.join(".git")));
+        assert!(!filter.is_allowed(&root.join(".git/objects/c.cpp")));
+        assert!(filter.is_allowed(&root.join("src/a.cpp")));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn isAllowed_relativeDir_valid() {
+        // cargo testはクレートのフォルダで動くので、targetの下を相対パスで指定する
+        let relative = format!("target/port_snippet_filter_relative_{}", std::process::id());
+        let _ = std::fs::remove_dir_all(&relative);
+        std::fs::create_dir_all(format!("{}/src", relative)).unwrap();
+        std::fs::write(format!("{}/src/a.cpp", relative), "a").unwrap();
+        std::fs::write(format!("{}/src/a.cpp.swp", relative), "a").unwrap();
+
+        let filter = FileFilter::new(
+            &[format!("./{}", relative)],
+            &[],
+            &[],
+            &[String::from("*.swp")],
+            false,
+            None,
+        )
+        .unwrap();
+        // イベントのパスは絶対パス
+        let root = std::fs::canonicalize(&relative).unwrap();
+        assert!(filter.is_allowed(&root.join("src/a.cpp")));
+        assert!(!filter.is_allowed(&root.join("src/a.cpp.swp")));
+        // 走査するときは、configに書かれたままのパスの下を辿る
+        let dir = format!("./{}", relative);
+        assert!(!filter.is_allowed(&Path::new(&dir).join("src/a.cpp.swp")));
+
+        std::fs::remove_dir_all(&relative).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[allow(non_snake_case)]
+    fn isAllowed_symlinkedDir_valid() {
+        let root = prepare_root("symlink");
+        let link = root.with_extension("link");
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(&root, &link).unwrap();
+
+        // シンボリックリンクで指定しても、辿った先のパスで判定できる
+        let filter = new_filter(&link, &[], &["*.swp"], false);
+        let root = std::fs::canonicalize(&root).unwrap();
+        assert!(filter.is_allowed(&root.join("src/a.cpp")));
+        assert!(!filter.is_allowed(&root.join("src/a.cpp.swp")));
+        assert!(!filter.is_allowed(&link.join("src/a.cpp.swp")));
+
+        std::fs::remove_file(&link).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn new_invalidPattern_error() {
+        let result = FileFilter::new(&[], &[], &[String::from("[")], &[], false, None);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,516 @@
+// #PORT# から本文までの間 (ヘッダ) の1行を読む
+//
+//   // #PORT#
+//   // name: "SegmentTree"              key: value の行
+//   // prefix: ["seg", "segtree"]       値は "文字列"、'文字列'、["文字列", ...]、true / false、
+//   // author: YuigaWada                 または引用符のない値 (行末まで)
+//   // description: "長い説明は"
+//   //     "次の行に続けて書ける"       文字列だけの行は、直前の文字列の値の続き
+//   //                                  空行・コメント記号だけの行
+//   // #PORT_BODY#                      (省略可) ここまでがヘッダ
+//   ...
+//   // #PORT_END#
+//
+// キーの前にはコメント記号 (//, #, --, /*, <!-- など) を書ける
+// 引用符で囲んだ文字列は、JSONと同じエスケープ (\" \\ \n \t \uXXXX など) を解釈する ('...' では \' も使える)
+// どのキーをどう扱うかは呼び出し元 (snippet) で決める
+
+pub const BODY_TAG: &str = "#PORT_BODY#";
+
+// 値のあとに書けるコメントの終わり
+const TRAILERS: &[&str] = &["*/", "-->"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Empty,        // key: だけ (続きの行に値を書く)
+    Text(String), // 引用符で囲んだ文字列
+    Bare(String), // 引用符のない値
+    List(Vec<String>),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line {
+    Meta {
+        key: String,
+        column: usize, // キーの列 (1始まり、文字単位)
+        value: Value,
+    },
+    Continuation(String), // 文字列だけの行
+    Blank,                // 空行、コメント記号だけの行
+    BodyTag,              // #PORT_BODY#
+    Other,                // ヘッダの行ではない
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineError {
+    pub key: String,   // 文字列だけの行なら空
+    pub column: usize, // 問題のある箇所の列 (1始まり、文字単位)
+    pub width: usize,  // 問題のある箇所の幅 (文字単位)
+    pub message: String,
+}
+
+pub fn parse_line(line: &str) -> Result<Line, LineError> {
+    let line = line.trim_end();
+    if line.contains(BODY_TAG) {
+        return Ok(Line::BodyTag);
+    }
+    if line.chars().all(is_comment_char) {
+        return Ok(Line::Blank);
+    }
+
+    let mut parser = Parser::new(line);
+    parser.skip_leader();
+    match parser.peek() {
+        Some('"') | Some('\'') => {
+            // 後ろに何か続く行は、本文のコードとして扱う
+            return match parser.parse_string() {
+                Ok(text) if parser.at_trailer() => Ok(Line::Continuation(text)),
+                Ok(_) => Ok(Line::Other),
+                Err(e) => Err(e.with_key("")),
+            };
+        }
+        Some(c) if c.is_ascii_alphabetic() => {}
+        _ => return Ok(Line::Other),
+    }
+
+    let column = parser.column();
+    let key = parser.parse_key();
+    parser.skip_whitespace();
+    if parser.peek() != Some(':') {
+        return Ok(Line::Other);
+    }
+    parser.bump();
+    parser.skip_whitespace();
+
+    return match parser.parse_value() {
+        Ok(value) => Ok(Line::Meta {
+            key: key,
+            column: column,
+            value: value,
+        }),
+        Err(e) => Err(e.with_key(&key)),
+    };
+}
+
+fn is_comment_char(c: char) -> bool {
+    return c.is_whitespace() || "/#*;%<>!-".contains(c);
+}
+
+// 値の問題 (キーはparse_lineで付ける)
+struct ValueError {
+    column: usize,
+    width: usize,
+    message: String,
+}
+
+impl ValueError {
+    fn with_key(self, key: &str) -> LineError {
+        return LineError {
+            key: key.to_string(),
+            column: self.column,
+            width: self.width,
+            message: self.message,
+        };
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        return Parser { text: text, pos: 0 };
+    }
+
+    fn peek(&self) -> Option<char> {
+        return self.text[self.pos..].chars().next();
+    }
+
+    // 1文字進める
+    fn bump(&mut self) {
+        self.pos += self.peek().map_or(1, |c| c.len_utf8());
+    }
+
+    fn starts_with(&self, pattern: &str) -> bool {
+        return self.text[self.pos..].starts_with(pattern);
+    }
+
+    fn column(&self) -> usize {
+        return self.text[..self.pos].chars().count() + 1;
+    }
+
+    // columnから今の位置までの問題
+    fn error_since(&self, column: usize, message: String) -> ValueError {
+        return ValueError {
+            column: column,
+            width: std::cmp::max(self.column() - column, 1),
+            message: message,
+        };
+    }
+
+    // columnから今の位置までの文字列
+    fn text_since(&self, column: usize) -> String {
+        return self
+            .text
+            .chars()
+            .skip(column - 1)
+            .take(self.column() - column)
+            .collect();
+    }
+
+    fn eat_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let start = self.pos;
+        while self.peek().is_some_and(&f) {
+            self.bump();
+        }
+        return &self.text[start..self.pos];
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.eat_while(char::is_whitespace);
+    }
+
+    // キーの前のコメント記号
+    fn skip_leader(&mut self) {
+        self.eat_while(|c| !c.is_alphanumeric() && c != '"' && c != '\'');
+    }
+
+    // 値のあとに残っているもの (空白とコメントの終わりを除く)
+    fn rest(&self) -> &'a str {
+        let rest = self.text[self.pos..].trim();
+        return match TRAILERS.iter().find(|trailer| rest.ends_with(*trailer)) {
+            Some(trailer) => rest[..rest.len() - trailer.len()].trim_end(),
+            None => rest,
+        };
+    }
+
+    fn at_trailer(&self) -> bool {
+        return self.rest().is_empty();
+    }
+
+    fn parse_key(&mut self) -> String {
+        return self
+            .eat_while(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            .to_string();
+    }
+
+    fn parse_value(&mut self) -> Result<Value, ValueError> {
+        let value = match self.peek() {
+            Some('"') | Some('\'') => Value::Text(self.parse_string()?),
+            Some('[') => Value::List(self.parse_list()?),
+            _ => {
+                let rest = self.rest();
+                self.pos = self.text.len();
+                return Ok(match rest {
+                    "" => Value::Empty,
+                    "true" => Value::Bool(true),
+                    "false" => Value::Bool(false),
+                    _ => Value::Bare(rest.to_string()),
+                });
+            }
+        };
+
+        self.skip_whitespace();
+        if !self.at_trailer() {
+            let column = self.column();
+            let message = match &value {
+                Value::Text(_) => {
+                    "unexpected text after the string; escape quotes inside it with `\\`"
+                }
+                _ => "unexpected text after the list",
+            };
+            return Err(ValueError {
+                column: column,
+                width: self.rest().chars().count(),
+                message: message.to_string(),
+            });
+        }
+        return Ok(value);
+    }
+
+    // "..." / '...' (閉じる引用符のあとまで進める)
+    fn parse_string(&mut self) -> Result<String, ValueError> {
+        let start = self.column();
+        let quote = self.peek().unwrap_or('"');
+        self.bump();
+
+        let mut text = String::new();
+        loop {
+            let column = self.column();
+            match self.peek() {
+                Some(c) if c == quote => break,
+                Some('\\') => {
+                    self.bump();
+                    if self.peek().is_none() {
+                        break; // 閉じていない
+                    }
+                    text.push(self.parse_escape(column)?);
+                }
+                Some(c) => {
+                    text.push(c);
+                    self.bump();
+                }
+                None => break,
+            }
+        }
+
+        if self.peek().is_none() {
+            return Err(ValueError {
+                column: start,
+                width: 1,
+                message: format!("string is not closed; add `{}` at the end", quote),
+            });
+        }
+        self.bump();
+        return Ok(text);
+    }
+
+    // \ の次の文字から読む (columnは \ の列)
+    fn parse_escape(&mut self, column: usize) -> Result<char, ValueError> {
+        let c = self.peek().unwrap_or('\\');
+        self.bump();
+        return match c {
+            '"' | '\'' | '\\' | '/' => Ok(c),
+            'b' => Ok('\u{8}'),
+            'f' => Ok('\u{c}'),
+            'n' => Ok('\n'),
+            'r' => Ok('\r'),
+            't' => Ok('\t'),
+            'u' => self.parse_unicode(column),
+            _ => Err(self.error_since(
+                column,
+                format!("invalid escape `\\{}`; write `\\\\` for a backslash", c),
+            )),
+        };
+    }
+
+    // \uXXXX (サロゲートペアは \uXXXX\uXXXX で1文字)
+    fn parse_unicode(&mut self, column: usize) -> Result<char, ValueError> {
+        let high = self.parse_hex(column)?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            if !self.starts_with("\\u") {
+                return Err(self.unicode_error(column));
+            }
+            self.bump();
+            self.bump();
+            let low = self.parse_hex(column)?;
+            if !(0xDC00..0xE000).contains(&low) {
+                return Err(self.unicode_error(column));
+            }
+            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+        } else {
+            high
+        };
+        return char::from_u32(code).ok_or_else(|| self.unicode_error(column));
+    }
+
+    // 16進数4桁
+    fn parse_hex(&mut self, column: usize) -> Result<u32, ValueError> {
+        let digits: String = self.text[self.pos..]
+            .chars()
+            .take(4)
+            .take_while(|c| c.is_ascii_hexdigit())
+            .collect();
+        self.pos += digits.len();
+        if digits.len() != 4 {
+            return Err(self.unicode_error(column));
+        }
+        return Ok(u32::from_str_radix(&digits, 16).unwrap_or_default());
+    }
+
+    fn unicode_error(&self, column: usize) -> ValueError {
+        let message = format!("invalid unicode escape `{}`", self.text_since(column));
+        return self.error_since(column, message);
+    }
+
+    // ["...", '...'] (末尾のコンマは許す)
+    fn parse_list(&mut self) -> Result<Vec<String>, ValueError> {
+        let start = self.column();
+        self.bump();
+        let not_closed = || ValueError {
+            column: start,
+            width: 1,
+            message: String::from("list is not closed; add `]` at the end"),
+        };
+
+        let mut items = vec![];
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(']') => break,
+                Some('"') | Some('\'') => items.push(self.parse_string()?),
+                Some(_) => return Err(self.unexpected("expected a quoted string")),
+                None => return Err(not_closed()),
+            }
+
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.bump(),
+                Some(']') => break,
+                Some(_) => return Err(self.unexpected("expected `,` or `]`")),
+                None => return Err(not_closed()),
+            }
+        }
+        self.bump();
+        return Ok(items);
+    }
+
+    // 今の位置の1文字
+    fn unexpected(&self, message: &str) -> ValueError {
+        return ValueError {
+            column: self.column(),
+            width: 1,
+            message: message.to_string(),
+        };
+    }
+}
+
+///// Unit Test
+
+#[cfg(test)]
+mod tests {
+    use crate::header::*;
+
+    fn meta(key: &str, column: usize, value: Value) -> Result<Line, LineError> {
+        return Ok(Line::Meta {
+            key: key.to_string(),
+            column: column,
+            value: value,
+        });
+    }
+
+    fn text(text: &str) -> Value {
+        return Value::Text(text.to_string());
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parseLine_meta_valid() {
+        assert_eq!(
+            parse_line("// name: \"seg\"\n"),
+            meta("name", 4, text("seg"))
+        );
+        assert_eq!(
+            parse_line("# author:\"me\" */"),
+            meta("author", 3, text("me"))
+        );
+        assert_eq!(
+            parse_line("<!-- tags: \"a\" -->"),
+            meta("tags", 6, text("a"))
+        );
+        assert_eq!(
+            parse_line("// prefix: [\"seg\", 'segtree',]"),
+            meta(
+                "prefix",
+                4,
+                Value::List(vec![String::from("seg"), String::from("segtree")])
+            )
+        );
+        assert_eq!(
+            parse_line("-- isFileTemplate: true"),
+            meta("isFileTemplate", 4, Value::Bool(true))
+        );
+        assert_eq!(
+            parse_line("// description:"),
+            meta("description", 4, Value::Empty)
+        );
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parseLine_randomSpacing_valid() {
+        assert_eq!(
+            parse_line("//   name:         \"just_a_mock\"    "),
+            meta("name", 6, text("just_a_mock"))
+        );
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parseLine_escapes_valid() {
+        assert_eq!(
+            parse_line(r#"// description: "say \"hi\"\n\tC:\\temp \u00e9\ud83d\ude00""#),
+            meta("description", 4, text("say \"hi\"\n\tC:\\temp é😀"))
+        );
+        assert_eq!(
+            parse_line(r#"// description: 'it\'s "quoted"'"#),
+            meta("description", 4, text("it's \"quoted\""))
+        );
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parseLine_bareValue_valid() {
+        assert_eq!(
+            parse_line("// author: Yuiga Wada */"),
+            meta("author", 4, Value::Bare(String::from("Yuiga Wada")))
+        );
+        assert_eq!(
+            parse_line("/* scope: c, cpp */"),
+            meta("scope", 4, Value::Bare(String::from("c, cpp")))
+        );
+        // キーを扱うかどうかは呼び出し元で決める
+        assert_eq!(
+            parse_line("// TODO: fix this"),
+            meta("TODO", 4, Value::Bare(String::from("fix this")))
+        );
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parseLine_notMeta_valid() {
+        assert_eq!(
+            parse_line("//     \"continued\"  "),
+            Ok(Line::Continuation(String::from("continued")))
+        );
+        assert_eq!(parse_line(""), Ok(Line::Blank));
+        assert_eq!(parse_line("  // "), Ok(Line::Blank));
+        assert_eq!(parse_line("// #PORT_BODY#"), Ok(Line::BodyTag));
+        assert_eq!(parse_line("\"\"\"docstring\"\"\""), Ok(Line::Other));
+        assert_eq!(parse_line("    return \"x\";"), Ok(Line::Other));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parseLine_broken_error() {
+        let error = parse_line("// name: \"seg").unwrap_err();
+        assert_eq!(error.key, "name");
+        assert_eq!(error.column, 10);
+
+        let error = parse_line("// prefix: [\"a\" \"b\"]").unwrap_err();
+        assert_eq!((error.column, error.width), (17, 1));
+        assert_eq!(error.message, "expected `,` or `]`");
+
+        let error = parse_line("// prefix: [\"a\",").unwrap_err();
+        assert_eq!(error.column, 12);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parseLine_brokenString_error() {
+        // エスケープしていない " で文字列が終わった
+        let error = parse_line(r#"// description: "say "hi"""#).unwrap_err();
+        assert_eq!((error.column, error.width), (23, 4));
+
+        let error = parse_line(r#"// description: "C:\temp\d""#).unwrap_err();
+        assert_eq!((error.column, error.width), (25, 2));
+        assert_eq!(
+            error.message,
+            "invalid escape `\\d`; write `\\\\` for a backslash"
+        );
+
+        let error = parse_line(r#"// description: "\u12G4""#).unwrap_err();
+        assert_eq!((error.column, error.width), (18, 4));
+        assert_eq!(error.message, "invalid unicode escape `\\u12`");
+
+        let error = parse_line(r#"// description: "\ud83d""#).unwrap_err();
+        assert_eq!(error.column, 18);
+
+        // 文字列だけの行
+        let error = parse_line(r#"//   "abc\x""#).unwrap_err();
+        assert_eq!((error.key.as_str(), error.column), ("", 10));
+    }
+}
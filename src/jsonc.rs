@@ -0,0 +1,555 @@
+// JSONC (コメントと末尾のコンマを許すJSON) を読み書きする
+// VSCodeのスニペットのjsonには手書きのコメントやスニペットが含まれるので、
+// 解析した位置をもとに必要な部分だけを書き換え、それ以外は1バイトも変えずに書き出す
+
+const BOM: char = '\u{feff}';
+
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub line: usize,   // 1始まり
+    pub column: usize, // 1始まり (文字単位)
+    pub message: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        return write!(f, "{}:{}: {}", self.line, self.column, self.message);
+    }
+}
+
+// Document::text上の範囲 [start, end) (バイト単位)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+// トップレベルのオブジェクトのメンバー ("key": value)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    pub key: String,
+    pub span: Span,           // keyの先頭からvalueの末尾まで
+    pub value: Span,          // valueの部分
+    pub comma: Option<usize>, // 後ろのコンマの位置
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Object {
+    pub open: usize,  // "{" の位置
+    pub close: usize, // "}" の位置
+    pub members: Vec<Member>,
+    pub comments: Vec<Span>, // メンバーの間にあるコメント (値の中のコメントは含まない)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Document {
+    pub bom: bool,
+    pub text: String,           // BOMを除いた本文
+    pub object: Option<Object>, // 空のファイルやコメントだけのファイルならNone
+}
+
+// textのspanをtextで置き換える (挿入ならspan.start == span.end)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edit {
+    pub span: Span,
+    pub text: String,
+}
+
+impl Document {
+    pub fn parse(text: &str) -> Result<Document, ParseError> {
+        let bom = text.starts_with(BOM);
+        let text = if bom { &text[BOM.len_utf8()..] } else { text };
+
+        let mut parser = Parser::new(text);
+        parser.skip_trivia(&mut vec![])?;
+        let object = match parser.peek() {
+            None => None,
+            Some(b'{') => Some(parser.parse_object()?),
+            Some(_) => return Err(parser.error("expected '{'")),
+        };
+
+        parser.skip_trivia(&mut vec![])?;
+        if parser.peek().is_some() {
+            return Err(parser.error("unexpected content after the object"));
+        }
+
+        return Ok(Document {
+            bom: bom,
+            text: text.to_string(),
+            object: object,
+        });
+    }
+
+    pub fn slice(&self, span: &Span) -> &str {
+        return &self.text[span.start..span.end];
+    }
+
+    // editsを反映した全文を返す (BOMがあれば付け直す)
+    pub fn apply(&self, mut edits: Vec<Edit>) -> String {
+        edits.sort_by_key(|edit| (edit.span.start, edit.span.end));
+
+        let mut text = String::new();
+        if self.bom {
+            text.push(BOM);
+        }
+        let mut cursor = 0;
+        for edit in edits.iter() {
+            text.push_str(&self.text[cursor..edit.span.start]);
+            text.push_str(&edit.text);
+            cursor = edit.span.end;
+        }
+        text.push_str(&self.text[cursor..]);
+        return text;
+    }
+}
+
+// トップレベルのオブジェクトのメンバーを足し引きする
+// コンマは、JSONとして正しくなるように(最後のメンバーの後ろにはつけない)必要な分だけ足し引きする
+pub struct ObjectEditor<'a> {
+    document: &'a Document,
+    object: &'a Object,
+    removed: Vec<usize>, // 削除するメンバーのindex
+    edits: Vec<Edit>,
+    inserted: Vec<usize>, // メンバーを追加した位置
+}
+
+impl<'a> ObjectEditor<'a> {
+    pub fn new(document: &'a Document, object: &'a Object) -> Self {
+        return ObjectEditor {
+            document: document,
+            object: object,
+            removed: vec![],
+            edits: vec![],
+            inserted: vec![],
+        };
+    }
+
+    // メンバーの値を書き換える
+    pub fn replace_value(&mut self, index: usize, text: String) {
+        self.edits.push(Edit {
+            span: self.object.members[index].value,
+            text: text,
+        });
+    }
+
+    // メンバーを削除する (その行が空になる場合は行ごと消す)
+    pub fn remove(&mut self, index: usize) {
+        let member = &self.object.members[index];
+        let text = &self.document.text;
+
+        let mut start = member.span.start;
+        let line_start = text[..start].rfind('\n').map_or(0, |i| i + 1);
+        if text[line_start..start].trim().is_empty() {
+            start = line_start;
+        }
+
+        let mut end = member.comma.map_or(member.span.end, |comma| comma + 1);
+        let line_end = text[end..].find('\n').map_or(text.len(), |i| end + i + 1);
+        if text[end..line_end].trim().is_empty() && start == line_start {
+            end = line_end;
+        }
+
+        self.removed.push(index);
+        self.edits.push(Edit {
+            span: Span {
+                start: start,
+                end: end,
+            },
+            text: String::new(),
+        });
+    }
+
+    // positionの後ろに(削除されずに)残るメンバーがあるか
+    pub fn has_members_after(&self, position: usize) -> bool {
+        return self.remaining().any(|member| member.span.start >= position)
+            || self.inserted.iter().any(|inserted| *inserted > position);
+    }
+
+    // positionにメンバーを追加する
+    // textはメンバー同士のコンマを含めて整形済みのものを渡す (後ろのコンマはhas_members_afterを見て呼び出し元で付ける)
+    pub fn insert(&mut self, position: usize, text: String) {
+        self.inserted.push(position);
+        self.edits.push(Edit {
+            span: Span {
+                start: position,
+                end: position,
+            },
+            text: text,
+        });
+    }
+
+    fn remaining(&self) -> impl Iterator<Item = &'a Member> + '_ {
+        return self
+            .object
+            .members
+            .iter()
+            .enumerate()
+            .filter(move |(index, _)| !self.removed.contains(index))
+            .map(|(_, member)| member);
+    }
+
+    // 書き換えた全文を返す
+    // コンマは追加・削除したメンバーの隣だけを直す (手書きの最後のコンマはそのまま残す)
+    pub fn apply(mut self) -> String {
+        let mut edits = std::mem::take(&mut self.edits);
+        let trailing_comma = self
+            .object
+            .members
+            .last()
+            .is_some_and(|member| member.comma.is_some());
+        for member in self.remaining() {
+            let is_last = !self.has_members_after(member.span.end);
+            let was_last = !self
+                .object
+                .members
+                .iter()
+                .any(|other| other.span.start > member.span.end);
+            match member.comma {
+                Some(comma) if is_last && !was_last && !trailing_comma => edits.push(Edit {
+                    span: Span {
+                        start: comma,
+                        end: comma + 1,
+                    },
+                    text: String::new(),
+                }),
+                None if !is_last => edits.push(Edit {
+                    span: Span {
+                        start: member.span.end,
+                        end: member.span.end,
+                    },
+                    text: String::from(","),
+                }),
+                _ => {}
+            }
+        }
+        return self.document.apply(edits);
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        return Parser { text: text, pos: 0 };
+    }
+
+    fn peek(&self) -> Option<u8> {
+        return self.text.as_bytes().get(self.pos).copied();
+    }
+
+    // 1文字進める
+    fn bump(&mut self) {
+        self.pos += match self.text[self.pos..].chars().next() {
+            Some(c) => c.len_utf8(),
+            None => 1,
+        };
+    }
+
+    fn starts_with(&self, pattern: &str) -> bool {
+        return self.text[self.pos..].starts_with(pattern);
+    }
+
+    fn error(&self, message: &str) -> ParseError {
+        let before = &self.text[..self.pos];
+        let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+        return ParseError {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            message: message.to_string(),
+        };
+    }
+
+    // 空白とコメントを読み飛ばす (コメントの位置はcommentsに積む)
+    fn skip_trivia(&mut self, comments: &mut Vec<Span>) -> Result<(), ParseError> {
+        while let Some(c) = self.peek() {
+            if c.is_ascii_whitespace() {
+                self.pos += 1;
+            } else if self.starts_with("//") {
+                let start = self.pos;
+                self.pos = match self.text[start..].find('\n') {
+                    Some(index) => start + index,
+                    None => self.text.len(),
+                };
+                comments.push(Span {
+                    start: start,
+                    end: self.pos,
+                });
+            } else if self.starts_with("/*") {
+                let start = self.pos;
+                match self.text[start + 2..].find("*/") {
+                    Some(index) => self.pos = start + 2 + index + 2,
+                    None => return Err(self.error("block comment is not closed")),
+                }
+                comments.push(Span {
+                    start: start,
+                    end: self.pos,
+                });
+            } else {
+                break;
+            }
+        }
+        return Ok(());
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), ParseError> {
+        if self.peek() != Some(c) {
+            return Err(self.error(&format!("expected '{}'", c as char)));
+        }
+        self.pos += 1;
+        return Ok(());
+    }
+
+    // トップレベルのオブジェクト
+    fn parse_object(&mut self) -> Result<Object, ParseError> {
+        let open = self.pos;
+        self.expect(b'{')?;
+
+        let mut members: Vec<Member> = vec![];
+        let mut comments = vec![];
+        loop {
+            self.skip_trivia(&mut comments)?;
+            match self.peek() {
+                Some(b'}') => break,
+                Some(b'"') => {}
+                Some(_) => return Err(self.error("expected a key or '}'")),
+                None => return Err(self.error("object is not closed")),
+            }
+            if members.last().is_some_and(|member| member.comma.is_none()) {
+                return Err(self.error("expected ',' or '}'"));
+            }
+
+            let start = self.pos;
+            let key_span = self.skip_string()?;
+            let key = match serde_json::from_str::<String>(&self.text[key_span.start..key_span.end])
+            {
+                Ok(key) => key,
+                Err(_) => return Err(self.error("invalid key")),
+            };
+
+            self.skip_trivia(&mut comments)?;
+            self.expect(b':')?;
+            self.skip_trivia(&mut comments)?;
+            let value = self.skip_value()?;
+
+            self.skip_trivia(&mut comments)?;
+            let comma = if self.peek() == Some(b',') {
+                self.pos += 1;
+                Some(self.pos - 1)
+            } else {
+                None
+            };
+
+            members.push(Member {
+                key: key,
+                span: Span {
+                    start: start,
+                    end: value.end,
+                },
+                value: value,
+                comma: comma,
+            });
+        }
+
+        let close = self.pos;
+        self.pos += 1;
+        return Ok(Object {
+            open: open,
+            close: close,
+            members: members,
+            comments: comments,
+        });
+    }
+
+    fn skip_string(&mut self) -> Result<Span, ParseError> {
+        let start = self.pos;
+        self.expect(b'"')?;
+        while let Some(c) = self.peek() {
+            match c {
+                b'"' => {
+                    self.pos += 1;
+                    return Ok(Span {
+                        start: start,
+                        end: self.pos,
+                    });
+                }
+                b'\\' => self.pos += 2,
+                b'\n' => break,
+                _ => self.pos += 1,
+            }
+        }
+        self.pos = std::cmp::min(self.pos, self.text.len());
+        return Err(self.error("string is not closed"));
+    }
+
+    // 値を読み飛ばす (中身は解析しない)
+    fn skip_value(&mut self) -> Result<Span, ParseError> {
+        let start = self.pos;
+        match self.peek() {
+            Some(b'"') => {
+                self.skip_string()?;
+            }
+            Some(b'{') | Some(b'[') => {
+                let mut depth = 0;
+                loop {
+                    self.skip_trivia(&mut vec![])?;
+                    match self.peek() {
+                        Some(b'{') | Some(b'[') => {
+                            depth += 1;
+                            self.pos += 1;
+                        }
+                        Some(b'}') | Some(b']') => {
+                            depth -= 1;
+                            self.pos += 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        Some(b'"') => {
+                            self.skip_string()?;
+                        }
+                        Some(_) => self.bump(),
+                        None => return Err(self.error("value is not closed")),
+                    }
+                }
+            }
+            _ => {
+                while let Some(c) = self.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == b'-' || c == b'+' || c == b'.') {
+                        break;
+                    }
+                    self.pos += 1;
+                }
+                if self.pos == start {
+                    return Err(self.error("expected a value"));
+                }
+            }
+        }
+        return Ok(Span {
+            start: start,
+            end: self.pos,
+        });
+    }
+}
+
+///// Unit Test
+
+#[cfg(test)]
+mod tests {
+    use crate::jsonc::*;
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parse_commentsAndTrailingComma_valid() {
+        let text =
+            "// { not an object\n{\n  /* a */ \"a\": {\"b\": [1, \"}\"]}, // c\n  \"c\": 2,\n}\n";
+        let document = Document::parse(text).unwrap();
+        let object = document.object.as_ref().unwrap();
+
+        assert_eq!(object.open, text.find("\n{").unwrap() + 1);
+        assert_eq!(object.members.len(), 2);
+        assert_eq!(object.members[0].key, "a");
+        assert_eq!(
+            document.slice(&object.members[0].value),
+            "{\"b\": [1, \"}\"]}"
+        );
+        assert!(object.members[1].comma.is_some()); // 末尾のコンマ
+        assert_eq!(object.comments.len(), 2);
+        assert_eq!(document.apply(vec![]), text);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parse_emptyAndBom_valid() {
+        assert_eq!(Document::parse("").unwrap().object, None);
+        assert_eq!(Document::parse("  // only\n").unwrap().object, None);
+
+        let text = "\u{feff}{\"a\": 1}";
+        let document = Document::parse(text).unwrap();
+        assert!(document.bom);
+        assert_eq!(document.object.as_ref().unwrap().members[0].key, "a");
+        assert_eq!(document.apply(vec![]), text);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parse_invalid_error() {
+        let e = Document::parse("[]").unwrap_err();
+        assert_eq!((e.line, e.column), (1, 1));
+
+        let e = Document::parse("{\n  \"a\": 1\n  \"b\": 2\n}").unwrap_err();
+        assert_eq!((e.line, e.column), (3, 3));
+        assert_eq!(e.message, "expected ',' or '}'");
+
+        assert!(Document::parse("{\"a\": 1} {}").is_err());
+        assert!(Document::parse("{\"a\": {").is_err());
+        assert!(Document::parse("{ /* \"a\": 1 }").is_err());
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn apply_edits_valid() {
+        let document = Document::parse("{\"a\": 1}").unwrap();
+        let text = document.apply(vec![
+            Edit {
+                span: Span { start: 7, end: 7 },
+                text: String::from(", \"b\": 2"),
+            },
+            Edit {
+                span: Span { start: 6, end: 7 },
+                text: String::from("3"),
+            },
+        ]);
+        assert_eq!(text, "{\"a\": 3, \"b\": 2}");
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn objectEditor_removeAndInsert_commaFixed() {
+        let text = "{\n  \"a\": 1,\n  \"b\": 2,\n  // end\n  \"c\": 3\n}";
+        let document = Document::parse(text).unwrap();
+        let object = document.object.as_ref().unwrap();
+
+        // 最後のメンバーを消すと、前のメンバーのコンマが外れる
+        let mut editor = ObjectEditor::new(&document, object);
+        editor.remove(2);
+        editor.replace_value(0, String::from("10"));
+        assert_eq!(editor.apply(), "{\n  \"a\": 10,\n  \"b\": 2\n  // end\n}");
+
+        // 最後に追加すると、前のメンバーにコンマが付く
+        let mut editor = ObjectEditor::new(&document, object);
+        editor.remove(1);
+        let position = object.close;
+        assert!(!editor.has_members_after(position));
+        editor.insert(position, String::from("  \"d\": 4\n"));
+        assert_eq!(
+            editor.apply(),
+            "{\n  \"a\": 1,\n  // end\n  \"c\": 3,\n  \"d\": 4\n}"
+        );
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn objectEditor_trailingComma_kept() {
+        let text = "{\n  \"a\": 1,\n  \"b\": 2,\n}";
+        let document = Document::parse(text).unwrap();
+        let object = document.object.as_ref().unwrap();
+
+        // 触っていない最後のメンバーのコンマは外さない
+        let mut editor = ObjectEditor::new(&document, object);
+        editor.replace_value(1, String::from("20"));
+        assert_eq!(editor.apply(), "{\n  \"a\": 1,\n  \"b\": 20,\n}");
+
+        // 最後のメンバーを消しても、最後にコンマを付ける書き方のままにする
+        let mut editor = ObjectEditor::new(&document, object);
+        editor.remove(1);
+        assert_eq!(editor.apply(), "{\n  \"a\": 1,\n}");
+
+        // 途中のメンバーを消しても、最後のコンマは残る
+        let mut editor = ObjectEditor::new(&document, object);
+        editor.remove(0);
+        assert_eq!(editor.apply(), "{\n  \"b\": 2,\n}");
+    }
+}
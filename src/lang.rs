@@ -1,33 +1,128 @@
-#[derive(Serialize, Deserialize, Debug)]
+use std::sync::RwLock;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 struct Languages {
     lang: Vec<Language>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 struct Language {
     name: String,
     identifier: String,
     extension: String,
 }
 
+// 読み込み済みのlang.json (lang.jsonが変更されたらreloadで差し替える)
+static LANGUAGES: RwLock<Option<Languages>> = RwLock::new(None);
+
 pub fn get_lang(extension: String) -> Option<String> {
-    let langs = get_langdata().lang;
+    if LANGUAGES.read().unwrap().is_none() {
+        if let Err(e) = reload() {
+            println!("error: {}", e);
+            return None;
+        }
+    }
 
-    for lang in langs {
+    let langs = LANGUAGES.read().unwrap();
+    for lang in langs.as_ref()?.lang.iter() {
         if lang.extension == extension {
-            return Some(lang.identifier);
+            return Some(lang.identifier.clone());
         }
     }
 
     return None;
 }
 
-fn get_langdata() -> Languages {
+// lang.jsonに登録されているidentifierの一覧
+pub fn get_identifiers() -> Vec<String> {
+    if LANGUAGES.read().unwrap().is_none() {
+        if let Err(e) = reload() {
+            println!("error: {}", e);
+            return vec![];
+        }
+    }
+
+    let langs = LANGUAGES.read().unwrap();
+    let mut identifiers: Vec<String> = match langs.as_ref() {
+        Some(langs) => langs
+            .lang
+            .iter()
+            .map(|lang| lang.identifier.clone())
+            .collect(),
+        None => vec![],
+    };
+    identifiers.sort();
+    identifiers.dedup();
+    return identifiers;
+}
+
+// lang.jsonを読み直す
+// 不正なlang.jsonだった場合は今のデータを残してErrを返す
+pub fn reload() -> Result<usize, String> {
+    let lang_json_path = get_lang_json_path();
+    let contents = match std::fs::read_to_string(&lang_json_path) {
+        Ok(contents) => contents,
+        Err(e) => return Err(format!("cannot read {}: {}", lang_json_path.display(), e)),
+    };
+
+    let langs = parse_langdata(&contents)?;
+    let count = langs.lang.len();
+    *LANGUAGES.write().unwrap() = Some(langs);
+    return Ok(count);
+}
+
+pub fn get_lang_json_path() -> std::path::PathBuf {
     let mut lang_json_path = std::env::current_exe().expect("cannot get current_exe");
     lang_json_path.pop();
     lang_json_path.push("lang.json");
+    return lang_json_path;
+}
+
+fn parse_langdata(contents: &str) -> Result<Languages, String> {
+    let langs: Languages = match serde_json::from_str(contents) {
+        Ok(langs) => langs,
+        Err(e) => return Err(format!("cannot perse lang.json: {}", e)),
+    };
 
-    let contents = super::file::read_file(&lang_json_path);
-    let langs: Languages = serde_json::from_str(&contents).expect("cannot get lang.json");
-    return langs;
+    for lang in langs.lang.iter() {
+        if lang.identifier.is_empty() || lang.extension.is_empty() {
+            return Err(format!(
+                "invalid lang.json: \"{}\" needs both identifier and extension",
+                lang.name
+            ));
+        }
+    }
+
+    return Ok(langs);
+}
+
+///// Unit Test
+
+#[cfg(test)]
+mod tests {
+    use crate::lang::*;
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parseLangdata_valid() {
+        let contents = r#"{"lang":[{"name":"C++","identifier":"cpp","extension":"cpp"}]}"#;
+        let langs = parse_langdata(contents).unwrap();
+
+        assert_eq!(langs.lang.len(), 1);
+        assert_eq!(langs.lang[0].identifier, "cpp");
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parseLangdata_brokenJson_invalid() {
+        let contents = r#"{"lang":[{"name":"C++","identifier":"cpp","extension":"cpp"}"#;
+        assert!(parse_langdata(contents).is_err());
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn parseLangdata_emptyExtension_invalid() {
+        let contents = r#"{"lang":[{"name":"C++","identifier":"cpp","extension":""}]}"#;
+        assert!(parse_langdata(contents).is_err());
+    }
 }
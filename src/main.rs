@@ -1,123 +1,1237 @@
+// 既存のコードスタイル(明示的なreturnなど)に合わせてclippyのstyle系lintを無効化する
+#![allow(
+    clippy::needless_return,
+    clippy::redundant_field_names,
+    clippy::bool_assert_comparison,
+    clippy::enum_variant_names,
+    clippy::four_forward_slashes,
+    clippy::ptr_arg,
+    clippy::question_mark,
+    clippy::len_zero,
+    clippy::single_match,
+    clippy::collapsible_match,
+    clippy::redundant_pattern_matching,
+    clippy::single_char_add_str,
+    clippy::redundant_closure,
+    clippy::needless_borrow
+)]
+
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
 extern crate serde_json;
 
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static VERBOSE: AtomicBool = AtomicBool::new(false); // --verbose
+
+// --verboseのときだけ表示する
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if crate::VERBOSE.load(std::sync::atomic::Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
+
 mod argparser; // 引数解析
+mod backup; // <lang>.jsonのバックアップ
+mod control; // デーモンとの通信
 mod daemon; // デーモン処理
 mod debounce; // 間引き処理
+mod diagnostic; // メタタグの検査結果
 mod file; // I/O
+mod filter; // 対象ファイルの絞り込み
+mod header; // メタタグのヘッダの読み込み
+mod jsonc; // コメントつきJSONの読み書き
 mod lang; // 言語特定
+mod placeholder; // 本文のプレースホルダの変換
+mod prefix; // prefixの重複検査
 mod snippet; // スニペット処理
+mod state; // 起動状態の記録
 mod watch; // 監視処理
 
-use argparser::LaunchType;
-use file::{open_file, FileReader};
+use argparser::{LaunchType, Options};
+use file::{open_file, FileReader, TextReader};
 use snippet::KeyList;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
 use std::thread;
 
-const DEBOUNCE_INTERVAL: u64 = 10_000; // ms
-
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Config {
     snippets_dir: String,
     dirs: Vec<String>,
     files: Vec<String>,
+    #[serde(default = "default_use_init_system")]
+    use_init_system: bool, // falseならlaunchd / systemd / scを使わずに自前でデーモン化する
+    #[serde(default)]
+    name_conflict: snippet::ConflictPolicy, // 他のファイルと同じ名前のスニペットがあったときの扱い
+    #[serde(default)]
+    include: Vec<String>, // dirsの中で対象にするファイルのglob (空ならすべて)
+    #[serde(default)]
+    exclude: Vec<String>, // dirsの中で対象にしないファイル・フォルダのglob
+    #[serde(default)]
+    use_gitignore: bool, // trueなら.gitignore / .ignoreで無視されるファイルを対象にしない
+    #[serde(default)]
+    max_file_size: Option<u64>, // これより大きいファイルは対象にしない (byte)
+    #[serde(default = "default_scan_threads")]
+    scan_threads: usize, // 一斉に走査するときにファイルを読み込むスレッドの数 (0ならCPUの数)
+    #[serde(default = "default_debounce_ms")]
+    debounce_ms: u64, // 最後の変更からこれだけ何も起きなかったらスニペットを生成する (ms)
+    #[serde(default = "default_debounce_max_wait_ms")]
+    debounce_max_wait_ms: u64, // 変更が続いても、最初の変更からこれだけたったら生成する (ms)
+    #[serde(default)]
+    global_snippets: bool, // trueならすべてのスニペットをportsnippet.code-snippetsに書き込む
+    #[serde(default)]
+    body_as_lines: bool, // trueならbodyを行の配列で書き込む
+    #[serde(default = "default_placeholder_marker")]
+    placeholder_marker: String, // 本文のプレースホルダの目印 (__PS1_n__ → ${1:n})
+}
+
+fn default_use_init_system() -> bool {
+    return true;
+}
+
+fn default_placeholder_marker() -> String {
+    return String::from(placeholder::DEFAULT_MARKER);
+}
+
+fn default_scan_threads() -> usize {
+    return 1;
+}
+
+fn default_debounce_ms() -> u64 {
+    return 1_000;
+}
+
+fn default_debounce_max_wait_ms() -> u64 {
+    return 10_000;
+}
+
+impl Config {
+    // 監視対象のフォルダ・ファイル
+    fn paths(&self) -> Vec<String> {
+        return [self.dirs.clone(), self.files.clone()].concat();
+    }
+
+    // 監視対象のうち、スニペットを生成するファイルの条件 (globが不正ならErr)
+    fn filter(&self) -> Result<filter::FileFilter, String> {
+        return filter::FileFilter::new(
+            &self.dirs,
+            &self.files,
+            &self.include,
+            &self.exclude,
+            self.use_gitignore,
+            self.max_file_size,
+        );
+    }
+
+    fn scan_threads(&self) -> usize {
+        if self.scan_threads > 0 {
+            return self.scan_threads;
+        }
+        return thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+    }
+
+    fn debounce_timing(&self) -> debounce::Timing {
+        return debounce::Timing {
+            quiet_period: std::time::Duration::from_millis(self.debounce_ms),
+            max_wait: std::time::Duration::from_millis(self.debounce_max_wait_ms),
+        };
+    }
+
+    // 対象ファイルのスニペットの書き込み先 (<lang>.jsonのlang、またはGLOBAL_IDENTIFIER) と書き出し方
+    // scopeが書かれていないスニペットには、portsnippet.code-snippetsでは対象ファイルの言語を付ける
+    // 言語が分からないファイルは、portsnippet.code-snippetsに書き込むときだけ対象にする
+    fn output(&self, code_filepath: &std::path::Path) -> Option<(String, snippet::OutputStyle)> {
+        let lang_identifier = detect_lang(&code_filepath.to_path_buf());
+        let (output, scope) = if self.global_snippets {
+            (snippet::GLOBAL_IDENTIFIER.to_string(), lang_identifier)
+        } else {
+            (lang_identifier?, None)
+        };
+        let style = snippet::OutputStyle {
+            scope: scope,
+            body_as_lines: self.body_as_lines,
+            placeholder_marker: self.placeholder_marker.clone(),
+        };
+        return Some((output, style));
+    }
+
+    // 対象ファイルのスニペットの名前の決め方
+    // 名前空間は対象ファイルを含むdirsからの相対パス (filesで指定されたものはファイル名)
+    fn naming(&self, code_filepath: &std::path::Path) -> snippet::Naming {
+        let relative = self
+            .dirs
+            .iter()
+            .flat_map(|dir| file::path_forms(dir))
+            .find_map(|dir| code_filepath.strip_prefix(dir).ok())
+            .filter(|relative| relative.components().next().is_some())
+            .map(|relative| {
+                relative
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy().to_string())
+                    .collect::<Vec<String>>()
+                    .join("/")
+            });
+        let namespace = match relative {
+            Some(relative) => relative,
+            None => code_filepath
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        };
+        return snippet::Naming {
+            policy: self.name_conflict,
+            namespace: namespace,
+        };
+    }
+}
+
+// 起動中のデーモンが持つ状態 (監視スレッドとcontrolソケットのスレッドで共有する)
+struct Context {
+    config: Mutex<Config>,
+    options: Options,
+    paused: AtomicBool,
+    meta_dir: std::path::PathBuf,
+    lock: Mutex<Option<daemon::pidfile::PidLock>>,
+    watcher: Mutex<watch::PathWatcher>,
+    filter: Mutex<filter::FileFilter>, // configから作る
+    queue: debounce::Queue,            // 変更されたファイル (workerスレッドでまとめて処理する)
+    // queueのworkerスレッド (shutdownでは、処理中のファイルを書き終えるまで待つ)
+    worker: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Context {
+    fn is_allowed(&self, code_filepath: &std::path::Path) -> bool {
+        return self.filter.lock().unwrap().is_allowed(code_filepath);
+    }
+
+    fn is_paused(&self) -> bool {
+        return self.paused.load(Ordering::SeqCst);
+    }
+}
+
+// 1回のbuildの間だけ持つ状態 (デーモンの走査ではNoneを渡す)
+#[derive(Default)]
+struct BuildContext {
+    dry_run: bool,               // --dry-runなら、ファイルに書き込む代わりにstagedに残す
+    backed_up: BTreeSet<String>, // バックアップを取った言語 (1回のbuildで言語ごとに1度だけ取る)
+    staged: BTreeMap<String, (String, String)>, // 言語ごとの書き込まなかった<lang>.jsonとnamelist
+}
+
+impl BuildContext {
+    fn new(dry_run: bool) -> Self {
+        return BuildContext {
+            dry_run: dry_run,
+            ..BuildContext::default()
+        };
+    }
+}
+
+fn is_dry_run(build_context: Option<&BuildContext>) -> bool {
+    return build_context.is_some_and(|build_context| build_context.dry_run);
+}
+
+// listコマンドの結果 (スニペットごと)
+#[derive(Serialize, Debug)]
+struct ListEntry {
+    lang: String,
+    name: String,
+    path: String,
 }
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    let launch_type = argparser::detect_type(args);
+    let args = match argparser::parse(args) {
+        Ok(args) => args,
+        Err(e) => {
+            println!("error: {}\n", e);
+            println!("Run './port_snippet help' for usage.");
+            std::process::exit(2);
+        }
+    };
+    let options = args.options;
+    VERBOSE.store(options.verbose, Ordering::Relaxed);
 
-    match launch_type {
-        LaunchType::Daemon => {
-            let config = get_config();
-            let paths = [config.dirs.clone(), config.files.clone()].concat();
+    match args.launch_type {
+        LaunchType::Daemon => run_daemon(options),
+        LaunchType::Install => {
+            let config = get_config(&options);
+            let daemon_args = argparser::get_daemon_args(&options);
+            if use_init_system(&config) {
+                // cronの登録処理
+                println!("Registering daemon...\n");
+                daemon::register(true, &daemon_args);
 
-            scan_all(&config, &paths); // 起動時にすべての対象ファイルを一度走査する
-            watch(config, paths);
+                let messages = daemon::get_complete_messages();
+                println!("{}", messages);
+            } else {
+                start_without_init_system(&config, &daemon_args);
+            }
         }
-        LaunchType::Man => {
-            // cronの登録処理
-            println!("Registering daemon...\n");
-            daemon::register(true);
-
-            let messages = daemon::get_complete_messages();
-            println!("{}", messages);
+        LaunchType::Uninstall => {
+            let config = get_config(&options);
+            if use_init_system(&config) {
+                daemon::unregister();
+            } else {
+                let meta_dir = snippet::get_meta_dir(&config.snippets_dir);
+                let _ = control::send(&meta_dir, control::Request::Shutdown);
+                stop_without_init_system(&config);
+            }
+            println!("uninstalled.");
+        }
+        LaunchType::Start => {
+            let config = get_config(&options);
+            if use_init_system(&config) {
+                daemon::run();
+            } else {
+                start_without_init_system(&config, &argparser::get_daemon_args(&options));
+            }
         }
         LaunchType::Stop => {
-            daemon::stop();
+            let config = get_config(&options);
+            if use_init_system(&config) {
+                daemon::stop();
+            } else {
+                let meta_dir = snippet::get_meta_dir(&config.snippets_dir);
+                let _ = control::send(&meta_dir, control::Request::Shutdown);
+                stop_without_init_system(&config);
+            }
             println!("stop");
         }
         LaunchType::Restart => {
-            daemon::stop();
-            daemon::run();
+            // 新しいexeを読み込めるように、プロセスごと起動し直す (configだけならreload)
+            let config = get_config(&options);
+            if use_init_system(&config) {
+                daemon::stop();
+                daemon::run();
+            } else {
+                let meta_dir = snippet::get_meta_dir(&config.snippets_dir);
+                let _ = control::send(&meta_dir, control::Request::Shutdown);
+                stop_without_init_system(&config);
+                start_without_init_system(&config, &argparser::get_daemon_args(&options));
+            }
             println!("restart!");
         }
-        LaunchType::Help => {
-            argparser::print_help();
+        LaunchType::Status { json } => {
+            let config = get_config(&options);
+            print_status(&config, json);
+        }
+        LaunchType::Build => {
+            let config = get_config(&options);
+            if !build(&config, options.dry_run) {
+                std::process::exit(1);
+            }
+        }
+        LaunchType::Diff => {
+            let config = get_config(&options);
+            if !build(&config, true) {
+                std::process::exit(1);
+            }
+        }
+        LaunchType::Check { json } => {
+            let config = get_config(&options);
+            if !check(&config, json) {
+                std::process::exit(1);
+            }
+        }
+        LaunchType::List { json } => {
+            let config = get_config(&options);
+            print_list(&config, json);
+        }
+        LaunchType::Prefixes { json } => {
+            let config = get_config(&options);
+            print_prefixes(&config, json);
+        }
+        LaunchType::Reload => send_request(&options, control::Request::ReloadConfig),
+        LaunchType::Rescan => send_request(&options, control::Request::Rescan),
+        LaunchType::Pause => send_request(&options, control::Request::Pause),
+        LaunchType::Resume => send_request(&options, control::Request::Resume),
+        LaunchType::Restore { lang, index } => {
+            let config = get_config(&options);
+            match lang {
+                Some(lang) => {
+                    if !restore(&config, &lang, index, options.dry_run) {
+                        std::process::exit(1);
+                    }
+                }
+                None => print_backups(&config),
+            }
+        }
+        LaunchType::Help(command) => {
+            argparser::print_help(command.as_deref());
+        }
+    }
+}
+
+// フォアグラウンドで監視する
+fn run_daemon(options: Options) {
+    println!("Config: {:?}", get_config_path(&options));
+    let config = get_config(&options);
+    let paths = config.paths();
+
+    // 同じconfigのwatcherが二重に起動しないようにロックを取る
+    let meta_dir = snippet::get_meta_dir(&config.snippets_dir);
+    let lock = match daemon::pidfile::acquire(&meta_dir) {
+        Ok(lock) => lock,
+        Err(daemon::pidfile::LockError::AlreadyRunning(pid)) => {
+            println!(
+                "PortSnippet is already running. (pid: {})",
+                daemon::pidfile::format_pid(pid)
+            );
+            std::process::exit(1);
+        }
+        Err(daemon::pidfile::LockError::Io(e)) => {
+            panic!("cannot lock {:?}: {}", meta_dir, e);
+        }
+    };
+
+    state::start(&meta_dir, &config.dirs, &config.files);
+    reconcile(&config, None, None); // 止まっている間に移動・削除されたファイルを反映する
+    scan_all(&config, &paths); // 起動時にすべての対象ファイルを一度走査する
+
+    let (mut watcher, rx) = match watch::PathWatcher::new() {
+        Ok(watcher) => watcher,
+        Err(e) => panic!("cannot watch files! {:?}", e),
+    };
+    watcher.update(&paths);
+
+    // config.json / lang.json の変更も監視する
+    let mut config_dir = get_config_path(&options);
+    config_dir.pop();
+    if let Err(e) = watcher.watch_shallow(&config_dir) {
+        println!("cannot watch {:?}: {:?}", config_dir, e);
+    }
+    let mut exe_dir = lang::get_lang_json_path();
+    exe_dir.pop();
+    if exe_dir != config_dir {
+        if let Err(e) = watcher.watch_shallow(&exe_dir) {
+            println!("cannot watch {:?}: {:?}", exe_dir, e);
+        }
+    }
+
+    let filter = config.filter().unwrap(); // load_configで検査済み
+    let queue = debounce::Queue::new(config.debounce_timing(), Arc::new(debounce::SystemClock));
+    let context = Arc::new(Context {
+        config: Mutex::new(config),
+        options: options,
+        paused: AtomicBool::new(false),
+        meta_dir: meta_dir,
+        lock: Mutex::new(Some(lock)),
+        watcher: Mutex::new(watcher),
+        filter: Mutex::new(filter),
+        queue: queue,
+        worker: Mutex::new(None),
+    });
+    let worker = spawn_sync_worker(context.clone());
+    *context.worker.lock().unwrap() = Some(worker);
+    serve_control(context.clone());
+    watch(context, rx);
+}
+
+// 起動中のデーモンにリクエストを送って結果を表示する
+fn send_request(options: &Options, request: control::Request) {
+    let config = get_config(options);
+    let meta_dir = snippet::get_meta_dir(&config.snippets_dir);
+    match control::send(&meta_dir, request) {
+        Ok(response) => {
+            println!("{}", response.message);
+            if !response.ok {
+                std::process::exit(1);
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+            println!("{}.", e);
+            std::process::exit(1);
+        }
+        Err(_) => {
+            println!("PortSnippet is not running.");
+            std::process::exit(1);
         }
     }
 }
 
-// Configを取得
-fn get_config() -> Config {
-    let mut config_path: std::path::PathBuf;
+// 起動状態を表示する
+fn print_status(config: &Config, json: bool) {
+    let meta_dir = snippet::get_meta_dir_path(&config.snippets_dir); // 表示するだけなので、フォルダは作らない
+
+    // 起動中ならデーモンに直接聞く
+    let report = match control::send(&meta_dir, control::Request::Status) {
+        Ok(response) => state::Report {
+            running: true,
+            pid: response.state.as_ref().map(|state| state.pid),
+            paused: response.paused,
+            snippets_dir: config.snippets_dir.clone(),
+            state: response.state,
+        },
+        Err(_) => {
+            let running = daemon::pidfile::is_locked(&meta_dir);
+            state::Report {
+                running: running,
+                pid: if running {
+                    daemon::pidfile::read_pid(&meta_dir)
+                } else {
+                    None
+                },
+                paused: false,
+                snippets_dir: config.snippets_dir.clone(),
+                state: state::load(&meta_dir),
+            }
+        }
+    };
+
+    if json {
+        if let Ok(json) = serde_json::to_string_pretty(&report) {
+            println!("{}", json);
+        }
+    } else {
+        print!("{}", state::format_report(&report));
+    }
+}
+
+// controlソケットでCLIからのリクエストを待ち受ける
+fn serve_control(context: Arc<Context>) {
+    let server = match control::Server::bind(&context.meta_dir) {
+        Ok(server) => server,
+        Err(e) => {
+            println!("cannot open the control socket: {}", e);
+            return;
+        }
+    };
+
+    thread::spawn(move || {
+        let handler = context.clone();
+        server.serve(move |request| handle_request(&handler, request));
+
+        // shutdown
+        drop(server);
+
+        // 書き込みの途中で終わらないように、生成中のスニペットを書き終えるまで待つ
+        context.queue.close();
+        if let Some(worker) = context.worker.lock().unwrap().take() {
+            let _ = worker.join();
+        }
+        let _config = context.config.lock().unwrap(); // 走査中なら終わるまで待つ
 
-    config_path = std::env::current_exe().expect("cannot get current_exe");
+        context.lock.lock().unwrap().take(); // PIDファイルを消してロックを解放する
+        println!("shutdown");
+        std::process::exit(0);
+    });
+}
+
+// CLIからのリクエストを処理する
+fn handle_request(context: &Context, request: control::Request) -> control::Response {
+    let mut response = match request {
+        control::Request::ReloadConfig => match reload_config(context) {
+            Ok(message) => control::Response::ok(&message),
+            Err(e) => control::Response::error(&e),
+        },
+        control::Request::Rescan => {
+            let config = context.config.lock().unwrap();
+            scan_all(&config, &config.paths());
+            control::Response::ok("rescanned.")
+        }
+        control::Request::Status => {
+            let mut response = control::Response::ok("running.");
+            response.state = state::load(&context.meta_dir);
+            response
+        }
+        control::Request::Pause => {
+            context.paused.store(true, Ordering::SeqCst);
+            control::Response::ok("paused.")
+        }
+        control::Request::Resume => {
+            context.paused.store(false, Ordering::SeqCst);
+
+            // 一時停止中の変更を拾う
+            let config = context.config.lock().unwrap();
+            scan_all(&config, &config.paths());
+            control::Response::ok("resumed.")
+        }
+        control::Request::Shutdown => control::Response::ok("shutting down."),
+    };
+
+    response.paused = context.is_paused();
+    return response;
+}
+
+// init systemを使うかどうか
+fn use_init_system(config: &Config) -> bool {
+    return config.use_init_system && daemon::is_available();
+}
+
+// PIDファイルを使って自前でデーモン化する
+fn start_without_init_system(config: &Config, daemon_args: &[String]) {
+    let meta_dir = snippet::get_meta_dir(&config.snippets_dir);
+    match daemon::pidfile::spawn(&meta_dir, daemon_args) {
+        Ok(pid) => println!("PortSnippet is running in the background. (pid: {})", pid),
+        Err(daemon::pidfile::LockError::AlreadyRunning(pid)) => {
+            println!(
+                "PortSnippet is already running. (pid: {})",
+                daemon::pidfile::format_pid(pid)
+            );
+        }
+        Err(daemon::pidfile::LockError::Io(e)) => println!("cannot start PortSnippet: {}", e),
+    }
+}
+
+// PIDファイルのプロセスを停止する
+fn stop_without_init_system(config: &Config) {
+    let meta_dir = snippet::get_meta_dir(&config.snippets_dir);
+    if !daemon::pidfile::stop(&meta_dir) {
+        let pid = daemon::pidfile::read_pid(&meta_dir);
+        println!(
+            "cannot stop PortSnippet. (pid: {})",
+            daemon::pidfile::format_pid(pid)
+        );
+    }
+}
+
+// config.jsonのパスを取得 (--configがなければexeと同じフォルダ)
+fn get_config_path(options: &Options) -> std::path::PathBuf {
+    if let Some(config) = &options.config {
+        return std::path::PathBuf::from(config);
+    }
+
+    let mut config_path = std::env::current_exe().expect("cannot get current_exe");
     config_path.pop();
     config_path.push("config.json");
-    println!("Config: {:?}", config_path);
+    return config_path;
+}
+
+// Configを取得 (読み込めなかった場合は終了する)
+fn get_config(options: &Options) -> Config {
+    return match load_config(options) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("error: {}", e);
+            std::process::exit(1);
+        }
+    };
+}
+
+// Configを読み込む (読み込めなかった場合はErrを返す)
+fn load_config(options: &Options) -> Result<Config, String> {
+    let config_path = get_config_path(options);
+    let contents = match std::fs::read_to_string(&config_path) {
+        Ok(contents) => contents,
+        Err(e) => return Err(format!("cannot read {}: {}", config_path.display(), e)),
+    };
+    let mut config = match serde_json::from_str::<Config>(&contents) {
+        Ok(config) => config,
+        Err(e) => return Err(format!("cannot perse config.json: {}", e)),
+    };
+
+    if let Err(e) = config.filter() {
+        return Err(format!("invalid include / exclude in config.json: {}", e));
+    }
+    if config.debounce_ms == 0 {
+        return Err(String::from(
+            "invalid debounce_ms in config.json: must be greater than 0",
+        ));
+    }
+    if config.debounce_max_wait_ms < config.debounce_ms {
+        return Err(format!(
+            "invalid debounce_max_wait_ms in config.json: {} is less than debounce_ms ({})",
+            config.debounce_max_wait_ms, config.debounce_ms
+        ));
+    }
+
+    // --snippets-dirはconfig.jsonより優先する
+    if let Some(snippets_dir) = &options.snippets_dir {
+        config.snippets_dir = snippets_dir.clone();
+    }
+    return Ok(config);
+}
+
+// config.jsonを読み直して、監視対象を差し替える
+// 不正なconfigだった場合は今のconfigのままErrを返す
+fn reload_config(context: &Context) -> Result<String, String> {
+    let new_config = load_config(&context.options)?;
+    let mut config = context.config.lock().unwrap();
+    if new_config.snippets_dir != config.snippets_dir {
+        return Err(String::from(
+            "snippets_dir cannot be changed without restarting.",
+        ));
+    }
+    if new_config == *config {
+        return Ok(String::from("config unchanged."));
+    }
+
+    *context.filter.lock().unwrap() = new_config.filter()?;
+    context.queue.set_timing(new_config.debounce_timing());
+    *config = new_config;
+    let added = context.watcher.lock().unwrap().update(&config.paths());
+    state::set_watched(&context.meta_dir, &config.dirs, &config.files);
+
+    scan_all(&config, &added); // 新しく監視を始めたパスだけ走査する
+    return Ok(format!("config reloaded. ({} new paths)", added.len()));
+}
+
+// すべての対象ファイルからスニペットを生成して、ファイルごとの変更を表示する (エラーがなければtrue)
+// 言語ごとにまとめて生成するので、<lang>.jsonは言語ごとに1度だけ書き込んでバックアップする
+// --dry-run(diffコマンド)のときは、書き込む代わりに差分を表示する
+fn build(config: &Config, dry_run: bool) -> bool {
+    let mut total = snippet::Changes::default();
+    let mut file_count = 0;
+    let mut error_count = 0;
+
+    let mut build_context = BuildContext::new(dry_run);
+    reconcile(config, None, Some(&mut build_context));
+
+    let paths = file::collect_files(&config.paths(), &config.filter().unwrap());
+    let mut results = HashMap::new();
+    for (lang_identifier, sources) in group_sources(config, &paths, config.scan_threads()) {
+        match make_snippets(config, &lang_identifier, sources, Some(&mut build_context)) {
+            Ok(synced) => results.extend(synced),
+            Err(e) => {
+                println!("error: {}", e);
+                error_count += 1;
+            }
+        }
+    }
+
+    // 対象ファイルの順に表示する
+    for path in paths {
+        let synced = match results.remove(&path) {
+            Some(Ok(synced)) => synced,
+            Some(Err(snippet::MakeError::NotFound)) | None => continue, // スニペットを含まないファイル
+            Some(Err(e)) => {
+                println!("error      {}: {}", path.display(), e);
+                for d in lint_file(&path).unwrap_or_default() {
+                    println!("\n{}", diagnostic::format(&d));
+                }
+                error_count += 1;
+                continue;
+            }
+        };
+
+        file_count += 1;
+        let changes = synced.changes;
+        for conflict in changes.conflicts.iter() {
+            println!("{}", format_conflict(conflict, &path));
+        }
+        if changes.is_empty() {
+            println!("unchanged  {}", path.display());
+            continue;
+        }
+        println!("changed    {}", path.display());
+        print!("{}", format_changes(&changes));
+        if let Some(diff) = synced.diff {
+            println!("\n{}", diff);
+        }
+
+        total.added.extend(changes.added);
+        total.updated.extend(changes.updated);
+        total.removed.extend(changes.removed);
+    }
+
+    let verb = if dry_run {
+        "would be built (dry-run)"
+    } else {
+        "built"
+    };
+    println!(
+        "\n{} files {}: {} added, {} updated, {} removed, {} errors.",
+        file_count,
+        verb,
+        total.added.len(),
+        total.updated.len(),
+        total.removed.len(),
+        error_count
+    );
+    return error_count == 0;
+}
+
+// スニペットごとの変更を整形する
+//   + seg
+//   ~ two (prefix, body)
+//   - old
+//   ! mine (edited by hand, not overwritten)
+fn format_changes(changes: &snippet::Changes) -> String {
+    let mut text = String::new();
+    for name in changes.added.iter() {
+        text.push_str(&format!("  + {}\n", name));
+    }
+    for name in changes.updated.iter() {
+        match changes.fields.get(name) {
+            Some(fields) if !fields.is_empty() => {
+                text.push_str(&format!("  ~ {} ({})\n", name, fields.join(", ")));
+            }
+            _ => text.push_str(&format!("  ~ {}\n", name)),
+        }
+    }
+    for name in changes.removed.iter() {
+        text.push_str(&format!("  - {}\n", name));
+    }
+    for name in changes.edited.iter() {
+        text.push_str(&format!("  ! {} (edited by hand, not overwritten)\n", name));
+    }
+    return text;
+}
+
+// 他のファイルと名前が被ったときの警告
+fn format_conflict(conflict: &snippet::Conflict, code_filepath: &std::path::Path) -> String {
+    let action = if conflict.key != conflict.name {
+        format!("generated as \"{}\"", conflict.key)
+    } else if conflict.kept {
+        String::from("keeping that one (change this one to overwrite it)")
+    } else {
+        format!("overwriting it with {}", code_filepath.display())
+    };
+    return format!(
+        "warning: \"{}\" is also defined in {}; {}.",
+        conflict.name, conflict.owner, action
+    );
+}
+
+// すべての対象ファイルのメタタグを検査する (エラーがなければtrue)
+fn check(config: &Config, json: bool) -> bool {
+    let mut file_count = 0;
+    let mut diagnostics = vec![];
+    for path in file::collect_files(&config.paths(), &config.filter().unwrap()) {
+        if config.output(&path).is_none() {
+            continue; // 言語が分からないファイル
+        }
+        if let Some(found) = lint_file(&path) {
+            file_count += 1;
+            diagnostics.extend(found);
+        }
+    }
+    diagnostics.extend(lint_prefixes(config));
+
+    let report = diagnostic::Report::new(file_count, diagnostics);
+    if json {
+        if let Ok(json) = serde_json::to_string_pretty(&report) {
+            println!("{}", json);
+        }
+    } else {
+        for d in report.diagnostics.iter() {
+            println!("{}", diagnostic::format(d));
+        }
+        println!(
+            "{} files checked: {} errors, {} warnings.",
+            report.files, report.errors, report.warnings
+        );
+    }
+
+    return report.errors == 0;
+}
+
+// 対象ファイルのメタタグを検査する
+// 開けないファイルの場合はNoneを返す
+fn lint_file(code_filepath: &std::path::PathBuf) -> Option<Vec<diagnostic::Diagnostic>> {
+    let snippet_file = open_file(code_filepath, false, false)?;
+    let path = code_filepath.to_string_lossy().to_string();
+    return Some(snippet::lint(FileReader::new(snippet_file), &path));
+}
+
+// 生成済みのスニペットのprefixが他のスニペットと被っていたら、生成元のprefixの行に警告を出す
+fn lint_prefixes(config: &Config) -> Vec<diagnostic::Diagnostic> {
+    let entries = prefix::load(&config.snippets_dir);
+    let mut diagnostics = vec![];
+    for issue in prefix::find_issues(&entries).iter() {
+        for entry in issue.entries.iter().filter(|e| e.prefix == issue.prefix) {
+            let source = match &entry.source {
+                Some(source) => source,
+                None => continue, // 手書きのスニペット
+            };
+            let text = match std::fs::read_to_string(source) {
+                Ok(text) => text,
+                Err(_) => continue,
+            };
+
+            // <namespace>::<name> で生成したものは元の名前で探す
+            let original = entry.name.rsplit("::").next().unwrap_or(&entry.name);
+            let located = snippet::locate_prefix(TextReader::new(text.clone()), &entry.name)
+                .or_else(|| snippet::locate_prefix(TextReader::new(text), original));
+            if let Some((line, column, line_text)) = located {
+                diagnostics.push(diagnostic::Diagnostic {
+                    severity: diagnostic::Severity::Warning,
+                    path: source.clone(),
+                    line: line,
+                    column: column,
+                    tag: String::from("prefix"),
+                    message: prefix::describe(issue, entry),
+                    width: "prefix".len(),
+                    source: line_text,
+                });
+            }
+        }
+    }
+    return diagnostics;
+}
+
+// prefixが被っているスニペットを表示する
+fn print_prefixes(config: &Config, json: bool) {
+    let entries = prefix::load(&config.snippets_dir);
+    let issues = prefix::find_issues(&entries);
+    if json {
+        if let Ok(json) = serde_json::to_string_pretty(&issues) {
+            println!("{}", json);
+        }
+        return;
+    }
+    print!("{}", prefix::format_report(&issues));
+}
+
+// 生成済みのスニペットを一覧表示する
+fn print_list(config: &Config, json: bool) {
+    let mut entries = vec![];
+    for lang in get_output_identifiers() {
+        let list_filepath = snippet::get_namelist_filepath(&lang, &config.snippets_dir);
+        let name_list = match std::fs::read_to_string(&list_filepath) {
+            Ok(contents) => serde_json::from_str::<KeyList>(&contents).unwrap_or_default(),
+            Err(_) => continue,
+        };
+
+        let mut paths: Vec<&String> = name_list.keys().collect();
+        paths.sort();
+        for path in paths {
+            let mut names: Vec<String> = name_list[path]
+                .iter()
+                .filter(|n| !n.shadowed) // 他のファイルに上書きされたもの
+                .map(|n| n.name.clone())
+                .collect();
+            names.sort();
+            for name in names {
+                entries.push(ListEntry {
+                    lang: lang.clone(),
+                    name: name,
+                    path: path.clone(),
+                });
+            }
+        }
+    }
+
+    if json {
+        if let Ok(json) = serde_json::to_string_pretty(&entries) {
+            println!("{}", json);
+        }
+        return;
+    }
+
+    if entries.is_empty() {
+        println!("no snippet generated yet.");
+        return;
+    }
+    println!("{:<8}  {:<24}  FILE", "LANG", "NAME");
+    for entry in entries.iter() {
+        println!("{:<8}  {:<24}  {}", entry.lang, entry.name, entry.path);
+    }
+}
+
+// バックアップを一覧表示する
+fn print_backups(config: &Config) {
+    let meta_dir = snippet::get_meta_dir_path(&config.snippets_dir);
+    let backups = backup::list(&meta_dir, None);
+    if backups.is_empty() {
+        println!("no backup yet.");
+        return;
+    }
+
+    println!("{:<8}  {:>2}  {:<23}  FILE", "LANG", "N", "CREATED");
+    let mut index = 0;
+    for (i, backup) in backups.iter().enumerate() {
+        // 言語が変わったら1から数え直す
+        index = if i > 0 && backups[i - 1].lang == backup.lang {
+            index + 1
+        } else {
+            1
+        };
+        println!(
+            "{:<8}  {:>2}  {:<23}  {}",
+            backup.lang,
+            index,
+            state::format_time(backup.created_at / 1000),
+            backup.path.display()
+        );
+    }
+    println!("\nRun './port_snippet restore <LANG> [N]' to restore one of them.");
+}
+
+// <lang>.jsonをindex番目に新しいバックアップから戻す
+fn restore(config: &Config, lang_identifier: &str, index: usize, dry_run: bool) -> bool {
+    let meta_dir = snippet::get_meta_dir_path(&config.snippets_dir);
+    let backups = backup::list(&meta_dir, Some(lang_identifier));
+    let target = match backups.get(index - 1) {
+        Some(target) => target,
+        None => {
+            println!(
+                "error: {}.json has only {} backup(s).",
+                lang_identifier,
+                backups.len()
+            );
+            return false;
+        }
+    };
+
+    let snippet_json_filepath =
+        snippet::get_snippet_json_filepath(lang_identifier, &config.snippets_dir);
+    let created_at = state::format_time(target.created_at / 1000);
+    if dry_run {
+        println!(
+            "would restore {} from the backup at {} (dry-run).",
+            snippet_json_filepath.display(),
+            created_at
+        );
+        return true;
+    }
+
+    let _lock = match snippet::lock(&config.snippets_dir, lang_identifier) {
+        Ok(lock) => lock,
+        Err(e) => {
+            println!("error: cannot lock {}: {}", config.snippets_dir, e);
+            return false;
+        }
+    };
+    if let Err(e) = backup::restore(&meta_dir, target, &snippet_json_filepath) {
+        println!(
+            "error: cannot restore {}: {}",
+            snippet_json_filepath.display(),
+            e
+        );
+        return false;
+    }
 
-    let contents = file::read_file(&config_path);
-    let config: Config = serde_json::from_str(&contents).expect("cannot perse config.json");
-    return config;
+    println!(
+        "restored {} from the backup at {}.",
+        snippet_json_filepath.display(),
+        created_at
+    );
+    println!("Run './port_snippet build' to regenerate the snippets from the current files.");
+    return true;
+}
+
+// config.json / lang.json が変更されたかどうか
+fn is_same_file(path: &std::path::Path, target: &std::path::Path) -> bool {
+    if path == target {
+        return true;
+    }
+    return match (std::fs::canonicalize(path), std::fs::canonicalize(target)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    };
 }
 
 // 監視対象を一斉に走査する
+// フォルダは再帰的に辿り、パス順に処理する (include / excludeなどで対象外のものは飛ばす)
 fn scan_all(config: &Config, paths: &Vec<String>) {
-    let snippets_dir = config.snippets_dir.clone();
-    for path in paths {
-        let path = std::path::PathBuf::from(path);
-        make_snippet(snippets_dir.clone().as_str(), &path);
+    let filter = config.filter().unwrap(); // load_configで検査済み
+    let code_filepaths = file::collect_files(paths, &filter);
+    sync_snippets(config, code_filepaths, config.scan_threads(), None);
+}
+
+// 対象ファイルのスニペットを生成する
+// 言語ごとにまとめて生成するので、<lang>.jsonとnamelistは言語ごとに1度だけ書き込む
+// 手で編集されていて書き換えなかったスニペットや、名前が被ったスニペットはログに残す
+// (buildから呼ぶときはbuild_contextを渡す。デーモンのときは結果をstate.jsonにまとめて記録する)
+fn sync_snippets(
+    config: &Config,
+    code_filepaths: Vec<std::path::PathBuf>,
+    threads: usize,
+    mut build_context: Option<&mut BuildContext>,
+) {
+    let mut sync_results = vec![];
+    for (lang_identifier, sources) in group_sources(config, &code_filepaths, threads) {
+        let results = match make_snippets(
+            config,
+            &lang_identifier,
+            sources,
+            build_context.as_deref_mut(),
+        ) {
+            Ok(results) => results,
+            Err(e) => {
+                println!("error: {}", e);
+                continue;
+            }
+        };
+        for (code_filepath, result) in results {
+            // デーモンのときだけ、statusコマンド用に記録しておく
+            if build_context.is_none() {
+                sync_results.extend(get_sync_result(&lang_identifier, &code_filepath, &result));
+            }
+            if let Ok(synced) = result {
+                report_synced(&synced, &code_filepath);
+            }
+        }
     }
+    state::record_results(&snippet::get_meta_dir(&config.snippets_dir), sync_results);
+}
+
+// 対象ファイルを読み込んで、書き込み先の言語ごとにまとめる
+fn group_sources(
+    config: &Config,
+    code_filepaths: &[std::path::PathBuf],
+    threads: usize,
+) -> BTreeMap<String, Vec<Source>> {
+    let mut langs: BTreeMap<String, Vec<Source>> = BTreeMap::new();
+    for (lang_identifier, source) in read_sources(config, code_filepaths, threads)
+        .into_iter()
+        .flatten()
+    {
+        langs.entry(lang_identifier).or_default().push(source);
+    }
+    return langs;
+}
+
+// 対象ファイルの書き込み先を特定して読み込む (threads個のスレッドで分担する)
+// 対象外のファイル(言語が分からない・開けない)はNone
+fn read_sources(
+    config: &Config,
+    code_filepaths: &[std::path::PathBuf],
+    threads: usize,
+) -> Vec<Option<(String, Source)>> {
+    let read = |code_filepath: &std::path::PathBuf| {
+        let (output, style) = config.output(code_filepath)?;
+        let text = std::fs::read_to_string(code_filepath).ok()?;
+        let source = Source {
+            path: code_filepath.clone(),
+            naming: config.naming(code_filepath),
+            text: text,
+            style: style,
+        };
+        return Some((output, source));
+    };
+    if threads <= 1 || code_filepaths.len() <= 1 {
+        return code_filepaths.iter().map(read).collect();
+    }
+
+    let chunk_size = code_filepaths.len().div_ceil(threads);
+    return thread::scope(|scope| {
+        let handles: Vec<_> = code_filepaths
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().map(read).collect::<Vec<_>>()))
+            .collect();
+        // 分けた順に繋げるので、結果の順番は変わらない
+        return handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect();
+    });
 }
 
 // フォルダ・ファイルを監視
-fn watch(config: Config, paths: Vec<String>) {
-    let mut debouncers = debounce::SafeFileDebouncer::new(DEBOUNCE_INTERVAL); // ファイルごとにdebounceする
-    let snippets_dir = config.snippets_dir.clone();
+fn watch(context: Arc<Context>, rx: watch::EventReceiver) {
+    let config_path = get_config_path(&context.options);
+    let lang_json_path = lang::get_lang_json_path();
 
-    // TODO: configも監視しておく
     // 監視する
-    let register_result = watch::watch_dir(paths, |code_filepath_string| {
-        let debouncer = debouncers.get(code_filepath_string.as_str()); // ファイルに紐付いたdebouncerを取り出す
-        let locked = debouncer.lock();
-        let code_filepath = std::path::PathBuf::from(code_filepath_string);
-
-        if let Ok(_) = locked {
-            let run = locked.unwrap().debounce(|| {
-                make_snippet(snippets_dir.clone().as_str(), &code_filepath);
-            });
+    watch::listen(rx, |event| {
+        // .gitignore / .ignore が変わったら、次に判定するときに読み直す
+        {
+            let filter = context.filter.lock().unwrap();
+            match &event {
+                watch::FileEvent::Created(path)
+                | watch::FileEvent::Modified(path)
+                | watch::FileEvent::Removed(path) => filter.forget_ignore(path),
+                watch::FileEvent::Renamed { from, to } => {
+                    filter.forget_ignore(from);
+                    filter.forget_ignore(to);
+                }
+            }
+        }
 
-            // これが最後のmake_snippetだった場合、debounce_interval間に起こる編集イベントに対応できない
-            // → 常にdebounce_interval後にファイルの編集を確認しに行く
-            if run {
-                let snippets_dir_string = snippets_dir.clone();
-                thread::spawn(move || {
-                    let debounce_interval = std::time::Duration::from_millis(DEBOUNCE_INTERVAL);
-                    thread::sleep(debounce_interval);
-                    make_snippet(snippets_dir_string.as_str(), &code_filepath);
-                });
+        let code_filepath = match event {
+            watch::FileEvent::Created(path) | watch::FileEvent::Modified(path) => path,
+            // 消えた (すぐに書き直されていたら変更として扱う)
+            // 一度にたくさん消えることが多いので、変更と同じく落ち着いてからworkerスレッドでまとめて外す
+            watch::FileEvent::Removed(path) if !path.exists() => {
+                if !context.is_paused() && is_watched(&context, &path) {
+                    context.queue.push(path);
+                }
+                return;
+            }
+            watch::FileEvent::Removed(path) => path,
+            // 名前が変わった・移動した (監視対象の外や対象外のファイルに移動したら削除として扱う)
+            watch::FileEvent::Renamed { from, to } => {
+                if !context.is_paused() && is_watched(&context, &from) {
+                    if is_watched(&context, &to) && context.is_allowed(&to) {
+                        let config = context.config.lock().unwrap();
+                        let renamed = rename_sources(&config, &from, &to);
+                        state::remove_sources(&context.meta_dir, &renamed);
+                    } else {
+                        context.queue.push(from); // 削除と同じ
+                    }
+                }
+                to
             }
+        };
+
+        // config.jsonが変更された
+        if is_same_file(&code_filepath, &config_path) {
+            match reload_config(&context) {
+                Ok(message) => println!("{}", message),
+                Err(e) => println!("error: {} (keeping the current config)", e),
+            }
+            return;
+        }
+
+        // lang.jsonが変更された
+        if is_same_file(&code_filepath, &lang_json_path) {
+            match lang::reload() {
+                Ok(count) => {
+                    println!("lang.json reloaded. ({} languages)", count);
+                    let config = context.config.lock().unwrap();
+                    scan_all(&config, &config.paths()); // 新しい拡張子のファイルを拾う
+                }
+                Err(e) => println!("error: {} (keeping the current lang.json)", e),
+            }
+            return;
         }
+
+        if context.is_paused() {
+            return;
+        }
+
+        // 監視対象外 (exeのフォルダにある他のファイル)
+        if !is_watched(&context, &code_filepath) {
+            return;
+        }
+
+        // include / exclude・.gitignore・max_file_sizeで対象外にしたファイル
+        if !context.is_allowed(&code_filepath) {
+            return;
+        }
+
+        context.queue.push(code_filepath); // 変更が落ち着いたらworkerスレッドで生成する
     });
-    match register_result {
-        Ok(()) => (),
-        Err(_) => {
-            panic!("cannot watch files!");
+}
+
+// debounceしたファイルのスニペットを生成し続けるスレッドを立てる
+fn spawn_sync_worker(context: Arc<Context>) -> thread::JoinHandle<()> {
+    let queue = context.queue.clone();
+    return queue.spawn_worker(move |code_filepaths| {
+        if context.is_paused() {
+            return; // resumeで走査し直す
         }
-    };
+        let config = context.config.lock().unwrap();
+
+        // 消えたファイルを先に外す (移動したファイルを、移動先の生成より前に付け替えられるように)
+        let (existing, missing): (Vec<_>, Vec<_>) =
+            code_filepaths.into_iter().partition(|path| path.exists());
+        if !missing.is_empty() {
+            let forgotten = reconcile(&config, Some(&missing), None);
+            state::remove_sources(&context.meta_dir, &forgotten);
+        }
+        sync_snippets(&config, existing, 1, None); // 同じ言語のファイルはまとめて書き込む
+    });
+}
+
+// configのdirs / filesに含まれるパスかどうか
+fn is_watched(context: &Context, code_filepath: &std::path::Path) -> bool {
+    return context.watcher.lock().unwrap().is_watched(code_filepath);
+}
+
+// namelistを持ちうる書き込み先 (lang.jsonの言語と、portsnippet.code-snippets)
+fn get_output_identifiers() -> Vec<String> {
+    let mut identifiers = lang::get_identifiers();
+    identifiers.push(snippet::GLOBAL_IDENTIFIER.to_string());
+    return identifiers;
 }
 
 // ファイルの拡張子から言語を特定する
@@ -129,57 +1243,860 @@ fn detect_lang(code_filepath: &std::path::PathBuf) -> Option<String> {
     return None;
 }
 
-// スニペットを生成
-fn make_snippet(snippets_dir: &str, code_filepath: &std::path::PathBuf) {
-    // 言語の特定 / 対象ファイルの読み込み
-    let lang_identifier = detect_lang(code_filepath);
-    let snippet_file = open_file(&code_filepath, false, false);
-    if lang_identifier.is_none() || snippet_file.is_none() {
-        return;
+// 存在しなくなった対象ファイル(missingを指定したらそのパスとその下のものだけ)をnamelistから外す
+// 同じ内容のスニペットを持つファイルがnamelistにまだなければ、移動・名前の変更とみなして付け替える
+// なければ、そのファイルのスニペットを<lang>.jsonから削除する
+// 削除したスニペットを同じ名前で定義している他のファイルは、ロックを外してから生成し直す
+// namelistから外したファイルのパスを返す (--dry-runのときは書き込まずに、差分を表示する)
+fn reconcile(
+    config: &Config,
+    missing: Option<&[std::path::PathBuf]>,
+    mut build_context: Option<&mut BuildContext>,
+) -> Vec<String> {
+    let snippets_dir = config.snippets_dir.as_str();
+    let mut forgotten = vec![];
+    let mut resync: Vec<std::path::PathBuf> = vec![];
+    let mut candidates: Option<Vec<Candidate>> = None; // 移動先の候補 (必要になったら1度だけ集める)
+    let dry_run = is_dry_run(build_context.as_deref());
+
+    for lang_identifier in get_output_identifiers() {
+        let list_filepath = snippet::get_namelist_filepath(&lang_identifier, snippets_dir);
+        if !list_filepath.exists() {
+            continue;
+        }
+        let _lock = if dry_run {
+            None
+        } else {
+            match snippet::lock(snippets_dir, &lang_identifier) {
+                Ok(lock) => Some(lock),
+                Err(e) => {
+                    println!("cannot lock {}: {}", snippets_dir, e);
+                    return forgotten;
+                }
+            }
+        };
+
+        let (_, list_text) = read_output(snippets_dir, &lang_identifier, build_context.as_deref());
+        let mut name_list = serde_json::from_str::<KeyList>(&list_text).unwrap_or_default();
+        let mut paths: Vec<String> = name_list
+            .keys()
+            .filter(|path| {
+                let path = std::path::Path::new(path);
+                !path.exists()
+                    && missing.is_none_or(|missing| {
+                        missing.iter().any(|missing| path.starts_with(missing))
+                    })
+            })
+            .cloned()
+            .collect();
+        paths.sort();
+
+        for path in paths {
+            let candidates = candidates.get_or_insert_with(|| collect_candidates(config));
+            let moved_to = find_moved_source(&name_list, &path, &lang_identifier, candidates);
+            let result = match moved_to {
+                Some(moved_to) => {
+                    println!("moved      {} -> {}", path, moved_to);
+                    let names = name_list.remove(&path).unwrap_or_default();
+                    name_list.insert(moved_to, names);
+                    match build_context.as_deref_mut() {
+                        Some(build_context) if build_context.dry_run => {
+                            stage_name_list(
+                                build_context,
+                                snippets_dir,
+                                &lang_identifier,
+                                &name_list,
+                            );
+                            Ok(())
+                        }
+                        _ => write_name_list(&list_filepath, &name_list),
+                    }
+                }
+                None => match forget_source(
+                    snippets_dir,
+                    &lang_identifier,
+                    &path,
+                    &name_list,
+                    build_context.as_deref_mut(),
+                ) {
+                    Ok((output, diff)) => {
+                        println!("removed    {} (no longer exists)", path);
+                        print!("{}", format_changes(&output.changes));
+                        if let Some(diff) = diff {
+                            println!("\n{}", diff);
+                        }
+                        resync.extend(output.resync.iter().map(std::path::PathBuf::from));
+                        name_list = output.name_list;
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                },
+            };
+            match result {
+                Ok(()) => forgotten.push(path),
+                Err(e) => println!("error: cannot forget {}: {}", path, e),
+            }
+        }
     }
 
-    let lang_identifier = lang_identifier.unwrap();
-    let code_filepath_string = std::path::PathBuf::from(code_filepath)
-        .into_os_string()
-        .into_string()
-        .clone()
-        .unwrap();
+    if !resync.is_empty() {
+        sync_snippets(config, resync, 1, build_context);
+    }
+    return forgotten;
+}
 
-    // namelistの読み込み
-    let list_filepath = snippet::get_namelist_filepath(lang_identifier.as_str(), snippets_dir);
-    let list_file = open_file(&list_filepath, true, false);
-    if list_file.is_none() {
-        return;
+// 名前が変わった・移動した対象ファイル(フォルダならその下のもの)をnamelistで付け替える
+// 付け替えたファイルの元のパスを返す
+fn rename_sources(config: &Config, from: &std::path::Path, to: &std::path::Path) -> Vec<String> {
+    let snippets_dir = config.snippets_dir.as_str();
+    let mut renamed = vec![];
+
+    for lang_identifier in get_output_identifiers() {
+        let list_filepath = snippet::get_namelist_filepath(&lang_identifier, snippets_dir);
+        if !list_filepath.exists() {
+            continue;
+        }
+        let _lock = match snippet::lock(snippets_dir, &lang_identifier) {
+            Ok(lock) => lock,
+            Err(e) => {
+                println!("cannot lock {}: {}", snippets_dir, e);
+                return renamed;
+            }
+        };
+
+        let list_text = std::fs::read_to_string(&list_filepath).unwrap_or_default();
+        let mut name_list = serde_json::from_str::<KeyList>(&list_text).unwrap_or_default();
+        let mut moves: Vec<(String, String)> = name_list
+            .keys()
+            .filter_map(|path| {
+                let rest = std::path::Path::new(path).strip_prefix(from).ok()?;
+                let moved_to = if rest.as_os_str().is_empty() {
+                    to.to_path_buf()
+                } else {
+                    to.join(rest)
+                };
+                return Some((path.clone(), moved_to.to_string_lossy().to_string()));
+            })
+            .filter(|(_, moved_to)| !name_list.contains_key(moved_to))
+            .collect();
+        if moves.is_empty() {
+            continue;
+        }
+        moves.sort();
+
+        for (path, moved_to) in moves {
+            println!("moved      {} -> {}", path, moved_to);
+            let names = name_list.remove(&path).unwrap_or_default();
+            name_list.insert(moved_to, names);
+            renamed.push(path);
+        }
+        if let Err(e) = write_name_list(&list_filepath, &name_list) {
+            println!("error: {}", e);
+        }
     }
+    return renamed;
+}
 
-    // スニペットのjsonファイルを読み込む
-    let snippet_json_filename = format!("{}.json", lang_identifier);
-    let mut snippet_json_filepath = std::path::PathBuf::from(snippets_dir);
-    snippet_json_filepath.push(snippet_json_filename);
-    println!("{:?}", snippet_json_filepath);
+// 移動先の候補 (対象ファイルと、そのファイルが持つスニペットのハッシュ)
+struct Candidate {
+    path: String,
+    output: String, // 書き込み先の言語
+    hashes: Vec<String>,
+}
 
-    // スニペットを生成
-    if let Some(snippet_json_file) = open_file(&snippet_json_filepath, true, false) {
-        // Readerを用意する
-        let snippet_reader = FileReader::new(snippet_file.unwrap());
-        let mut list_file_reader = FileReader::new(list_file.unwrap());
-        let snippet_json_reader = FileReader::new(snippet_json_file);
+// すべての対象ファイルを読んで、スニペットのハッシュを集める
+fn collect_candidates(config: &Config) -> Vec<Candidate> {
+    let paths = file::collect_files(&config.paths(), &config.filter().unwrap());
+    return paths
+        .iter()
+        .filter_map(|path| {
+            let (output, style) = config.output(path)?;
+            let text = std::fs::read_to_string(path).ok()?;
+            let hashes = snippet::get_snippet_hashes(TextReader::new(text), &style)?;
+            return Some(Candidate {
+                path: path.to_string_lossy().to_string(),
+                output: output,
+                hashes: hashes,
+            });
+        })
+        .collect();
+}
+
+// pathのスニペットと同じ内容のスニペットを持っていて、まだnamelistにないファイルを探す
+fn find_moved_source(
+    name_list: &KeyList,
+    path: &str,
+    lang_identifier: &str,
+    candidates: &[Candidate],
+) -> Option<String> {
+    let names = &name_list[path];
+    return candidates
+        .iter()
+        .filter(|candidate| candidate.output == lang_identifier)
+        .filter(|candidate| !name_list.contains_key(&candidate.path))
+        .find(|candidate| snippet::has_same_snippets(names, &candidate.hashes))
+        .map(|candidate| candidate.path.clone());
+}
+
+fn write_name_list(
+    list_filepath: &std::path::PathBuf,
+    name_list: &KeyList,
+) -> Result<(), snippet::MakeError> {
+    let text = serde_json::to_string::<KeyList>(name_list).unwrap_or_default();
+    return file::write_file(list_filepath, text).map_err(|e| {
+        snippet::MakeError::Io(format!("cannot write {}: {}", list_filepath.display(), e))
+    });
+}
+
+// pathのスニペットを<lang>.jsonから削除して、namelistから外す
+// --dry-runのときは、<lang>.jsonのunified diffも返す
+fn forget_source(
+    snippets_dir: &str,
+    lang_identifier: &str,
+    path: &str,
+    name_list: &KeyList,
+    build_context: Option<&mut BuildContext>,
+) -> Result<(snippet::Output, Option<String>), snippet::MakeError> {
+    let (snippet_json_text, _) =
+        read_output(snippets_dir, lang_identifier, build_context.as_deref());
+    let list_text = serde_json::to_string::<KeyList>(name_list).unwrap_or_default();
+
+    let output = snippet::forget(
+        TextReader::new(snippet_json_text.clone()),
+        &mut TextReader::new(list_text),
+        path.to_string(),
+    )?;
+    let diff = if is_dry_run(build_context.as_deref()) {
+        let snippet_json_filepath =
+            snippet::get_snippet_json_filepath(lang_identifier, snippets_dir);
+        Some(format_diff(
+            &snippet_json_filepath,
+            &snippet_json_text,
+            &output.json,
+        ))
+    } else {
+        None
+    };
+    write_output(
+        snippets_dir,
+        lang_identifier,
+        &snippet_json_text,
+        &output,
+        build_context,
+    )?;
+    return Ok((output, diff));
+}
+
+// 手で編集されていて書き換えなかったスニペットや、名前が被ったスニペットはログに残す
+fn report_synced(synced: &Synced, code_filepath: &std::path::Path) {
+    for conflict in synced.changes.conflicts.iter() {
+        println!("{}", format_conflict(conflict, code_filepath));
+    }
+    for name in synced.changes.edited.iter() {
+        println!(
+            "warning: \"{}\" ({}) was edited by hand; not overwriting it.",
+            name,
+            code_filepath.display()
+        );
+    }
+}
+
+// make_snippetsの結果
+struct Synced {
+    changes: snippet::Changes,
+    diff: Option<String>, // --dry-runのときだけ、生成部分のunified diff
+    names: Vec<String>,   // このファイルが持つスニペットの名前 (statusに記録する)
+}
+
+// 言語ごとにまとめて生成する対象ファイル
+struct Source {
+    path: std::path::PathBuf,
+    naming: snippet::Naming,
+    text: String,
+    style: snippet::OutputStyle,
+}
+
+// 同じ言語の対象ファイルのスニペットを順番に生成して、<lang>.jsonとnamelistに1度だけ書き込む
+// (lang_identifierがGLOBAL_IDENTIFIERなら、portsnippet.code-snippetsに書き込む)
+// 消したスニペットを同じ名前で定義している他のファイルがあれば、そのファイルも読み直して生成する
+// 対象ファイルごとの結果を返す (snippets_dirがない・ロックできない場合はErr)
+// --dry-runのときはファイルを一切書き換えない
+fn make_snippets(
+    config: &Config,
+    lang_identifier: &str,
+    sources: Vec<Source>,
+    build_context: Option<&mut BuildContext>,
+) -> std::io::Result<Vec<(std::path::PathBuf, Result<Synced, snippet::MakeError>)>> {
+    let snippets_dir = config.snippets_dir.as_str();
+    let dry_run = is_dry_run(build_context.as_deref());
+    if !std::path::Path::new(snippets_dir).is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("snippets_dir {} is not a folder", snippets_dir),
+        ));
+    }
+
+    // 読み込んでから書き込むまでの間、他のPortSnippetに書き換えられないようにする
+    // (--dry-runのときはロックファイルも作らない)
+    let _lock = if dry_run {
+        None
+    } else {
+        match snippet::lock(snippets_dir, lang_identifier) {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                return Err(std::io::Error::new(
+                    e.kind(),
+                    format!("cannot lock {}: {}", snippets_dir, e),
+                ))
+            }
+        }
+    };
+
+    // namelist / スニペットのjsonを読み込む (まだなければ空として扱う)
+    let snippet_json_filepath = snippet::get_snippet_json_filepath(lang_identifier, snippets_dir);
+    verbose!("{:?}", snippet_json_filepath);
+    let (snippet_json_text, list_text) =
+        read_output(snippets_dir, lang_identifier, build_context.as_deref());
+
+    // 前のファイルの結果に重ねて生成していく
+    let mut json = snippet_json_text.clone();
+    let mut name_list: Option<KeyList> = None;
+    let mut results: Vec<(std::path::PathBuf, Result<_, _>)> = vec![];
+    let mut pending: VecDeque<Source> = sources.into();
+    while let Some(source) = pending.pop_front() {
+        let code_filepath_string = source.path.to_string_lossy().to_string();
+        let list_text = match &name_list {
+            Some(name_list) => serde_json::to_string::<KeyList>(name_list).unwrap_or_default(),
+            None => list_text.clone(),
+        };
 
         // make!
         let result = snippet::make(
-            snippet_reader,
-            snippet_json_reader,
-            &mut list_file_reader,
-            code_filepath_string,
+            TextReader::new(source.text),
+            TextReader::new(json.clone()),
+            &mut TextReader::new(list_text),
+            code_filepath_string.clone(),
+            &source.naming,
+            &source.style,
         );
-        if let Some(result) = result {
-            // スニペットのjsonを書き込む
-            file::write_file(&snippet_json_filepath, result.json);
 
-            // 新しいnamelistを書き込む
-            if let Ok(name_list_string) = serde_json::to_string::<KeyList>(&result.name_list) {
-                file::write_file(&list_filepath, name_list_string);
+        let mut resync = vec![];
+        let result = result.map(|output| {
+            let diff = if dry_run {
+                Some(format_diff(&snippet_json_filepath, &json, &output.json))
+            } else {
+                None
+            };
+            let names: Vec<String> = output
+                .name_list
+                .get(&code_filepath_string)
+                .map(|names| {
+                    names
+                        .iter()
+                        .filter(|n| !n.shadowed)
+                        .map(|n| n.name.clone())
+                        .collect()
+                })
+                .unwrap_or_default();
+            resync = output.resync;
+            json = output.json;
+            name_list = Some(output.name_list);
+            return Synced {
+                changes: output.changes,
+                diff: diff,
+                names: names,
+            };
+        });
+        let code_filepath = source.path;
+        match results.iter_mut().find(|(path, _)| *path == code_filepath) {
+            Some(previous) => previous.1 = result, // 作り直したファイルは後の結果だけを返す
+            None => results.push((code_filepath, result)),
+        }
+
+        let resync: Vec<std::path::PathBuf> = resync
+            .into_iter()
+            .map(std::path::PathBuf::from)
+            .filter(|path| !pending.iter().any(|source| source.path == *path))
+            .collect();
+        pending.extend(
+            read_sources(config, &resync, 1)
+                .into_iter()
+                .flatten()
+                .filter(|(output, _)| output == lang_identifier)
+                .map(|(_, source)| source),
+        );
+    }
+
+    // スニペットのjson / 新しいnamelistを書き込む (--dry-runのときはbuild_contextに残す)
+    if let Some(name_list) = name_list {
+        let output = snippet::Output {
+            json: json,
+            name_list: name_list,
+            changes: snippet::Changes::default(),
+            resync: vec![],
+        };
+        if let Err(e) = write_output(
+            snippets_dir,
+            lang_identifier,
+            &snippet_json_text,
+            &output,
+            build_context,
+        ) {
+            for (_, result) in results.iter_mut().filter(|(_, result)| result.is_ok()) {
+                *result = Err(snippet::MakeError::Io(e.to_string()));
             }
         }
     }
+
+    return Ok(results);
+}
+
+// statusコマンド用に記録する結果 (スニペットを含まないファイルはNone)
+// エラーのときは、どの行が悪いのかを添えて記録する
+fn get_sync_result(
+    lang_identifier: &str,
+    code_filepath: &std::path::PathBuf,
+    result: &Result<Synced, snippet::MakeError>,
+) -> Option<state::SyncResult> {
+    let result = match result {
+        Ok(synced) => Ok(synced.names.clone()),
+        Err(snippet::MakeError::NotFound) => return None,
+        Err(e) => Err(
+            match lint_file(code_filepath)
+                .unwrap_or_default()
+                .iter()
+                .find(|d| d.is_error())
+            {
+                Some(d) => diagnostic::format_short(d),
+                None => e.to_string(),
+            },
+        ),
+    };
+    return Some(state::SyncResult {
+        path: code_filepath.to_string_lossy().to_string(),
+        lang: lang_identifier.to_string(),
+        result: result,
+    });
+}
+
+// 生成したスニペットのjsonとnamelistを書き込む
+// 書き換える前の<lang>.jsonはバックアップしておく
+fn write_output(
+    snippets_dir: &str,
+    lang_identifier: &str,
+    old_json: &str,
+    output: &snippet::Output,
+    mut build_context: Option<&mut BuildContext>,
+) -> Result<(), snippet::MakeError> {
+    if let Some(build_context) = build_context.as_deref_mut() {
+        if build_context.dry_run {
+            let list_text = serde_json::to_string::<KeyList>(&output.name_list).unwrap_or_default();
+            build_context.staged.insert(
+                lang_identifier.to_string(),
+                (output.json.clone(), list_text),
+            );
+            return Ok(());
+        }
+    }
+
+    let meta_dir = snippet::get_meta_dir(snippets_dir);
+    let to_error = |path: &std::path::PathBuf, e: std::io::Error| {
+        snippet::MakeError::Io(format!("cannot write {}: {}", path.display(), e))
+    };
+
+    if !old_json.is_empty()
+        && old_json != output.json
+        && should_back_up(build_context, lang_identifier)
+    {
+        if let Err(e) = backup::save(&meta_dir, lang_identifier, old_json) {
+            println!("cannot back up {}.json: {}", lang_identifier, e);
+        }
+    }
+
+    let snippet_json_filepath = snippet::get_snippet_json_filepath(lang_identifier, snippets_dir);
+    file::write_file(&snippet_json_filepath, output.json.clone())
+        .map_err(|e| to_error(&snippet_json_filepath, e))?;
+
+    if let Ok(name_list_string) = serde_json::to_string::<KeyList>(&output.name_list) {
+        let list_filepath = snippet::get_namelist_filepath(lang_identifier, snippets_dir);
+        file::write_file(&list_filepath, name_list_string)
+            .map_err(|e| to_error(&list_filepath, e))?;
+    }
+    return Ok(());
+}
+
+// <lang>.jsonとnamelistを読み込む (まだなければ空)
+// --dry-runで書き込まずに残したものがあれば、そちらを返す
+fn read_output(
+    snippets_dir: &str,
+    lang_identifier: &str,
+    build_context: Option<&BuildContext>,
+) -> (String, String) {
+    if let Some(staged) = build_context.and_then(|c| c.staged.get(lang_identifier)) {
+        return staged.clone();
+    }
+    let snippet_json_filepath = snippet::get_snippet_json_filepath(lang_identifier, snippets_dir);
+    let list_filepath = snippet::get_namelist_filepath(lang_identifier, snippets_dir);
+    return (
+        std::fs::read_to_string(snippet_json_filepath).unwrap_or_default(),
+        std::fs::read_to_string(list_filepath).unwrap_or_default(),
+    );
+}
+
+// --dry-runで付け替えたnamelistを残す (<lang>.jsonは変わらない)
+fn stage_name_list(
+    build_context: &mut BuildContext,
+    snippets_dir: &str,
+    lang_identifier: &str,
+    name_list: &KeyList,
+) {
+    let (json, _) = read_output(snippets_dir, lang_identifier, Some(build_context));
+    let list_text = serde_json::to_string::<KeyList>(name_list).unwrap_or_default();
+    build_context
+        .staged
+        .insert(lang_identifier.to_string(), (json, list_text));
+}
+
+// <lang>.jsonをバックアップするか (build中は言語ごとに最初の1度だけ)
+// 1回のbuildで古いバックアップを押し出して、build前の状態に戻せなくならないようにする
+fn should_back_up(build_context: Option<&mut BuildContext>, lang_identifier: &str) -> bool {
+    return match build_context {
+        Some(build_context) => build_context.backed_up.insert(lang_identifier.to_string()),
+        None => true,
+    };
+}
+
+// スニペットのjsonのunified diff (手書きの部分は変わらないので、書き換えた部分だけが出る)
+fn format_diff(snippet_json_filepath: &std::path::Path, old: &str, new: &str) -> String {
+    let path = snippet_json_filepath.display().to_string();
+    return similar::TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(3)
+        .header(&path, &path)
+        .to_string();
+}
+
+///// Unit Test
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    fn prepare_dir(name: &str) -> std::path::PathBuf {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("port_snippet_main_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        return dir;
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn build_manyFiles_restorable() {
+        let root = prepare_dir("build");
+        let snippets_dir = root.join("snippets");
+        let src_dir = root.join("src");
+        std::fs::create_dir_all(&snippets_dir).unwrap();
+        std::fs::create_dir_all(&src_dir).unwrap();
+
+        // 手書きのスニペットだけがある状態
+        let original = "{\n    \"mine\": {\"prefix\": \"mine\", \"body\": \"mine\"}\n}\n";
+        let snippet_json_filepath = snippet::get_snippet_json_filepath(
+            snippet::GLOBAL_IDENTIFIER,
+            &snippets_dir.to_string_lossy(),
+        );
+        std::fs::write(&snippet_json_filepath, original).unwrap();
+        for i in 0..12 {
+            let text = format!(
+                "// #PORT#\n// name: \"s{0}\"\n// prefix: \"s{0}\"\nbody{0}\n// #PORT_END#\n",
+                i
+            );
+            std::fs::write(src_dir.join(format!("f{:02}.txt", i)), text).unwrap();
+        }
+
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "snippets_dir": snippets_dir,
+            "dirs": [src_dir],
+            "files": [],
+            "global_snippets": true,
+        }))
+        .unwrap();
+        assert!(build(&config, false));
+        let built = std::fs::read_to_string(&snippet_json_filepath).unwrap();
+        assert!(built.contains("\"s11\""));
+
+        // build前の状態のバックアップが1つだけ残る
+        let meta_dir = snippet::get_meta_dir_path(&config.snippets_dir);
+        let count_backups = || backup::list(&meta_dir, Some(snippet::GLOBAL_IDENTIFIER)).len();
+        assert_eq!(count_backups(), 1);
+
+        // 消えたファイルの削除と生成で2度書き込んでも、バックアップは1つ
+        std::fs::remove_file(src_dir.join("f00.txt")).unwrap();
+        std::fs::write(
+            src_dir.join("f01.txt"),
+            "// #PORT#\n// name: \"s1\"\n// prefix: \"s1\"\nchanged\n// #PORT_END#\n",
+        )
+        .unwrap();
+        assert!(build(&config, false));
+        assert_eq!(count_backups(), 2);
+
+        // 戻す前の内容もバックアップされるので、元の状態は3番目
+        assert!(restore(&config, snippet::GLOBAL_IDENTIFIER, 1, false));
+        assert_eq!(
+            std::fs::read_to_string(&snippet_json_filepath).unwrap(),
+            built
+        );
+        assert!(restore(&config, snippet::GLOBAL_IDENTIFIER, 3, false));
+        assert_eq!(
+            std::fs::read_to_string(&snippet_json_filepath).unwrap(),
+            original
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn build_overwritingFileRemoved_snippetSurvives() {
+        let root = prepare_dir("overwrite");
+        let snippets_dir = root.join("snippets");
+        let src_dir = root.join("src");
+        std::fs::create_dir_all(&snippets_dir).unwrap();
+        std::fs::create_dir_all(&src_dir).unwrap();
+        let define = |file: &str, body: &str| {
+            let text = format!(
+                "// #PORT#\n// name: \"dup\"\n// prefix: \"dup\"\n{}\n// #PORT_END#\n",
+                body
+            );
+            std::fs::write(src_dir.join(file), text).unwrap();
+        };
+        define("a.txt", "from_a");
+        define("b.txt", "from_b");
+
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "snippets_dir": snippets_dir,
+            "dirs": [src_dir],
+            "files": [],
+            "global_snippets": true,
+        }))
+        .unwrap();
+        let snippet_json_filepath = snippet::get_snippet_json_filepath(
+            snippet::GLOBAL_IDENTIFIER,
+            &snippets_dir.to_string_lossy(),
+        );
+        let read_json = || std::fs::read_to_string(&snippet_json_filepath).unwrap();
+
+        // 後から生成したb.txtが上書きする (走査し直しても変わらない)
+        assert!(build(&config, false));
+        assert!(read_json().contains("from_b"));
+        assert!(build(&config, false));
+        assert!(read_json().contains("from_b"));
+
+        // b.txtから消すと、a.txtのスニペットに戻る
+        std::fs::write(src_dir.join("b.txt"), "no snippet\n").unwrap();
+        assert!(build(&config, false));
+        assert!(read_json().contains("from_a"));
+
+        // 上書きしたファイルを削除しても、a.txtのスニペットに戻る
+        define("b.txt", "from_b");
+        assert!(build(&config, false));
+        assert!(read_json().contains("from_b"));
+        std::fs::remove_file(src_dir.join("b.txt")).unwrap();
+        assert!(build(&config, false));
+        assert!(read_json().contains("from_a"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn reconcile_missingBatch_movedAndRemoved() {
+        let root = prepare_dir("reconcile");
+        let snippets_dir = root.join("snippets");
+        let src_dir = root.join("src");
+        std::fs::create_dir_all(&snippets_dir).unwrap();
+        std::fs::create_dir_all(&src_dir).unwrap();
+        let define = |file: &str, name: &str| {
+            let text = format!(
+                "// #PORT#\n// name: \"{}\"\n// prefix: \"{}\"\nbody\n// #PORT_END#\n",
+                name, name
+            );
+            std::fs::write(src_dir.join(file), text).unwrap();
+        };
+        define("a.txt", "snip_a");
+        define("b.txt", "snip_b");
+        define("c.txt", "snip_c");
+
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "snippets_dir": snippets_dir,
+            "dirs": [src_dir],
+            "files": [],
+            "global_snippets": true,
+        }))
+        .unwrap();
+        assert!(build(&config, false));
+
+        // a.txtはd.txtに移動して、b.txtは削除した (c.txtは消えていないので触らない)
+        std::fs::rename(src_dir.join("a.txt"), src_dir.join("d.txt")).unwrap();
+        std::fs::remove_file(src_dir.join("b.txt")).unwrap();
+        let missing = vec![src_dir.join("a.txt"), src_dir.join("b.txt")];
+        let mut forgotten = reconcile(&config, Some(&missing), None);
+        forgotten.sort();
+        let expected: Vec<String> = missing
+            .iter()
+            .map(|path| path.to_string_lossy().to_string())
+            .collect();
+        assert_eq!(forgotten, expected);
+
+        let snippets_dir_string = snippets_dir.to_string_lossy().to_string();
+        let list_filepath =
+            snippet::get_namelist_filepath(snippet::GLOBAL_IDENTIFIER, &snippets_dir_string);
+        let list_text = std::fs::read_to_string(&list_filepath).unwrap();
+        let name_list = serde_json::from_str::<KeyList>(&list_text).unwrap();
+        let mut paths: Vec<String> = name_list.keys().cloned().collect();
+        paths.sort();
+        let expected: Vec<String> = ["c.txt", "d.txt"]
+            .iter()
+            .map(|file| src_dir.join(file).to_string_lossy().to_string())
+            .collect();
+        assert_eq!(paths, expected);
+
+        let snippet_json_filepath =
+            snippet::get_snippet_json_filepath(snippet::GLOBAL_IDENTIFIER, &snippets_dir_string);
+        let json = std::fs::read_to_string(&snippet_json_filepath).unwrap();
+        assert!(json.contains("snip_a"));
+        assert!(!json.contains("snip_b"));
+        assert!(json.contains("snip_c"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn build_dryRunRemoved_notWritten() {
+        let root = prepare_dir("dryrun");
+        let snippets_dir = root.join("snippets");
+        let src_dir = root.join("src");
+        std::fs::create_dir_all(&snippets_dir).unwrap();
+        std::fs::create_dir_all(&src_dir).unwrap();
+        for name in ["snip_a", "snip_b"].iter() {
+            let text = format!(
+                "// #PORT#\n// name: \"{}\"\n// prefix: \"{}\"\nbody\n// #PORT_END#\n",
+                name, name
+            );
+            std::fs::write(src_dir.join(format!("{}.txt", name)), text).unwrap();
+        }
+
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "snippets_dir": snippets_dir,
+            "dirs": [src_dir],
+            "files": [],
+            "global_snippets": true,
+        }))
+        .unwrap();
+        assert!(build(&config, false));
+
+        let snippets_dir_string = snippets_dir.to_string_lossy().to_string();
+        let snippet_json_filepath =
+            snippet::get_snippet_json_filepath(snippet::GLOBAL_IDENTIFIER, &snippets_dir_string);
+        let list_filepath =
+            snippet::get_namelist_filepath(snippet::GLOBAL_IDENTIFIER, &snippets_dir_string);
+        let json = std::fs::read_to_string(&snippet_json_filepath).unwrap();
+        let list_text = std::fs::read_to_string(&list_filepath).unwrap();
+
+        // 削除したファイルのスニペットは、書き込まずに残した方からだけ消える
+        std::fs::remove_file(src_dir.join("snip_b.txt")).unwrap();
+        let mut build_context = BuildContext::new(true);
+        let forgotten = reconcile(&config, None, Some(&mut build_context));
+        assert_eq!(forgotten.len(), 1);
+        let (staged_json, staged_list) = &build_context.staged[snippet::GLOBAL_IDENTIFIER];
+        assert!(staged_json.contains("snip_a"));
+        assert!(!staged_json.contains("snip_b"));
+        assert!(!staged_list.contains("snip_b.txt"));
+
+        assert!(build(&config, true));
+        assert_eq!(
+            std::fs::read_to_string(&snippet_json_filepath).unwrap(),
+            json
+        );
+        assert_eq!(std::fs::read_to_string(&list_filepath).unwrap(), list_text);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn loadConfig_debounce_invalid() {
+        let root = prepare_dir("debounce");
+        let config_path = root.join("config.json");
+        let options = Options {
+            config: Some(config_path.to_string_lossy().to_string()),
+            ..Options::default()
+        };
+        let write_config = |debounce: serde_json::Value| {
+            let mut config = serde_json::json!({
+                "snippets_dir": root,
+                "dirs": [],
+                "files": [],
+            });
+            for (key, value) in debounce.as_object().unwrap() {
+                config[key] = value.clone();
+            }
+            std::fs::write(&config_path, config.to_string()).unwrap();
+        };
+
+        write_config(serde_json::json!({}));
+        assert!(load_config(&options).is_ok());
+        write_config(serde_json::json!({"debounce_ms": 500, "debounce_max_wait_ms": 500}));
+        assert!(load_config(&options).is_ok());
+
+        write_config(serde_json::json!({"debounce_ms": 0}));
+        let e = load_config(&options).unwrap_err();
+        assert!(e.contains("debounce_ms"));
+        write_config(serde_json::json!({"debounce_ms": 2000, "debounce_max_wait_ms": 1000}));
+        let e = load_config(&options).unwrap_err();
+        assert!(e.contains("debounce_max_wait_ms"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn printStatus_neverSynced_noMetaDir() {
+        let root = prepare_dir("status");
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "snippets_dir": root,
+            "dirs": [],
+            "files": [],
+        }))
+        .unwrap();
+
+        print_status(&config, false);
+        print_status(&config, true);
+        assert!(!snippet::get_meta_dir_path(&config.snippets_dir).exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn build_missingSnippetsDir_error() {
+        let root = prepare_dir("missing");
+        std::fs::write(
+            root.join("a.txt"),
+            "// #PORT#\n// name: \"a\"\n// prefix: \"a\"\na\n// #PORT_END#\n",
+        )
+        .unwrap();
+
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "snippets_dir": root.join("missing"),
+            "dirs": [root],
+            "files": [],
+            "global_snippets": true,
+        }))
+        .unwrap();
+        assert!(!build(&config, false));
+        assert!(!root.join("missing").exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
@@ -0,0 +1,175 @@
+// スニペットの本文のプレースホルダを、VSCodeのタブストップに変換する
+// 元のファイルがそのままコンパイルできるように、コメントか識別子として書く
+//
+// /*${1:n}*/n  → ${1:n}  (コメントの中のタブストップ。直後の仮の値 n は消す)
+// /*$0*/       → $0
+// __PS1_n__    → ${1:n}  (識別子のマーカー。__PS は設定で変えられる)
+// __PS2__      → $2
+//
+// それ以外の $ はVSCodeの変数にならないように \$ にする
+
+pub const DEFAULT_MARKER: &str = "__PS";
+
+// 本文を変換する (markerが空なら、識別子のマーカーは使わない)
+pub fn convert(body: &str, marker: &str) -> String {
+    let mut result = String::with_capacity(body.len());
+    let mut previous: Option<char> = None;
+    let mut rest = body;
+
+    while let Some(c) = rest.chars().next() {
+        let found = read_comment(rest).or_else(|| {
+            if previous.is_some_and(is_identifier) {
+                return None;
+            }
+            return read_marker(rest, marker);
+        });
+        if let Some((tabstop, length)) = found {
+            result.push_str(&tabstop);
+            previous = rest[..length].chars().last();
+            rest = &rest[length..];
+            continue;
+        }
+
+        match c {
+            '$' => result.push_str("\\$"),
+            // \$ \} \\ はVSCodeのエスケープとして読まれるので、\ をそのまま残す
+            '\\' if rest[1..].starts_with(['$', '}', '\\']) => result.push_str("\\\\"),
+            _ => result.push(c),
+        }
+        previous = Some(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    return result;
+}
+
+fn is_identifier(c: char) -> bool {
+    return c.is_ascii_alphanumeric() || c == '_';
+}
+
+// 先頭の数字の長さ
+fn count_digits(text: &str) -> usize {
+    return text.bytes().take_while(|b| b.is_ascii_digit()).count();
+}
+
+// /*<タブストップ>*/<仮の値> を読む
+// 仮の値はタブストップの初期値 (選択肢なら1つ目) と同じときだけ消す
+fn read_comment(text: &str) -> Option<(String, usize)> {
+    if !text.starts_with("/*$") {
+        return None;
+    }
+    let end = text.find("*/")?;
+    let tabstop = &text[2..end];
+    if tabstop.contains('\n') {
+        return None;
+    }
+    let stand_in = parse_tabstop(tabstop)?;
+
+    let mut length = end + 2;
+    if text[length..].starts_with(stand_in) {
+        length += stand_in.len();
+    }
+    return Some((tabstop.to_string(), length));
+}
+
+// $1, ${1}, ${1:初期値}, ${1|a,b|} を読み、初期値を返す
+fn parse_tabstop(text: &str) -> Option<&str> {
+    let text = text.strip_prefix('$')?;
+    let digits = count_digits(text);
+    if digits == text.len() {
+        return if digits > 0 { Some("") } else { None };
+    }
+
+    let text = text.strip_prefix('{')?.strip_suffix('}')?;
+    let digits = count_digits(text);
+    if digits == 0 {
+        return None;
+    }
+    let rest = &text[digits..];
+    if rest.is_empty() {
+        return Some("");
+    }
+    if let Some(default) = rest.strip_prefix(':') {
+        return Some(default);
+    }
+    let choices = rest.strip_prefix('|')?.strip_suffix('|')?;
+    return choices.split(',').next();
+}
+
+// <marker><番号>__ または <marker><番号>_<初期値>__ を読む
+fn read_marker(text: &str, marker: &str) -> Option<(String, usize)> {
+    if marker.is_empty() || !text.starts_with(marker) {
+        return None;
+    }
+    let after = &text[marker.len()..];
+    let digits = count_digits(after);
+    if digits == 0 {
+        return None;
+    }
+    let number = &after[..digits];
+    let tail_length = after[digits..]
+        .chars()
+        .take_while(|c| is_identifier(*c))
+        .count(); // ASCIIだけなのでバイト数と同じ
+    let tail = &after[digits..digits + tail_length];
+    let length = marker.len() + digits + tail_length;
+
+    if tail == "__" {
+        return Some((format!("${}", number), length));
+    }
+    let default = tail.strip_prefix('_')?.strip_suffix("__")?;
+    if default.is_empty() {
+        return None;
+    }
+    return Some((format!("${{{}:{}}}", number, default), length));
+}
+
+///// Unit Test
+#[cfg(test)]
+mod tests {
+    use crate::placeholder::*;
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn convert_comment_valid() {
+        assert_eq!(convert("int f(int /*${1:n}*/n);", ""), "int f(int ${1:n});");
+        assert_eq!(convert("/*$0*/return x;", ""), "$0return x;");
+        assert_eq!(convert("/*${2}*/", ""), "${2}");
+        assert_eq!(
+            convert("std::/*${1|cin,cout|}*/cin", ""),
+            "std::${1|cin,cout|}"
+        );
+        // 初期値と違う仮の値は残す
+        assert_eq!(convert("/*${1:n}*/m", ""), "${1:n}m");
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn convert_marker_valid() {
+        let marker = DEFAULT_MARKER;
+        assert_eq!(
+            convert(
+                "for (int __PS1_i__ = 0; __PS1_i__ < __PS2_n__; ++__PS1_i__) {__PS0__}",
+                marker
+            ),
+            "for (int ${1:i} = 0; ${1:i} < ${2:n}; ++${1:i}) {$0}"
+        );
+        assert_eq!(convert("__PS1_max_value__", marker), "${1:max_value}");
+        assert_eq!(convert("@@1_x__", "@@"), "${1:x}");
+        // 識別子の途中にあるもの、形が違うものはそのまま
+        assert_eq!(
+            convert("a__PS1__ __PS__ __PS1_x", marker),
+            "a__PS1__ __PS__ __PS1_x"
+        );
+        assert_eq!(convert("__PS1__", ""), "__PS1__");
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn convert_literalDollar_escaped() {
+        assert_eq!(convert("echo \"$HOME\"", ""), "echo \"\\$HOME\"");
+        assert_eq!(convert("$a = ${b};", ""), "\\$a = \\${b};");
+        assert_eq!(convert("/* $1 */", ""), "/* \\$1 */");
+        assert_eq!(convert("\"\\\\n\" \\$", ""), "\"\\\\\\n\" \\\\\\$");
+        assert_eq!(convert("printf(\"\\n\");", ""), "printf(\"\\n\");");
+    }
+}
@@ -0,0 +1,355 @@
+use super::jsonc;
+use super::snippet::{self, KeyList};
+use std::collections::{BTreeMap, HashMap};
+
+// snippets_dirの<lang>.jsonにあるスニペットのprefixが被っていないか調べる
+// VSCodeは入力と先頭が一致するスニペットをすべて候補に出すので、
+// 同じprefixや、他のprefixの先頭と一致するprefixがあると紛らわしい
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub lang: String,
+    pub name: String,
+    pub prefix: String,
+    pub source: Option<String>, // 生成元のファイル (手書きのスニペットならNone)
+    pub line: usize,            // <lang>.json内の行
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IssueKind {
+    Duplicate, // 同じprefixのスニペットが複数ある
+    Shadowing, // prefixが他のprefixの先頭と一致する
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub lang: String,
+    pub prefix: String,
+    pub entries: Vec<Entry>, // prefixのスニペット (Shadowingなら、続けてprefixで始まるスニペット)
+}
+
+// snippets_dirの<lang>.jsonと、そのnamelistをすべて読み込む
+pub fn load(snippets_dir: &str) -> Vec<Entry> {
+    let dir_entries = match std::fs::read_dir(snippets_dir) {
+        Ok(dir_entries) => dir_entries,
+        Err(_) => return vec![],
+    };
+    let mut paths: Vec<std::path::PathBuf> = dir_entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+
+    let mut entries = vec![];
+    for path in paths {
+        let lang = match path.file_stem() {
+            Some(stem) => stem.to_string_lossy().to_string(),
+            None => continue,
+        };
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(_) => continue,
+        };
+        let list_filepath = snippet::get_namelist_filepath(&lang, snippets_dir);
+        let name_list = std::fs::read_to_string(&list_filepath)
+            .ok()
+            .and_then(|contents| serde_json::from_str::<KeyList>(&contents).ok())
+            .unwrap_or_default();
+        entries.extend(read_entries(&lang, &text, &name_list));
+    }
+    return entries;
+}
+
+// <lang>.jsonからスニペットのprefixを取り出す (prefixのないものは飛ばす)
+pub fn read_entries(lang: &str, text: &str, name_list: &KeyList) -> Vec<Entry> {
+    let document = match jsonc::Document::parse(text) {
+        Ok(document) => document,
+        Err(e) => {
+            println!("cannot parse {}.json: {}", lang, e);
+            return vec![];
+        }
+    };
+    let object = match &document.object {
+        Some(object) => object,
+        None => return vec![],
+    };
+
+    let mut owners: HashMap<&str, &str> = HashMap::new();
+    for (path, names) in name_list.iter() {
+        for owned in names.iter().filter(|n| !n.shadowed) {
+            owners.insert(&owned.name, path);
+        }
+    }
+
+    let mut entries = vec![];
+    for member in object.members.iter() {
+        let value = match serde_json::from_str::<serde_json::Value>(document.slice(&member.value)) {
+            Ok(value) => value,
+            Err(_) => continue, // 値の中にコメントがある
+        };
+        let line = document.text[..member.span.start].matches('\n').count() + 1;
+        for prefix in get_prefixes(&value) {
+            entries.push(Entry {
+                lang: lang.to_string(),
+                name: member.key.clone(),
+                prefix: prefix,
+                source: owners.get(member.key.as_str()).map(|path| path.to_string()),
+                line: line,
+            });
+        }
+    }
+    return entries;
+}
+
+// "prefix" は文字列か文字列の配列
+fn get_prefixes(value: &serde_json::Value) -> Vec<String> {
+    let prefixes = match value.get("prefix") {
+        Some(serde_json::Value::String(prefix)) => vec![prefix.clone()],
+        Some(serde_json::Value::Array(prefixes)) => prefixes
+            .iter()
+            .filter_map(|prefix| prefix.as_str().map(|prefix| prefix.to_string()))
+            .collect(),
+        _ => vec![],
+    };
+    return prefixes
+        .into_iter()
+        .filter(|prefix| !prefix.is_empty())
+        .collect();
+}
+
+// 言語ごとに、同じprefixと、他のprefixの先頭と一致するprefixを探す
+pub fn find_issues(entries: &[Entry]) -> Vec<Issue> {
+    let mut langs: BTreeMap<&str, BTreeMap<&str, Vec<&Entry>>> = BTreeMap::new();
+    for entry in entries.iter() {
+        langs
+            .entry(&entry.lang)
+            .or_default()
+            .entry(&entry.prefix)
+            .or_default()
+            .push(entry);
+    }
+
+    let mut issues = vec![];
+    for (lang, prefixes) in langs.iter() {
+        for (prefix, group) in prefixes.iter() {
+            if group.len() > 1 {
+                issues.push(Issue {
+                    kind: IssueKind::Duplicate,
+                    lang: lang.to_string(),
+                    prefix: prefix.to_string(),
+                    entries: group.iter().map(|entry| (*entry).clone()).collect(),
+                });
+            }
+
+            // 名前順に並んでいるので、prefixで始まるものはすぐ後ろに続く
+            // 同じスニペットの別のprefix (prefix: ["seg", "segtree"]) は紛らわしくないので除く
+            let longer: Vec<&Entry> = prefixes
+                .range(*prefix..)
+                .skip(1)
+                .take_while(|(other, _)| other.starts_with(prefix))
+                .flat_map(|(_, others)| others.iter().cloned())
+                .filter(|other| group.iter().any(|entry| entry.name != other.name))
+                .collect();
+            if !longer.is_empty() {
+                issues.push(Issue {
+                    kind: IssueKind::Shadowing,
+                    lang: lang.to_string(),
+                    prefix: prefix.to_string(),
+                    entries: group
+                        .iter()
+                        .chain(longer.iter())
+                        .map(|e| (*e).clone())
+                        .collect(),
+                });
+            }
+        }
+    }
+    return issues;
+}
+
+// スニペットがどこにあるか (生成元のファイル or 手書き)
+fn format_origin(entry: &Entry) -> String {
+    return match &entry.source {
+        Some(source) => source.clone(),
+        None => format!("{}.json:{} (hand-written)", entry.lang, entry.line),
+    };
+}
+
+// checkコマンドで、生成元のファイルに出す警告
+pub fn describe(issue: &Issue, entry: &Entry) -> String {
+    let others: Vec<String> = issue
+        .entries
+        .iter()
+        .filter(|other| *other != entry)
+        .filter(|other| issue.kind == IssueKind::Duplicate || other.prefix != issue.prefix)
+        .map(|other| match issue.kind {
+            IssueKind::Duplicate => format!("\"{}\" ({})", other.name, format_origin(other)),
+            IssueKind::Shadowing => format!(
+                "\"{}\" of \"{}\" ({})",
+                other.prefix,
+                other.name,
+                format_origin(other)
+            ),
+        })
+        .collect();
+
+    return match issue.kind {
+        IssueKind::Duplicate => format!(
+            "prefix \"{}\" is also used by {}",
+            issue.prefix,
+            others.join(", ")
+        ),
+        IssueKind::Shadowing => format!(
+            "prefix \"{}\" is the start of {}",
+            issue.prefix,
+            others.join(", ")
+        ),
+    };
+}
+
+// prefixesコマンドの出力
+//
+// cpp: duplicate prefix "seg"
+//   SegmentTree               seg           /src/seg.cpp
+//   seg                       seg           cpp.json:12 (hand-written)
+pub fn format_report(issues: &[Issue]) -> String {
+    let mut text = String::new();
+    for issue in issues.iter() {
+        let title = match issue.kind {
+            IssueKind::Duplicate => format!("duplicate prefix \"{}\"", issue.prefix),
+            IssueKind::Shadowing => {
+                format!("prefix \"{}\" is the start of other prefixes", issue.prefix)
+            }
+        };
+        text.push_str(&format!("{}: {}\n", issue.lang, title));
+        for entry in issue.entries.iter() {
+            text.push_str(&format!(
+                "  {:<24}  {:<12}  {}\n",
+                entry.name,
+                entry.prefix,
+                format_origin(entry)
+            ));
+        }
+        text.push('\n');
+    }
+
+    let duplicates = issues
+        .iter()
+        .filter(|issue| issue.kind == IssueKind::Duplicate)
+        .count();
+    if issues.is_empty() {
+        text.push_str("no prefix collision found.\n");
+    } else {
+        text.push_str(&format!(
+            "{} duplicate prefixes, {} prefixes shadowing others.\n",
+            duplicates,
+            issues.len() - duplicates
+        ));
+    }
+    return text;
+}
+
+///// Unit Test
+
+#[cfg(test)]
+mod tests {
+    use crate::prefix::*;
+
+    const MOCK_JSON: &str = r#"{
+	// hand-written
+	"mine": {"prefix": ["seg", "dump"], "body": ""},
+////////// [[Generated By PortSnippet]] (DON'T REMOVE) //////////
+  "SegmentTree": {
+    "prefix": "seg",
+    "body": "",
+    "description": ""
+  },
+  "segtree2": {
+    "prefix": "segtree",
+    "body": "",
+    "description": ""
+  },
+  "other": {
+    "prefix": "sg",
+    "body": "",
+    "description": ""
+  }
+////////// [[PortSnippet End]] (DON'T REMOVE) //////////
+}"#;
+
+    fn mock_entries() -> Vec<Entry> {
+        let name_list = serde_json::from_str::<KeyList>(
+            r#"{"/src/seg.cpp":["SegmentTree","segtree2"],"/src/sg.cpp":["other"]}"#,
+        )
+        .unwrap();
+        return read_entries("cpp", MOCK_JSON, &name_list);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn readEntries_valid() {
+        let entries = mock_entries();
+        let prefixes: Vec<(&str, &str)> = entries
+            .iter()
+            .map(|e| (e.name.as_str(), e.prefix.as_str()))
+            .collect();
+        assert_eq!(
+            prefixes,
+            vec![
+                ("mine", "seg"),
+                ("mine", "dump"),
+                ("SegmentTree", "seg"),
+                ("segtree2", "segtree"),
+                ("other", "sg"),
+            ]
+        );
+        assert_eq!(entries[0].source, None);
+        assert_eq!(entries[0].line, 3);
+        assert_eq!(entries[2].source, Some(String::from("/src/seg.cpp")));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn findIssues_duplicateAndShadowing_valid() {
+        let entries = mock_entries();
+        let issues = find_issues(&entries);
+        assert_eq!(issues.len(), 2);
+
+        assert_eq!(issues[0].kind, IssueKind::Duplicate);
+        assert_eq!(issues[0].prefix, "seg");
+        let names: Vec<&str> = issues[0].entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["mine", "SegmentTree"]);
+
+        assert_eq!(issues[1].kind, IssueKind::Shadowing);
+        assert_eq!(issues[1].entries.len(), 3);
+        assert_eq!(issues[1].entries[2].prefix, "segtree");
+
+        assert_eq!(
+            describe(&issues[0], &issues[0].entries[1]),
+            "prefix \"seg\" is also used by \"mine\" (cpp.json:3 (hand-written))"
+        );
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn findIssues_sameSnippetPrefixes_ignored() {
+        let json = r#"{"seg": {"prefix": ["seg", "segtree"], "body": ""}}"#;
+        let entries = read_entries("cpp", json, &KeyList::new());
+        assert_eq!(entries.len(), 2);
+        assert!(find_issues(&entries).is_empty());
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn findIssues_otherLang_ignored() {
+        let mut entries = mock_entries();
+        entries.retain(|e| e.name != "mine");
+        for entry in entries.iter_mut().filter(|e| e.name == "segtree2") {
+            entry.lang = String::from("rust");
+        }
+        assert!(find_issues(&entries).is_empty());
+    }
+}
@@ -1,42 +1,249 @@
-extern crate regex;
-use regex::Regex;
-
-use super::file::Reader;
+use super::diagnostic::{Diagnostic, Severity};
+use super::file::{FileLock, Reader};
+use super::header::{self, Line, Value};
+use super::jsonc;
+use super::placeholder;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 
 ///// Type
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 struct Snippet {
-    prefix: String,
-    body: String,
+    prefix: Prefix,
+    body: Body,
     description: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scope: Option<String>, // 使える言語 ("c,cpp" など。なければすべての言語)
+    #[serde(rename = "isFileTemplate", default, skip_serializing_if = "is_false")]
+    is_file_template: bool, // 新しいファイルの雛形として使えるか
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    author: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    extra: BTreeMap<String, serde_json::Value>, // ヘッダに書かれたその他のキー
 }
 
 impl Snippet {
     fn new() -> Self {
         return Snippet {
-            prefix: String::new(),
-            body: String::new(),
+            prefix: Prefix::Many(vec![]),
+            body: Body::Text(String::new()),
             description: String::new(),
+            scope: None,
+            is_file_template: false,
+            tags: vec![],
+            author: None,
+            extra: BTreeMap::new(),
+        };
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    return !*value;
+}
+
+// VSCodeのprefixは文字列か、別名を並べた配列
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+enum Prefix {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Prefix {
+    // prefixを追加する (1つだけなら文字列として書き出す)
+    fn push(&mut self, prefix: String) {
+        let mut prefixes = match std::mem::replace(self, Prefix::Many(vec![])) {
+            Prefix::One(first) => vec![first],
+            Prefix::Many(prefixes) => prefixes,
+        };
+        if !prefixes.contains(&prefix) {
+            prefixes.push(prefix);
+        }
+        *self = match prefixes.len() {
+            1 => Prefix::One(prefixes.remove(0)),
+            _ => Prefix::Many(prefixes),
+        };
+    }
+}
+
+impl PartialEq<&str> for Prefix {
+    fn eq(&self, other: &&str) -> bool {
+        return matches!(self, Prefix::One(prefix) if prefix == other);
+    }
+}
+
+// VSCodeのbodyは文字列か、行の配列 (改行で繋げたものがbodyになる)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+enum Body {
+    Text(String),
+    Lines(Vec<String>),
+}
+
+impl Body {
+    fn text(&self) -> String {
+        return match self {
+            Body::Text(text) => text.clone(),
+            Body::Lines(lines) => lines.join("\n"),
         };
     }
+
+    // 行の配列にする (繋げると元のbodyに戻るように、末尾の改行は空の行として残す)
+    fn to_lines(&self) -> Body {
+        return Body::Lines(
+            self.text()
+                .split('\n')
+                .map(|line| line.to_string())
+                .collect(),
+        );
+    }
+}
+
+impl PartialEq<&str> for Body {
+    fn eq(&self, other: &&str) -> bool {
+        return self.text() == *other;
+    }
+}
+
+impl PartialEq<String> for Body {
+    fn eq(&self, other: &String) -> bool {
+        return self.text() == *other;
+    }
 }
 
 struct BandledSnippet {
     meta: SnippetMetaData,
-    code: String,
+    shadowed: SnippetNames, // 他のファイルが持ち主なので書き込まなかったスニペット (namelistにだけ記録する)
+}
+
+// namelistに記録する、生成したスニペット1つ分
+// hashは書き込んだときの内容のハッシュ (VSCode上で手で編集されたかどうかの判定に使う)
+// shadowedは、同じ名前を定義しているが他のファイルに上書きされたもの (hashはこのファイルでの内容)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(from = "NameEntry")]
+pub struct OwnedName {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub shadowed: bool,
+}
+
+// 以前のnamelistは名前だけを記録していた
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NameEntry {
+    Name(String),
+    Owned {
+        name: String,
+        hash: Option<String>,
+        #[serde(default)]
+        shadowed: bool,
+    },
+}
+
+impl From<NameEntry> for OwnedName {
+    fn from(entry: NameEntry) -> Self {
+        return match entry {
+            NameEntry::Name(name) => OwnedName {
+                name: name,
+                hash: None,
+                shadowed: false,
+            },
+            NameEntry::Owned {
+                name,
+                hash,
+                shadowed,
+            } => OwnedName {
+                name: name,
+                hash: hash,
+                shadowed: shadowed,
+            },
+        };
+    }
+}
+
+impl PartialEq<&str> for OwnedName {
+    fn eq(&self, other: &&str) -> bool {
+        return self.name == *other;
+    }
 }
 
-type SnippetNames = Vec<String>;
+type SnippetNames = Vec<OwnedName>;
 pub type KeyList = HashMap<String, SnippetNames>; // path, names
-type SnippetMetaData = HashMap<String, Snippet>; // name, Snippet
+type SnippetMetaData = BTreeMap<String, Snippet>; // name, Snippet (名前順に出力する)
+
+// 他のファイルのスニペットと名前が被ったときの扱い (config.jsonの"name_conflict")
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    Error,     // エラーにして書き込まない
+    Overwrite, // 後から生成した方で上書きする (警告を出す)
+    Namespace, // 後から生成した方を <relative-path>::<name> にする
+}
+
+impl Default for ConflictPolicy {
+    fn default() -> Self {
+        return ConflictPolicy::Overwrite;
+    }
+}
+
+// スニペットの名前の決め方
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Naming {
+    pub policy: ConflictPolicy,
+    pub namespace: String, // Namespaceのときの接頭辞 (監視しているフォルダからの相対パス)
+}
+
+// 書き込み先に合わせたスニペットの書き出し方
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct OutputStyle {
+    pub scope: Option<String>, // scopeが書かれていないスニペットに付けるscope
+    pub body_as_lines: bool,   // bodyを行の配列で書き出す
+    pub placeholder_marker: String, // 本文のプレースホルダの目印 (空なら使わない)
+}
+
+// 他のファイルと名前が被ったスニペット
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub name: String,
+    pub owner: String, // 先に同じ名前のスニペットを生成していたファイル
+    pub key: String,   // 実際に書き込んだ名前
+    // 持ち主のスニペットをそのまま残した (Overwriteで、こちらが前回から変わっていないとき)
+    #[serde(skip_serializing_if = "is_false")]
+    pub kept: bool,
+}
 
 #[derive(Debug, PartialEq)]
 pub struct Output {
     pub json: String,
     pub name_list: KeyList,
+    pub changes: Changes,
+    pub resync: Vec<String>, // 消したスニペットを同じ名前で定義している他のファイル (作り直せば復活する)
+}
+
+// 対象ファイルのスニペットが前回からどう変わったか
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct Changes {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub removed: Vec<String>,
+    pub edited: Vec<String>, // 手で書かれた・編集されたスニペットがあるので書き換えなかった
+    pub conflicts: Vec<Conflict>, // 他のファイルと名前が被ったスニペット (毎回報告するのでis_emptyには含めない)
+    pub fields: BTreeMap<String, Vec<String>>, // updatedのスニペットごとに、変わった項目 (prefix / body / description など)
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        return self.added.is_empty()
+            && self.updated.is_empty()
+            && self.removed.is_empty()
+            && self.edited.is_empty();
+    }
 }
 
 #[derive(PartialEq)]
@@ -44,15 +251,57 @@ enum SearchStep {
     StartTag,
     Meta,
     EndTag,
-    None,
 }
 
 #[derive(Debug, PartialEq)]
-enum TrimError {
+pub enum TrimError {
     InvalidName,
     InvalidPrefix,
     InvalidDescription,
+    InvalidScope,
+    InvalidValue(String), // name / prefix / description / scope 以外のキー
     InvalidMeta,
+    DuplicateName(String), // 同じファイルの中で同じnameが2回書かれている
+}
+
+impl std::fmt::Display for TrimError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let message = match self {
+            TrimError::InvalidName => "invalid name",
+            TrimError::InvalidPrefix => "invalid prefix",
+            TrimError::InvalidDescription => "invalid description",
+            TrimError::InvalidScope => "invalid scope",
+            TrimError::InvalidValue(key) => return write!(f, "invalid {}", key),
+            TrimError::InvalidMeta => "invalid form",
+            TrimError::DuplicateName(name) => return write!(f, "duplicate name `{}`", name),
+        };
+        return write!(f, "{}", message);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MakeError {
+    InvalidSnippet(TrimError), // メタタグが壊れている
+    NotFound,                  // スニペットが1つもない
+    InvalidJson,               // スニペットのjsonを書き換えられない
+    Io(String),                // ファイルを書き込めない
+    NameConflict(Conflict),    // 他のファイルと名前が被った (ConflictPolicy::Error)
+}
+
+impl std::fmt::Display for MakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        return match self {
+            MakeError::InvalidSnippet(e) => write!(f, "{}", e),
+            MakeError::NotFound => write!(f, "no snippet found"),
+            MakeError::InvalidJson => write!(f, "cannot update the snippet json"),
+            MakeError::Io(message) => write!(f, "{}", message),
+            MakeError::NameConflict(conflict) => write!(
+                f,
+                "\"{}\" is already defined in {}",
+                conflict.name, conflict.owner
+            ),
+        };
+    }
 }
 
 ///// Tag
@@ -60,9 +309,16 @@ enum TrimError {
 const START_TAG: &str = "#PORT#";
 const END_TAG: &str = "#PORT_END#";
 
-const NAME_RE: &str = "name:\\s*\"((?:[^\"]|\\.)*)\"";
-const PREFIX_RE: &str = "prefix:\\s*\"((?:[^\"]|\\.)*)\"";
-const DESC_RE: &str = "description:\\s*\"((?:[^\"]|\\.)*)\"";
+// ヘッダに書けるキー (これ以外のキーはextraに入れる)
+const META_KEYS: &[&str] = &[
+    "name",
+    "prefix",
+    "description",
+    "scope",
+    "isFileTemplate",
+    "tags",
+    "author",
+];
 
 const GEN_START_TAG: &str = "[[Generated By PortSnippet]]";
 const GEN_END_TAG: &str = "[[PortSnippet End]]";
@@ -71,18 +327,6 @@ fn format_tag(tag: &str) -> String {
     return format!("////////// {} (DON'T REMOVE) //////////", tag);
 }
 
-fn add_tag(code: &String) -> String {
-    let start_tag = format_tag(GEN_START_TAG);
-    let end_tag = format_tag(GEN_END_TAG);
-
-    let mut new_code = String::new();
-    new_code.push_str(format!("{}\n", start_tag).as_str());
-    new_code.push_str(&code);
-    new_code.push_str(format!("\n{}\n", end_tag).as_str());
-
-    return new_code;
-}
-
 //// Main
 
 // ファイルからスニペットを切り出して、スニペットのjsonを作成する
@@ -91,62 +335,197 @@ pub fn make<R: Reader>(
     snippet_json_reader: R,
     list_file_reader: &mut R,
     code_filepath_string: String,
-) -> Option<Output> {
+    naming: &Naming,
+    style: &OutputStyle,
+) -> Result<Output, MakeError> {
+    // 現存してるスニペット情報を取得する
+    let mut all_name_list = get_snippet_namelist(list_file_reader);
+
     // スニペットを切り出す
-    let snippet = gen_snippet_json(snippet_reader);
-    if snippet.is_none() {
-        return None;
-    }
+    let mut snippet = match gen_snippet_json(snippet_reader) {
+        Ok(snippet) => snippet,
+        // 前回あったスニペットがすべて消された場合は、空として扱って削除する
+        Err(MakeError::NotFound)
+            if filter_namelist(&all_name_list, &code_filepath_string)
+                .is_some_and(|names| !names.is_empty()) =>
+        {
+            BandledSnippet {
+                meta: SnippetMetaData::new(),
+                shadowed: SnippetNames::new(),
+            }
+        }
+        Err(e) => return Err(e),
+    };
 
-    let snippet = snippet.unwrap();
+    apply_style(&mut snippet.meta, style);
+
+    // 他のファイルと名前が被っていたら、namingに従って名前や持ち主を決める
+    let conflicts = resolve_conflicts(
+        &mut snippet,
+        &mut all_name_list,
+        &code_filepath_string,
+        naming,
+    )?;
+
+    let mut output = update_json(
+        snippet_json_reader,
+        all_name_list,
+        code_filepath_string,
+        &snippet,
+    )?;
+    output.changes.conflicts = conflicts;
+    return Ok(output);
+}
 
-    // 現存してるスニペット情報を取得する + コードの削除をチェック
-    let mut all_name_list = get_snippet_namelist(list_file_reader);
+// 存在しなくなったファイルのスニペットを削除して、namelistから外す
+// 手で編集されたスニペットはjsonに残す
+pub fn forget<R: Reader>(
+    snippet_json_reader: R,
+    list_file_reader: &mut R,
+    code_filepath_string: String,
+) -> Result<Output, MakeError> {
+    let all_name_list = get_snippet_namelist(list_file_reader);
+    let snippet = BandledSnippet {
+        meta: SnippetMetaData::new(),
+        shadowed: SnippetNames::new(),
+    };
+    let mut output = update_json(
+        snippet_json_reader,
+        all_name_list,
+        code_filepath_string.clone(),
+        &snippet,
+    )?;
+    output.name_list.remove(&code_filepath_string);
+    return Ok(output);
+}
+
+// readerのファイルが持つスニペットのハッシュ (ファイルの移動・名前の変更の検出に使う)
+// styleはmakeと同じ。メタタグが壊れていればNone
+pub fn get_snippet_hashes<R: Reader>(reader: R, style: &OutputStyle) -> Option<Vec<String>> {
+    let mut meta = trim_code(reader).ok()?;
+    apply_style(&mut meta, style);
+    let mut hashes: Vec<String> = meta.values().map(hash_snippet).collect();
+    hashes.sort();
+    return Some(hashes);
+}
+
+// namesと同じ内容のスニペットか (hashesはget_snippet_hashesの結果)
+pub fn has_same_snippets(names: &SnippetNames, hashes: &[String]) -> bool {
+    let recorded: Option<Vec<String>> = names.iter().map(|n| n.hash.clone()).collect();
+    let mut recorded = match recorded {
+        Some(recorded) if !recorded.is_empty() => recorded,
+        _ => return false, // 以前のnamelistにはハッシュがない
+    };
+    recorded.sort();
+    return recorded == hashes;
+}
+
+// snippetをスニペットのjsonに反映して、namelistの対象ファイルの部分を書き換える
+fn update_json<R: Reader>(
+    snippet_json_reader: R,
+    mut all_name_list: KeyList,
+    code_filepath_string: String,
+    snippet: &BandledSnippet,
+) -> Result<Output, MakeError> {
     let name_list = filter_namelist(&all_name_list, &code_filepath_string);
 
     // 現在編集しているファイルに関してリストを持ってくる
     let mut deleted_name_list: Option<HashSet<String>> = None;
     if let Some(name_list) = name_list {
-        deleted_name_list = Some(get_deleted_list(&snippet, &name_list));
+        deleted_name_list = Some(get_deleted_list(snippet, &name_list));
     }
 
-    // タグを探索し、過去に配置したコードを書き換える
-    if let Some(alljson) = gen_alljson(snippet_json_reader, &deleted_name_list, &snippet) {
-        // namelist を書き換える
-        all_name_list = update_name_list(
-            &code_filepath_string,
-            &all_name_list,
+    // スニペットのjsonのうち、PortSnippetが書き込んだものだけを書き換える
+    if let Some(alljson) = gen_alljson(
+        snippet_json_reader,
+        &all_name_list,
+        name_list,
+        &deleted_name_list,
+        snippet,
+    ) {
+        let changes = get_changes(
+            snippet,
             &name_list,
-            deleted_name_list,
-            &snippet,
+            &deleted_name_list,
+            &alljson.ported,
+            &alljson.edited,
         );
 
-        return Some(Output {
-            json: alljson,
+        // 消したスニペットを他のファイルも定義していたら、そのファイルから作り直してもらう
+        let resync = match &deleted_name_list {
+            Some(deleted_name_list) => {
+                find_shadowing(&all_name_list, deleted_name_list, &code_filepath_string)
+            }
+            None => vec![],
+        };
+
+        // namelist を書き換える
+        let mut owned = alljson.owned;
+        owned.extend(snippet.shadowed.iter().cloned());
+        owned.sort_by(|a, b| a.name.cmp(&b.name));
+        all_name_list.insert(code_filepath_string, owned);
+
+        return Ok(Output {
+            json: alljson.json,
             name_list: all_name_list,
+            changes: changes,
+            resync: resync,
         });
     }
 
-    return None;
+    return Err(MakeError::InvalidJson);
 }
 
 ///// namelist
 
-// lang_identifierごとのnamelistのファイルパスを返す
-pub fn get_namelist_filepath(lang_identifier: &str, snippets_dir: &str) -> std::path::PathBuf {
+// すべてのスニペットを1つのファイルに書き込むときのidentifier (namelist・ロック・バックアップにも使う)
+pub const GLOBAL_IDENTIFIER: &str = "portsnippet";
+
+// メタデータを保存するフォルダ(snippets_dir/.port_snippet)のパスを返す (フォルダは作らない)
+pub fn get_meta_dir_path(snippets_dir: &str) -> std::path::PathBuf {
     let mut meta_dir = std::path::PathBuf::from(snippets_dir);
     meta_dir.push(".port_snippet");
+    return meta_dir;
+}
+
+// メタデータを保存するフォルダ(snippets_dir/.port_snippet)のパスを返す
+pub fn get_meta_dir(snippets_dir: &str) -> std::path::PathBuf {
+    let meta_dir = get_meta_dir_path(snippets_dir);
     match std::fs::create_dir(&meta_dir) {
         // フォルダを作成
         Err(_) => {}
         Ok(_) => {}
     }
 
-    let mut list_filepath = meta_dir;
+    return meta_dir;
+}
+
+// lang_identifierごとのnamelistのファイルパスを返す
+pub fn get_namelist_filepath(lang_identifier: &str, snippets_dir: &str) -> std::path::PathBuf {
+    let mut list_filepath = get_meta_dir_path(snippets_dir);
     list_filepath.push(format!("{}.json", lang_identifier));
     return list_filepath;
 }
 
+// lang_identifierごとのスニペットのjson (snippets_dir/<lang>.json) のファイルパスを返す
+// GLOBAL_IDENTIFIERなら、すべての言語で使うsnippets_dir/portsnippet.code-snippets
+pub fn get_snippet_json_filepath(lang_identifier: &str, snippets_dir: &str) -> std::path::PathBuf {
+    let mut snippet_json_filepath = std::path::PathBuf::from(snippets_dir);
+    if lang_identifier == GLOBAL_IDENTIFIER {
+        snippet_json_filepath.push(format!("{}.code-snippets", lang_identifier));
+    } else {
+        snippet_json_filepath.push(format!("{}.json", lang_identifier));
+    }
+    return snippet_json_filepath;
+}
+
+// lang_identifierのスニペットのjson / namelistを読み書きする間握るロック (.port_snippet/write-<lang>.lock)
+// 同じsnippets_dirを扱う他のPortSnippet(build, restoreなど)や、他のスレッドと共有する
+pub fn lock(snippets_dir: &str, lang_identifier: &str) -> std::io::Result<FileLock> {
+    let meta_dir = get_meta_dir(snippets_dir);
+    return super::file::lock_file(&meta_dir.join(format!("write-{}.lock", lang_identifier)));
+}
+
 fn get_snippet_namelist<T: Reader>(list_file_reader: &mut T) -> KeyList {
     if let Ok(name_list_vec) = serde_json::from_str::<KeyList>(list_file_reader.all().as_str()) {
         return name_list_vec;
@@ -167,60 +546,125 @@ fn filter_namelist<'a>(
     return None;
 }
 
-// name_listをupdateする
-fn update_name_list(
-    code_filepath_string: &String,
-    all_name_list: &KeyList,
-    name_list: &Option<&SnippetNames>,
-    deleted_name_list: Option<HashSet<String>>,
-    snippet: &BandledSnippet,
-) -> KeyList {
-    let mut all_name_list = all_name_list.clone();
+//// Snippet
 
-    if let Some(name_list) = name_list {
-        // すでにnamelistが存在していた場合
-        let name_list = name_list.clone(); // all_name_listの参照を指しているので、contains判定のため一度cloneする
-
-        // 新しいスニペット
-        for (name, _) in snippet.meta.iter() {
-            if !name_list.contains(name) {
-                let name_set = all_name_list.get_mut(code_filepath_string);
-                name_set.unwrap().push(name.clone());
+// 他のファイルがnameを持っていたら、そのファイルのパスを返す (上書きされたものは持ち主ではない)
+fn find_owner(all_name_list: &KeyList, name: &str, code_filepath_string: &str) -> Option<String> {
+    let mut owners: Vec<&String> = all_name_list
+        .iter()
+        .filter(|(path, names)| {
+            *path != code_filepath_string && names.iter().any(|n| !n.shadowed && n == &name)
+        })
+        .map(|(path, _)| path)
+        .collect();
+    owners.sort(); // HashMapの順番によらないようにする
+    return owners.first().map(|path| path.to_string());
+}
+
+// 他のファイルのスニペットと名前が被っていないか調べて、naming.policyに従って解決する
+//   Error: エラーを返す
+//   Overwrite: 他のファイルから持ち主を移す (元の持ち主はshadowedとして記録し、こちらが消したら作り直す)
+//              前回上書きされたときから変わっていなければ、持ち主のスニペットを残す
+//   Namespace: こちらのスニペットの名前を <namespace>::<name> にする
+fn resolve_conflicts(
+    snippet: &mut BandledSnippet,
+    all_name_list: &mut KeyList,
+    code_filepath_string: &str,
+    naming: &Naming,
+) -> Result<Vec<Conflict>, MakeError> {
+    let mut conflicts = vec![];
+    let names: Vec<String> = snippet.meta.keys().cloned().collect();
+    for name in names {
+        let owner = match find_owner(all_name_list, &name, code_filepath_string) {
+            Some(owner) => owner,
+            None => continue,
+        };
+
+        let key = match naming.policy {
+            ConflictPolicy::Error => {
+                return Err(MakeError::NameConflict(Conflict {
+                    name: name.clone(),
+                    owner: owner,
+                    key: name,
+                    kept: false,
+                }));
             }
-        }
+            ConflictPolicy::Overwrite => {
+                // 走査し直すたびに持ち主が入れ替わらないように、変わっていなければ上書きしない
+                let hash = Some(hash_snippet(&snippet.meta[&name]));
+                let unchanged = all_name_list
+                    .get(code_filepath_string)
+                    .is_some_and(|names| {
+                        names
+                            .iter()
+                            .any(|n| n.shadowed && *n == name.as_str() && n.hash == hash)
+                    });
+                if unchanged {
+                    snippet.meta.remove(&name);
+                    snippet.shadowed.push(OwnedName {
+                        name: name.clone(),
+                        hash: hash,
+                        shadowed: true,
+                    });
+                    verbose!("\"{}\" is also defined in {}", name, owner);
+                    conflicts.push(Conflict {
+                        name: name.clone(),
+                        owner: owner,
+                        key: name,
+                        kept: true,
+                    });
+                    continue;
+                }
 
-        // 削除されたスニペット
-        if let Some(deleted_name_list) = deleted_name_list {
-            for name in name_list.iter() {
-                if deleted_name_list.contains(name) {
-                    // 削除
-                    let name_set = all_name_list.get_mut(code_filepath_string);
-                    name_set.unwrap().retain(|x| x.as_str() != name.as_str());
+                let mut taken = vec![];
+                for (path, names) in all_name_list.iter_mut() {
+                    if path != code_filepath_string {
+                        for owned in names.iter_mut().filter(|n| !n.shadowed && n.name == name) {
+                            taken.push(owned.clone());
+                            owned.shadowed = true;
+                        }
+                    }
+                }
+                let names = all_name_list
+                    .entry(code_filepath_string.to_string())
+                    .or_default();
+                names.retain(|n| !(n.shadowed && *n == name.as_str()));
+                if !names.iter().any(|n| *n == name.as_str()) {
+                    names.extend(taken.into_iter().take(1));
                 }
+                name.clone()
             }
-        }
-    } else {
-        // namelistが存在しない場合
-        let mut name_set: SnippetNames = SnippetNames::new();
-        for (name, _) in snippet.meta.iter() {
-            name_set.push(name.clone());
-        }
+            ConflictPolicy::Namespace => {
+                // 以前のnamelistで両方が持ち主になっていた場合は、こちらを外す
+                if let Some(names) = all_name_list.get_mut(code_filepath_string) {
+                    names.retain(|n| *n != name.as_str());
+                }
+                let key = format!("{}::{}", naming.namespace, name);
+                if let Some(value) = snippet.meta.remove(&name) {
+                    snippet.meta.insert(key.clone(), value);
+                }
+                key
+            }
+        };
 
-        all_name_list.insert(code_filepath_string.clone(), name_set);
+        verbose!("\"{}\" is also defined in {}", name, owner);
+        conflicts.push(Conflict {
+            name: name,
+            owner: owner,
+            key: key,
+            kept: false,
+        });
     }
-
-    return all_name_list;
+    return Ok(conflicts);
 }
 
-//// Snippet
-
 // 現存してるスニペット情報を取得する + コードの削除をチェック
 fn get_deleted_list(snippet: &BandledSnippet, name_list: &SnippetNames) -> HashSet<String> {
     let mut deleted_name_list: HashSet<String> = HashSet::new();
 
     // 現在編集しているファイルに関してリストを持ってくる
-    for existing in name_list.iter() {
-        let name = existing.clone();
+    for existing in name_list.iter().filter(|n| !n.shadowed) {
+        let name = existing.name.clone();
         if !snippet.meta.contains_key(&name) {
             // スニペットが消えてたら、deleted_name_listにぶちこむ
             deleted_name_list.insert(name);
@@ -230,295 +674,723 @@ fn get_deleted_list(snippet: &BandledSnippet, name_list: &SnippetNames) -> HashS
     return deleted_name_list;
 }
 
-// 対象ファイルをトリミングして、スニペット用のjsonの断片を作成
-fn gen_snippet_json(reader: impl Reader) -> Option<BandledSnippet> {
-    let mut code = String::new();
-    let mut is_empty: bool = true;
-    let trimmed_map = match trim_code(reader) {
-        Ok(t) => t,
-        Err(e) => match e {
-            TrimError::InvalidName => {
-                println!("error: invalid name");
-                return None;
-            }
+// 前回生成したスニペットと比べて、追加・更新・削除されたスニペットを返す
+fn get_changes(
+    snippet: &BandledSnippet,
+    name_list: &Option<&SnippetNames>,
+    deleted_name_list: &Option<HashSet<String>>,
+    ported: &SnippetMetaData,
+    edited: &[String],
+) -> Changes {
+    let mut changes = Changes {
+        edited: edited.to_vec(),
+        ..Default::default()
+    };
 
-            TrimError::InvalidPrefix => {
-                println!("error: invalid prefix");
-                return None;
-            }
+    for (name, value) in snippet.meta.iter() {
+        let existed =
+            name_list.is_some_and(|names| names.iter().any(|n| !n.shadowed && &n.name == name));
+        if edited.contains(name) {
+            continue;
+        } else if !existed {
+            changes.added.push(name.clone());
+        } else if ported.get(name) != Some(value) {
+            changes.updated.push(name.clone());
+            changes
+                .fields
+                .insert(name.clone(), get_changed_fields(ported.get(name), value));
+        }
+    }
 
-            TrimError::InvalidDescription => {
-                println!("error: invalid description");
-                return None;
-            }
-            TrimError::InvalidMeta => {
-                println!("error: invalid form");
-                return None;
-            }
-        },
+    if let Some(deleted_name_list) = deleted_name_list {
+        changes.removed = deleted_name_list
+            .iter()
+            .filter(|name| !edited.contains(name))
+            .cloned()
+            .collect();
+    }
+
+    changes.added.sort();
+    changes.updated.sort();
+    changes.removed.sort();
+    changes.edited.sort();
+    return changes;
+}
+
+// スニペットのどの項目が変わったか
+fn get_changed_fields(old: Option<&Snippet>, new: &Snippet) -> Vec<String> {
+    let old = match old {
+        Some(old) => old,
+        None => return vec![], // 生成済みのjsonから消えていた
     };
 
-    // スニペット用のjsonを生成
-    for (name, trimmed) in trimmed_map.iter() {
-        let name = name.clone();
-        let snippet = trimmed.clone();
-        let mut meta = HashMap::new();
-        meta.insert(name, snippet);
-
-        if let Ok(json) = serde_json::to_string(&meta) {
-            if let Some(json) = json.get(1..json.len() - 1) {
-                code.push_str(&json);
-                code.push(','); // 常にコンマ打ってOK
-                is_empty = false;
-            }
-        }
+    let mut fields = vec![];
+    if old.prefix != new.prefix {
+        fields.push(String::from("prefix"));
+    }
+    if old.body != new.body {
+        fields.push(String::from("body"));
+    }
+    if old.description != new.description {
+        fields.push(String::from("description"));
+    }
+    if old.scope != new.scope {
+        fields.push(String::from("scope"));
+    }
+    if old.is_file_template != new.is_file_template {
+        fields.push(String::from("isFileTemplate"));
+    }
+    if old.tags != new.tags {
+        fields.push(String::from("tags"));
+    }
+    if old.author != new.author {
+        fields.push(String::from("author"));
+    }
+    if old.extra != new.extra {
+        fields.push(String::from("extra"));
     }
+    return fields;
+}
 
-    // 何らかの理由でコードが空の場合はNoneを返す
-    if is_empty {
-        return None;
+// 対象ファイルをトリミングして、スニペット用のjsonの断片を作成
+fn gen_snippet_json(reader: impl Reader) -> Result<BandledSnippet, MakeError> {
+    let trimmed_map = match trim_code(reader) {
+        Ok(t) => t,
+        Err(e) => {
+            verbose!("error: {}", e); // 呼び出し元でstatusに記録・表示する
+            return Err(MakeError::InvalidSnippet(e));
+        }
+    };
+
+    // 何らかの理由でスニペットが空の場合はErrを返す
+    if trimmed_map.is_empty() {
+        return Err(MakeError::NotFound);
     }
 
-    return Some(BandledSnippet {
+    return Ok(BandledSnippet {
         meta: trimmed_map,
-        code: code,
+        shadowed: SnippetNames::new(),
     });
 }
 
+// gen_alljsonの結果
+struct AllJson {
+    json: String,
+    ported: SnippetMetaData, // 書き換える前に生成済みだったスニペット
+    owned: SnippetNames,     // 対象ファイルのスニペットとして書き込んだもの (namelistに記録する)
+    edited: Vec<String>,     // 手で書かれた・編集されていたので書き換えなかったもの
+}
+
 // スニペットの全文を作成
+// PortSnippetが書き込んだスニペット(namelistに載っているもの)だけを、ファイルのどこにあっても書き換える
+// 書き込んだときから内容が変わっている(VSCode上で編集された)ものは書き換えずに残す
+// 新しいスニペットはタグで囲まれた部分(生成部分)に追加する
 fn gen_alljson(
-    reader: impl Reader,
+    mut reader: impl Reader,
+    all_name_list: &KeyList,
+    name_list: Option<&SnippetNames>,
     deleted_name_list: &Option<HashSet<String>>,
     bandled: &BandledSnippet,
-) -> Option<String> {
-    let mut current_step = SearchStep::StartTag;
-    let mut allcode = String::new();
-    let mut found_tag: bool = false;
+) -> Option<AllJson> {
+    let document = match jsonc::Document::parse(&reader.all()) {
+        Ok(document) => document,
+        Err(e) => {
+            println!("cannot parse the snippet json: {}", e);
+            return None;
+        }
+    };
 
-    let mut already_ported_json = String::from("{");
-    for line in reader.lines() {
-        let mut line = line;
-        line.push('\n');
+    let mut alljson = AllJson {
+        json: String::new(),
+        ported: SnippetMetaData::new(),
+        owned: SnippetNames::new(),
+        edited: vec![],
+    };
 
-        // タグが存在してるなら、jsonを解析する
-        // 存在してないならcodeをそのまま貼り付ける
-        match current_step {
-            SearchStep::StartTag => {
-                if line.contains(GEN_START_TAG) {
-                    current_step = SearchStep::EndTag;
-                    found_tag = true;
-                } else {
-                    allcode.push_str(&line);
-                }
+    // スニペットのjsonがまだない場合は、オブジェクトごと作る
+    let object = match &document.object {
+        Some(object) => object,
+        None => {
+            let mut allcode = String::new();
+            if !document.text.is_empty() && !document.text.ends_with('\n') {
+                allcode.push('\n');
             }
-            SearchStep::EndTag => {
-                if line.contains(GEN_END_TAG) {
-                    // タグが存在する場合
-                    current_step = SearchStep::None;
-                    let mut found_bracket = false;
-                    let mut bracket_index = 0;
-
-                    // already_ported_jsonの末尾の"}"を探す
-                    for c in already_ported_json.as_str().chars().rev() {
-                        match c {
-                            '}' => {
-                                found_bracket = true;
-                                break;
-                            }
-                            _ => {
-                                bracket_index += 1;
-                            }
-                        };
-                    }
-
-                    if !found_bracket {
-                        return None;
-                    }
-
-                    for _ in 0..bracket_index {
-                        already_ported_json.pop();
-                    }
+            let members = format_members(bandled.meta.iter(), false)?;
+            allcode.push_str(&format!("{{\n{}\n}}\n", format_generated(&members)));
+            alljson.json = document.apply(vec![jsonc::Edit {
+                span: jsonc::Span {
+                    start: document.text.len(),
+                    end: document.text.len(),
+                },
+                text: allcode,
+            }]);
+            alljson.owned = get_owned_names(bandled.meta.iter());
+            return Some(alljson);
+        }
+    };
 
-                    already_ported_json.push_str("}"); // serde_jsonを通すために{}で囲う
-
-                    let mut success = false;
-                    if let Ok(already_ported) =
-                        serde_json::from_str::<SnippetMetaData>(&already_ported_json)
-                    {
-                        let mut new_snippets = SnippetMetaData::new();
-                        // println!("delete!: {:?}", deleted_name_list);
-                        for (name, existing_snippet) in already_ported.iter() {
-                            if let Some(deleted_name_list) = deleted_name_list {
-                                if deleted_name_list.contains(name) {
-                                    // 削除対象は弾く
-                                    continue;
-                                }
-                            }
-                            // 詰め替える
-                            new_snippets.insert(name.clone(), existing_snippet.clone());
-                        }
+    let region = find_generated_region(&document, object)?;
+    let hashes = get_owned_hashes(all_name_list);
+    let mut editor = jsonc::ObjectEditor::new(&document, object);
+    let mut placed: HashSet<String> = HashSet::new();
+
+    for (index, member) in object.members.iter().enumerate() {
+        let name = &member.key;
+        let new = bandled.meta.get(name);
+        let owned_here =
+            name_list.is_some_and(|names| names.iter().any(|n| !n.shadowed && &n.name == name));
+        let deleted = owned_here
+            && deleted_name_list
+                .as_ref()
+                .is_some_and(|names| names.contains(name));
+        if placed.contains(name) || (new.is_none() && !deleted) {
+            continue; // 他のファイルのスニペットや手書きのスニペット、重複したキー
+        }
+        placed.insert(name.clone());
 
-                        for (name, value) in bandled.meta.iter() {
-                            new_snippets.insert(name.clone(), value.clone());
-                        }
+        let value = document.slice(&member.value);
+        if owned_here {
+            if let Ok(snippet) = serde_json::from_str::<Snippet>(value) {
+                alljson.ported.insert(name.clone(), snippet);
+            }
+        }
 
-                        if let Ok(code) = serde_json::to_string_pretty(&new_snippets) {
-                            let mut code = code
-                                .chars()
-                                .skip(1) // 先頭の "{" を取り除く
-                                .take((code.chars().count() - 1) - 1) // 末尾の "}" を取り除く
-                                .collect::<String>();
-
-                            code.push(',');
-                            let formated_code = add_tag(&code);
-                            allcode.push_str(&formated_code); // stringに直して書き込む
-                            success = true;
-                        } else {
-                            success = false;
-                        }
-                    }
+        // どのファイルのものでもない = 手書きのスニペット
+        // 書き込んだときから内容が変わっている = VSCode上で編集された
+        let recorded_hash = match hashes.get(name) {
+            Some(hash) => hash,
+            None => {
+                verbose!("\"{}\" is written by hand; not overwriting it.", name);
+                alljson.edited.push(name.clone());
+                continue;
+            }
+        };
+        if recorded_hash.is_some() && hash_json(value) != *recorded_hash {
+            verbose!("\"{}\" was edited by hand; not overwriting it.", name);
+            alljson.edited.push(name.clone());
+            if new.is_some() {
+                // 所有したままにして、手で消されたら作り直す
+                alljson.owned.push(OwnedName {
+                    name: name.clone(),
+                    hash: recorded_hash.clone(),
+                    shadowed: false,
+                });
+            }
+            continue;
+        }
 
-                    if !success {
-                        println!("failed to perse.");
-                        let formated_code = add_tag(&bandled.code);
-                        allcode.push_str(&formated_code); // パースできなかったら、そのまま書き込む
-                    }
-                } else {
-                    // タグが存在しない場合
-                    already_ported_json.push_str(&line);
+        match new {
+            Some(new) => {
+                let new_hash = hash_snippet(new);
+                if hash_json(value).as_ref() != Some(&new_hash) {
+                    let indent = get_indent(&document.text, member.span.start);
+                    editor.replace_value(index, format_value(new, &indent)?);
                 }
+                alljson.owned.push(OwnedName {
+                    name: name.clone(),
+                    hash: Some(new_hash),
+                    shadowed: false,
+                });
             }
-            _ => {
-                allcode.push_str(&line);
-            }
+            None => editor.remove(index),
         }
     }
 
-    if found_tag {
-        return Some(allcode);
-    } else {
-        // タグが存在しなかった場合
-        let mut bracket_index: usize = 0;
-        let mut found_bracket = false;
-        for c in allcode.as_str().chars() {
-            match c {
-                '{' => {
-                    found_bracket = true;
-                    break;
-                }
-                _ => {
-                    bracket_index += 1;
-                }
-            };
+    // まだjsonにないスニペットは生成部分に追加する
+    let missing: Vec<(&String, &Snippet)> = bandled
+        .meta
+        .iter()
+        .filter(|(name, _)| !placed.contains(*name))
+        .collect();
+    if !missing.is_empty() {
+        alljson
+            .owned
+            .extend(get_owned_names(missing.iter().cloned()));
+        match region {
+            Some((_, end_tag)) => {
+                let trailing_comma = editor.has_members_after(end_tag.start);
+                let members = format_members(missing.into_iter(), trailing_comma)?;
+                editor.insert(end_tag.start, format!("{}\n", members));
+            }
+            None => {
+                let position = object.open + 1;
+                let trailing_comma = editor.has_members_after(position);
+                let members = format_members(missing.into_iter(), trailing_comma)?;
+                let rest = &document.text[position..];
+                let newline = if rest.starts_with('\n') || rest.starts_with("\r\n") {
+                    ""
+                } else {
+                    "\n"
+                };
+                editor.insert(
+                    position,
+                    format!("\n{}{}", format_generated(&members), newline),
+                );
+            }
         }
-        bracket_index += 1;
-        println!("{}", bracket_index);
+    }
+
+    alljson.owned.sort_by(|a, b| a.name.cmp(&b.name));
+    alljson.json = editor.apply();
+    return Some(alljson);
+}
 
-        if !found_bracket {
-            bracket_index = 0;
+// 生成部分のタグ (GEN_START_TAGのコメントとGEN_END_TAGのコメント) を探す
+// 片方のタグしかない場合は、どこに追加すればよいか分からないのでNoneを返す
+fn find_generated_region(
+    document: &jsonc::Document,
+    object: &jsonc::Object,
+) -> Option<Option<(jsonc::Span, jsonc::Span)>> {
+    let start = object
+        .comments
+        .iter()
+        .find(|comment| document.slice(comment).contains(GEN_START_TAG));
+    let end = object
+        .comments
+        .iter()
+        .find(|comment| document.slice(comment).contains(GEN_END_TAG));
+
+    return match (start, end) {
+        (Some(start), Some(end)) if start.end <= end.start => Some(Some((*start, *end))),
+        (None, None) => Some(None),
+        _ => {
+            println!("cannot find the generated part: a tag is missing or broken.");
+            None
         }
+    };
+}
 
-        if let (Some(first), Some(second)) = (
-            allcode.get(0..bracket_index),
-            allcode.get(bracket_index..allcode.len()),
-        ) {
-            // first: [0,i), second: [i,len)
-            let mut allcode = String::from(first.clone());
-            let formated_code = add_tag(&bandled.code);
+// 全ファイルのnamelistから、スニペットの名前と書き込んだときのハッシュを返す
+fn get_owned_hashes(all_name_list: &KeyList) -> HashMap<String, Option<String>> {
+    let mut hashes = HashMap::new();
+    for names in all_name_list.values() {
+        for owned in names.iter().filter(|n| !n.shadowed) {
+            hashes.insert(owned.name.clone(), owned.hash.clone());
+        }
+    }
+    return hashes;
+}
 
-            if !found_bracket {
-                allcode.push_str("{");
-            }
-            allcode.push_str("\n");
-            allcode.push_str(&formated_code);
-            allcode.push_str(&second);
+// namesのどれかを、持ち主ではないが定義している他のファイルのパスを返す
+fn find_shadowing(
+    all_name_list: &KeyList,
+    names: &HashSet<String>,
+    code_filepath_string: &str,
+) -> Vec<String> {
+    let mut paths: Vec<String> = all_name_list
+        .iter()
+        .filter(|(path, list)| {
+            *path != code_filepath_string
+                && list.iter().any(|n| n.shadowed && names.contains(&n.name))
+        })
+        .map(|(path, _)| path.clone())
+        .collect();
+    paths.sort();
+    return paths;
+}
 
-            if !found_bracket {
-                allcode.push_str("\n}");
-            }
+fn get_owned_names<'a>(snippets: impl Iterator<Item = (&'a String, &'a Snippet)>) -> SnippetNames {
+    return snippets
+        .map(|(name, snippet)| OwnedName {
+            name: name.clone(),
+            hash: Some(hash_snippet(snippet)),
+            shadowed: false,
+        })
+        .collect();
+}
 
-            println!("{}", allcode);
-            return Some(allcode);
-        }
+// 内容のハッシュ (FNV-1a)
+// serde_json::Valueを通すので、キーの順番や空白の違いは無視される
+fn hash_value(value: &serde_json::Value) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in value.to_string().bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
     }
+    return format!("{:016x}", hash);
+}
 
-    return None;
+// JSONとして読めない(コメントが書き足されたなど)場合はNone
+fn hash_json(text: &str) -> Option<String> {
+    let value = serde_json::from_str::<serde_json::Value>(text).ok()?;
+    return Some(hash_value(&value));
 }
 
-// 対象のコードから、スニペット部分を取り出す
-fn trim_code(reader: impl Reader) -> Result<SnippetMetaData, TrimError> {
-    let mut current_step = SearchStep::StartTag;
-    let mut meta = SnippetMetaData::new();
-    let mut code = String::new();
-    let mut target = Snippet::new();
-    let mut target_name = String::new();
+fn hash_snippet(snippet: &Snippet) -> String {
+    return hash_value(&serde_json::to_value(snippet).unwrap_or_default());
+}
 
-    for line in reader.lines() {
-        let mut line = line;
-        line.push_str("\n");
+// posの行のインデント
+fn get_indent(text: &str, pos: usize) -> String {
+    let line_start = text[..pos].rfind('\n').map_or(0, |i| i + 1);
+    return text[line_start..pos]
+        .chars()
+        .take_while(|c| *c == ' ' || *c == '\t')
+        .collect();
+}
 
-        // メタデータを探索
-        match &current_step {
-            SearchStep::StartTag => {
-                if line.contains(START_TAG) {
-                    current_step = SearchStep::Meta;
-                }
-            }
-            SearchStep::Meta => {
-                if let Some(result) = regex_search(DESC_RE, &line) {
-                    // description
-                    if result.len() != 2 {
-                        return Err(TrimError::InvalidDescription);
-                    }
+// 値を整形する (2行目以降はindentに揃える)
+fn format_value(snippet: &Snippet, indent: &str) -> Option<String> {
+    let json = serde_json::to_string_pretty(snippet).ok()?;
+    return Some(json.replace('\n', &format!("\n{}", indent)));
+}
 
-                    target.description = result.get(1).unwrap().to_string(); // 1の方がキャプチャされた文字列
+// "name": {...} をコンマ区切りで並べる
+// 後ろに他のスニペットが続く場合だけ、最後にコンマを打つ
+fn format_members<'a>(
+    snippets: impl Iterator<Item = (&'a String, &'a Snippet)>,
+    trailing_comma: bool,
+) -> Option<String> {
+    let mut members = vec![];
+    for (name, snippet) in snippets {
+        let key = serde_json::to_string(name).ok()?;
+        members.push(format!("  {}: {}", key, format_value(snippet, "  ")?));
+    }
+    let mut text = members.join(",\n");
+    if trailing_comma && !text.is_empty() {
+        text.push(',');
+    }
+    return Some(text);
+}
+
+// タグで囲んだ生成部分
+fn format_generated(members: &str) -> String {
+    let mut code = format_tag(GEN_START_TAG);
+    code.push('\n');
+    if !members.is_empty() {
+        code.push_str(members);
+        code.push('\n');
+    }
+    code.push_str(&format_tag(GEN_END_TAG));
+    return code;
+}
+
+// 切り出したスニペットを書き込み先に合わせる
+fn apply_style(meta: &mut SnippetMetaData, style: &OutputStyle) {
+    for snippet in meta.values_mut() {
+        // scopeが書かれていないスニペットは、対象ファイルの言語で使えるようにする
+        if let Some(scope) = &style.scope {
+            snippet.scope.get_or_insert_with(|| scope.clone());
+        }
+        // プレースホルダをタブストップにして、それ以外の$をエスケープする
+        snippet.body = Body::Text(placeholder::convert(
+            &snippet.body.text(),
+            &style.placeholder_marker,
+        ));
+        if style.body_as_lines {
+            snippet.body = snippet.body.to_lines();
+        }
+    }
+}
+
+// "c, cpp" → "c,cpp"
+fn normalize_scope(scope: &str) -> String {
+    return scope
+        .split(',')
+        .map(|lang| lang.trim())
+        .filter(|lang| !lang.is_empty())
+        .collect::<Vec<&str>>()
+        .join(",");
+}
+
+///// Header
+
+// #PORT# の後のヘッダを読み込む (trim_code / lint 共通)
+// #PORT_BODY# がなければ、ヘッダの行ではない最初の行から本文になる
+// name と prefix が揃うまでは、ヘッダの行ではない行を読み飛ばす (以前の書き方との互換)
+struct HeaderParser {
+    snippet: Snippet,
+    name: Option<String>,
+    has_prefix: bool,          // 空のprefixも、書かれていれば見つかったものとする
+    continued: Option<String>, // 継続行を受け付けるキー
+    blanks: String,            // 本文の先頭になるかもしれない空行
+}
+
+enum HeaderEvent {
+    Meta(String, usize), // キーとその列
+    Skipped,             // 継続行・空行・読み飛ばした行
+    BodyTag,             // #PORT_BODY# (次の行から本文)
+    Body(String),        // この行から本文 (直前の空行も本文に含める)
+}
+
+struct HeaderError {
+    key: String,
+    column: usize,
+    width: usize, // 問題のある箇所の幅 (文字単位)
+    message: String,
+}
+
+impl HeaderParser {
+    fn new() -> Self {
+        return HeaderParser {
+            snippet: Snippet::new(),
+            name: None,
+            has_prefix: false,
+            continued: None,
+            blanks: String::new(),
+        };
+    }
+
+    fn is_ready(&self) -> bool {
+        return self.name.is_some() && self.has_prefix;
+    }
+
+    fn read(&mut self, line: &str) -> Result<HeaderEvent, HeaderError> {
+        match header::parse_line(line) {
+            Ok(Line::BodyTag) => return Ok(HeaderEvent::BodyTag),
+            Ok(Line::Blank) => {
+                self.continued = None;
+                if self.is_ready() {
+                    self.blanks.push_str(line);
+                }
+                return Ok(HeaderEvent::Skipped);
+            }
+            Ok(Line::Continuation(text)) => {
+                if let Some(key) = self.continued.clone() {
+                    self.continue_value(&key, &text);
+                    return Ok(HeaderEvent::Skipped);
+                }
+            }
+            // 知らないキーは、引用符で囲んだ値などがあるときだけヘッダの行とする
+            // (// TODO: ... のようなコメントや、public: のようなコードのラベルかもしれない)
+            Ok(Line::Meta { key, column, value }) if is_header_value(&key, &value) => {
+                self.blanks.clear();
+                self.continued = None;
+                self.apply(&key, column, value)?;
+                return Ok(HeaderEvent::Meta(key, column));
+            }
+            Ok(_) => {}
+            Err(e) => {
+                // 知らないキーや、続きを受け付けていない文字列だけの行は本文のコードとして扱う
+                let key = if e.key.is_empty() {
+                    self.continued.clone()
                 } else {
-                    // すでにnameとprefixが見つかってたなら、EndTagを探すように
-                    if !target_name.is_empty() && !target.prefix.is_empty() {
-                        current_step = SearchStep::EndTag;
+                    Some(e.key).filter(|key| META_KEYS.contains(&key.as_str()))
+                };
+                if let Some(key) = key {
+                    // 読めなかったname / prefixも、書かれていたものとして扱う
+                    match key.as_str() {
+                        "name" => {
+                            self.name.get_or_insert_with(String::new);
+                        }
+                        "prefix" => self.has_prefix = true,
+                        _ => {}
                     }
+                    self.continued = None;
+                    return Err(HeaderError {
+                        key: key,
+                        column: e.column,
+                        width: e.width,
+                        message: e.message,
+                    });
                 }
+            }
+        }
 
-                if let Some(result) = regex_search(NAME_RE, &line) {
-                    // name
-                    if result.len() != 2 {
-                        return Err(TrimError::InvalidName);
-                    }
+        // ヘッダの行ではない
+        if !self.is_ready() {
+            return Ok(HeaderEvent::Skipped);
+        }
+        return Ok(HeaderEvent::Body(std::mem::take(&mut self.blanks)));
+    }
 
-                    target_name = result.get(1).unwrap().to_string();
-                }
+    // キーの値をスニペットに反映する
+    // 問題があっても、書かれていたものとして扱えるところまでは反映する
+    fn apply(&mut self, key: &str, column: usize, value: Value) -> Result<(), HeaderError> {
+        let error = |message: String| HeaderError {
+            key: key.to_string(),
+            column: column,
+            width: key.chars().count(),
+            message: message,
+        };
 
-                if let Some(result) = regex_search(PREFIX_RE, &line) {
-                    // prefix
-                    if result.len() != 2 {
-                        return Err(TrimError::InvalidPrefix);
+        match (key, value) {
+            ("name", Value::Text(name) | Value::Bare(name)) => {
+                let is_empty = name.is_empty();
+                self.name = Some(name);
+                if is_empty {
+                    return Err(error(String::from("`name` is empty")));
+                }
+            }
+            ("prefix", Value::Text(prefix) | Value::Bare(prefix)) => {
+                self.has_prefix = true;
+                if prefix.is_empty() {
+                    return Err(error(String::from("`prefix` is empty")));
+                }
+                self.snippet.prefix.push(prefix);
+            }
+            ("prefix", Value::List(prefixes)) => {
+                self.has_prefix = true;
+                let prefixes: Vec<String> =
+                    prefixes.into_iter().filter(|p| !p.is_empty()).collect();
+                if prefixes.is_empty() {
+                    return Err(error(String::from("`prefix` list is empty")));
+                }
+                for prefix in prefixes {
+                    self.snippet.prefix.push(prefix);
+                }
+            }
+            ("description", Value::Text(description) | Value::Bare(description)) => {
+                self.snippet.description = description;
+                self.continued = Some(key.to_string());
+            }
+            ("description", Value::Empty) => {
+                self.snippet.description = String::new();
+                self.continued = Some(key.to_string());
+            }
+            ("scope", Value::Text(scope) | Value::Bare(scope)) => {
+                self.snippet.scope = Some(normalize_scope(&scope));
+            }
+            ("isFileTemplate", Value::Bool(is_file_template)) => {
+                self.snippet.is_file_template = is_file_template;
+            }
+            ("tags", Value::Text(tag) | Value::Bare(tag)) => self.push_tags(vec![tag]),
+            ("tags", Value::List(tags)) => self.push_tags(tags),
+            ("author", Value::Text(author) | Value::Bare(author)) => {
+                self.snippet.author = Some(author);
+                self.continued = Some(key.to_string());
+            }
+            ("author", Value::Empty) => {
+                self.snippet.author = Some(String::new());
+                self.continued = Some(key.to_string());
+            }
+            (key, _) if META_KEYS.contains(&key) => {
+                let expected = match key {
+                    "prefix" | "tags" => "a string or a list of strings",
+                    "isFileTemplate" => "`true` or `false`",
+                    _ => "a string",
+                };
+                return Err(error(format!("`{}` must be {}", key, expected)));
+            }
+            (key, value) => {
+                let value = match value {
+                    Value::Text(text) | Value::Bare(text) => {
+                        self.continued = Some(key.to_string());
+                        serde_json::Value::from(text)
                     }
+                    Value::List(list) => serde_json::Value::from(list),
+                    Value::Bool(flag) => serde_json::Value::from(flag),
+                    Value::Empty => serde_json::Value::from(""),
+                };
+                self.snippet.extra.insert(key.to_string(), value);
+            }
+        }
+        return Ok(());
+    }
 
-                    target.prefix = result.get(1).unwrap().to_string();
-                }
+    fn push_tags(&mut self, tags: Vec<String>) {
+        for tag in tags {
+            if !tag.is_empty() && !self.snippet.tags.contains(&tag) {
+                self.snippet.tags.push(tag);
             }
-            _ => {}
         }
+    }
 
-        // コードを記録
+    // 継続行をkeyの文字列に繋げる (空白1つで区切る)
+    fn continue_value(&mut self, key: &str, text: &str) {
+        let value = match key {
+            "description" => &mut self.snippet.description,
+            "author" => self.snippet.author.get_or_insert_with(String::new),
+            _ => match self.snippet.extra.get_mut(key) {
+                Some(serde_json::Value::String(value)) => value,
+                _ => return,
+            },
+        };
+        if !value.is_empty() && !text.is_empty() {
+            value.push(' ');
+        }
+        value.push_str(text);
+    }
+}
+
+// ヘッダの行として読む値か (知らないキーは、引用符のない値や空の値を受け付けない)
+fn is_header_value(key: &str, value: &Value) -> bool {
+    if META_KEYS.contains(&key) {
+        return true;
+    }
+    return !matches!(value, Value::Empty | Value::Bare(_));
+}
+
+// ヘッダの問題をtrim_codeのエラーにする
+fn to_trim_error(e: HeaderError) -> TrimError {
+    return match e.key.as_str() {
+        "name" => TrimError::InvalidName,
+        "prefix" => TrimError::InvalidPrefix,
+        "description" => TrimError::InvalidDescription,
+        "scope" => TrimError::InvalidScope,
+        _ => TrimError::InvalidValue(e.key),
+    };
+}
+
+// 対象のコードから、スニペット部分を取り出す
+fn trim_code(reader: impl Reader) -> Result<SnippetMetaData, TrimError> {
+    let mut current_step = SearchStep::StartTag;
+    let mut meta = SnippetMetaData::new();
+    let mut code = String::new();
+    let mut header = HeaderParser::new();
+
+    for line in reader.lines() {
+        let mut line = line;
+        line.push_str("\n");
+
+        // メタデータを探索
         match &current_step {
-            SearchStep::StartTag => {}
+            SearchStep::StartTag => {
+                if line.contains(START_TAG) {
+                    current_step = SearchStep::Meta;
+                }
+            }
+            SearchStep::Meta => {
+                if line.contains(END_TAG) && !header.is_ready() {
+                    // name / prefix が足りないまま閉じられた
+                    return Err(TrimError::InvalidMeta);
+                }
+
+                match header.read(&line).map_err(to_trim_error)? {
+                    HeaderEvent::BodyTag => {
+                        if !header.is_ready() {
+                            return Err(TrimError::InvalidMeta);
+                        }
+
+                        // #PORT_BODY# の行は本文に含めない
+                        current_step = SearchStep::EndTag;
+                        continue;
+                    }
+                    HeaderEvent::Body(blanks) => {
+                        code = blanks;
+                        current_step = SearchStep::EndTag;
+                    }
+                    _ => {}
+                }
+            }
             SearchStep::EndTag => {
-                if line.contains(END_TAG) {
-                    // 詰める
-                    target.body = code;
-                    meta.insert(target_name.clone(), target);
+                if line.contains(header::BODY_TAG) {
+                    // 本文が始まった後の #PORT_BODY#
+                    return Err(TrimError::InvalidMeta);
+                }
+            }
+        }
 
-                    // 諸々初期化
-                    code = String::new();
-                    current_step = SearchStep::StartTag;
-                    target = Snippet::new();
-                } else {
-                    code.push_str(&line);
+        // コードを記録
+        if current_step == SearchStep::EndTag {
+            if line.contains(END_TAG) {
+                // 詰める
+                let finished = std::mem::replace(&mut header, HeaderParser::new());
+                let name = finished.name.unwrap_or_default();
+                if meta.contains_key(&name) {
+                    // 後のもので上書きせず、lintと同じくエラーにする
+                    return Err(TrimError::DuplicateName(name));
                 }
+                let mut target = finished.snippet;
+                target.body = Body::Text(std::mem::take(&mut code));
+                meta.insert(name, target);
+
+                // 諸々初期化
+                current_step = SearchStep::StartTag;
+            } else {
+                code.push_str(&line);
             }
-            _ => {}
-        };
+        }
     }
 
     if current_step != SearchStep::StartTag {
@@ -530,23 +1402,330 @@ fn trim_code(reader: impl Reader) -> Result<SnippetMetaData, TrimError> {
     return Ok(meta);
 }
 
-///// Util
+///// Lint
+
+// 検査中の #PORT# ブロック
+struct LintBlock {
+    line: usize,
+    column: usize,
+    source: String,
+    name: Option<LintName>,
+    header: HeaderParser,
+}
+
+// nameが書かれていた場所
+struct LintName {
+    name: String,
+    line: usize,
+    column: usize,
+    source: String,
+}
+
+// trim_codeと同じ規則でメタタグを検査して、見つかった問題をすべて返す
+// trim_codeは最初の問題で止まるが、こちらは最後まで読む
+pub fn lint<R: Reader>(reader: R, path: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut current_step = SearchStep::StartTag;
+    let mut block: Option<LintBlock> = None;
+    let mut defined: HashMap<String, usize> = HashMap::new(); // name, line
+
+    let diagnose = |severity: Severity,
+                    line: usize,
+                    column: usize,
+                    tag: &str,
+                    message: String,
+                    source: &str| {
+        return Diagnostic {
+            severity: severity,
+            path: path.to_string(),
+            line: line,
+            column: column,
+            tag: tag.to_string(),
+            message: message,
+            width: tag.chars().count(),
+            source: source.to_string(),
+        };
+    };
+
+    for (index, line) in reader.lines().iter().enumerate() {
+        let line_number = index + 1;
+
+        match current_step {
+            SearchStep::StartTag => {
+                if let Some(column) = find_column(line, START_TAG) {
+                    block = Some(LintBlock {
+                        line: line_number,
+                        column: column,
+                        source: line.clone(),
+                        name: None,
+                        header: HeaderParser::new(),
+                    });
+                    current_step = SearchStep::Meta;
+                } else if let Some(column) = find_column(line, END_TAG) {
+                    diagnostics.push(diagnose(
+                        Severity::Warning,
+                        line_number,
+                        column,
+                        END_TAG,
+                        format!("`{}` without `{}`", END_TAG, START_TAG),
+                        line,
+                    ));
+                }
+                continue;
+            }
+            SearchStep::Meta => {
+                let target = block.as_mut().unwrap();
 
-fn regex_search(re: &str, text: &String) -> Option<Vec<String>> {
-    let re = Regex::new(re).unwrap();
-    if let Some(caps) = re.captures(&text) {
-        let mut captured: Vec<String> = vec![];
-        for i in 0..caps.len() {
-            let captured_str = caps.get(i).unwrap().as_str();
-            captured.push(String::from(captured_str));
+                if find_column(line, END_TAG).is_some() && !target.header.is_ready() {
+                    // name / prefix が足りないまま閉じられた
+                    diagnostics.extend(lint_missing_meta(target, path));
+                    current_step = SearchStep::StartTag;
+                    block = None;
+                    continue;
+                }
+
+                // trim_codeと同じ規則でヘッダを読む (空のname / prefixは、報告して見つかったものとして扱う)
+                match target.header.read(line) {
+                    Ok(HeaderEvent::Meta(key, column)) => {
+                        if key == "name" {
+                            target.name = Some(LintName {
+                                name: target.header.name.clone().unwrap_or_default(),
+                                line: line_number,
+                                column: column,
+                                source: line.clone(),
+                            });
+                        }
+
+                        // 知らないキーはextraに入れるが、既知のキーの書き間違いらしければ知らせる
+                        if let Some(known) = find_similar_key(&key) {
+                            diagnostics.push(diagnose(
+                                Severity::Warning,
+                                line_number,
+                                column,
+                                &key,
+                                format!(
+                                    "unknown meta key `{}` is kept in `extra`; did you mean `{}`?",
+                                    key, known
+                                ),
+                                line,
+                            ));
+                        }
+                    }
+                    Ok(HeaderEvent::BodyTag) => {
+                        if !target.header.is_ready() {
+                            diagnostics.extend(lint_missing_meta(target, path));
+                        }
+                        current_step = SearchStep::EndTag;
+                        continue;
+                    }
+                    Ok(HeaderEvent::Body(_)) => current_step = SearchStep::EndTag,
+                    Ok(HeaderEvent::Skipped) => {}
+                    Err(e) => {
+                        // 値の中の問題は、その箇所の幅だけ印を付ける
+                        let mut diagnostic = diagnose(
+                            Severity::Error,
+                            line_number,
+                            e.column,
+                            &e.key,
+                            e.message,
+                            line,
+                        );
+                        diagnostic.width = e.width;
+                        diagnostics.push(diagnostic);
+                    }
+                }
+
+                if current_step == SearchStep::Meta {
+                    if let Some(column) = find_column(line, START_TAG) {
+                        diagnostics.push(diagnose(
+                            Severity::Error,
+                            line_number,
+                            column,
+                            START_TAG,
+                            format!(
+                                "`{}` inside the block that starts at line {}",
+                                START_TAG, target.line
+                            ),
+                            line,
+                        ));
+                    }
+                    continue;
+                }
+            }
+            _ => {}
+        }
+
+        // コード部分
+        if current_step == SearchStep::EndTag {
+            let target = block.as_ref().unwrap();
+            if find_column(line, END_TAG).is_some() {
+                // 同じファイル内で名前が重複していないか
+                if let Some(name) = target.name.as_ref().filter(|name| !name.name.is_empty()) {
+                    if let Some(first_line) = defined.get(&name.name) {
+                        diagnostics.push(diagnose(
+                            Severity::Error,
+                            name.line,
+                            name.column,
+                            "name",
+                            format!(
+                                "duplicate name `{}` (first defined at line {})",
+                                name.name, first_line
+                            ),
+                            &name.source,
+                        ));
+                    } else {
+                        defined.insert(name.name.clone(), name.line);
+                    }
+                }
+                current_step = SearchStep::StartTag;
+                block = None;
+            } else if let Some(column) = find_column(line, START_TAG) {
+                diagnostics.push(diagnose(
+                    Severity::Error,
+                    line_number,
+                    column,
+                    START_TAG,
+                    format!(
+                        "`{}` inside the block that starts at line {}; is `{}` missing?",
+                        START_TAG, target.line, END_TAG
+                    ),
+                    line,
+                ));
+            } else if let Some(column) = find_column(line, header::BODY_TAG) {
+                diagnostics.push(diagnose(
+                    Severity::Error,
+                    line_number,
+                    column,
+                    header::BODY_TAG,
+                    format!(
+                        "`{}` after the snippet body started; put it right after the meta tags",
+                        header::BODY_TAG
+                    ),
+                    line,
+                ));
+            }
+        }
+    }
+
+    // タグが閉じていない
+    if let Some(target) = block {
+        diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            path: path.to_string(),
+            line: target.line,
+            column: target.column,
+            tag: String::from(START_TAG),
+            message: format!("`{}` block is not closed", START_TAG),
+            width: START_TAG.chars().count(),
+            source: target.source.clone(),
+        });
+        if current_step == SearchStep::Meta {
+            diagnostics.extend(lint_missing_meta(&target, path));
         }
+    }
+
+    diagnostics.sort_by_key(|d| d.line);
+    return diagnostics;
+}
+
+// name / prefix が見つからなかったブロック
+fn lint_missing_meta(target: &LintBlock, path: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut missing = vec![];
+    if target.header.name.is_none() {
+        missing.push("name");
+    }
+    if !target.header.has_prefix {
+        missing.push("prefix");
+    }
 
-        return Some(captured);
+    for key in missing {
+        diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            path: path.to_string(),
+            line: target.line,
+            column: target.column,
+            tag: String::from(START_TAG),
+            message: format!("missing `{}` in the block", key),
+            width: START_TAG.chars().count(),
+            source: target.source.clone(),
+        });
     }
+    return diagnostics;
+}
 
+// nameのスニペットのprefixが書かれている行 (行番号, 列, 該当行) を返す
+pub fn locate_prefix<R: Reader>(reader: R, name: &str) -> Option<(usize, usize, String)> {
+    let mut block_name: Option<String> = None;
+    let mut prefix: Option<(usize, usize, String)> = None;
+    let mut in_block = false;
+
+    for (index, line) in reader.lines().iter().enumerate() {
+        if find_column(line, START_TAG).is_some() {
+            in_block = true;
+            block_name = None;
+            prefix = None;
+        } else if find_column(line, END_TAG).is_some() {
+            if in_block && block_name.as_deref() == Some(name) {
+                return prefix;
+            }
+            in_block = false;
+        } else if in_block {
+            // 本文中のものは拾わないように、最初に見つかったものだけを使う
+            if let Ok(Line::Meta { key, column, value }) = header::parse_line(line) {
+                match (key.as_str(), value) {
+                    ("name", Value::Text(name)) if block_name.is_none() => block_name = Some(name),
+                    ("prefix", _) if prefix.is_none() => {
+                        prefix = Some((index + 1, column, line.clone()));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
     return None;
 }
 
+///// Util
+
+// tagが見つかった列 (1始まり、文字単位)
+fn find_column(text: &str, tag: &str) -> Option<usize> {
+    let index = text.find(tag)?;
+    return Some(text[..index].chars().count() + 1);
+}
+
+// keyが既知のキーの書き間違い (大文字・小文字、_ / - の違いや1, 2文字の違い) らしければ、そのキーを返す
+fn find_similar_key(key: &str) -> Option<&'static str> {
+    if META_KEYS.contains(&key) {
+        return None;
+    }
+    let normalize = |key: &str| key.to_lowercase().replace(['_', '-'], "");
+    return META_KEYS.iter().copied().find(|known| {
+        let limit = if known.len() <= 5 { 1 } else { 2 };
+        normalize(known) == normalize(key) || edit_distance(known, key) <= limit
+    });
+}
+
+// 編集距離 (文字単位)
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for j in 0..b.len() {
+            let above = row[j + 1];
+            row[j + 1] = if a == b[j] {
+                diagonal
+            } else {
+                1 + diagonal.min(row[j]).min(above)
+            };
+            diagonal = above;
+        }
+    }
+    return row[b.len()];
+}
+
 ///// Unit Test
 
 #[cfg(test)]
@@ -630,18 +1809,6 @@ mod tests {
 
     //////// test ////////
 
-    #[test]
-    #[allow(non_snake_case)]
-    fn regexSearch_randomSpacing_valid() {
-        let line = String::from("//   name:         \"just_a_mock\"    ");
-        let result = regex_search(NAME_RE, &line);
-
-        assert_ne!(result, None);
-        let result = result.unwrap();
-        assert_eq!(result.len(), 2);
-        assert_eq!(result[1], String::from("just_a_mock"));
-    }
-
     #[test]
     #[allow(non_snake_case)]
     fn trimCode_oneCode_valid() {
@@ -828,6 +1995,176 @@ prinln!("test2!");
         }
     }
 
+    #[test]
+    #[allow(non_snake_case)]
+    fn trimCode_multiplePrefixes_valid() {
+        let text = r#"
+//#PORT#
+//name:"list"
+//prefix: ["seg", "segtree", "seg"]
+fn list() {}
+//#PORT_END#
+//#PORT#
+//name:"lines"
+//prefix:"seg"
+//prefix:"segtree"
+//description:"test_desc"
+fn lines() {}
+//#PORT_END#"#;
+
+        let result = trim_code(MockReader::new(String::from(text))).unwrap();
+        let expected = Prefix::Many(vec![String::from("seg"), String::from("segtree")]);
+        assert_eq!(result["list"].prefix, expected);
+        assert_eq!(result["lines"].prefix, expected);
+        assert_eq!(result["lines"].description, "test_desc");
+
+        let json = serde_json::to_value(&result["list"]).unwrap();
+        assert_eq!(json["prefix"], serde_json::json!(["seg", "segtree"]));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn trimCode_emptyPrefixList_invalid() {
+        let text = String::from(
+            "//#PORT#\n//name:\"just_a_mock\"\n//prefix: []\nfn test() {} \n//#PORT_END#",
+        );
+        let reader = MockReader::new(text);
+        assert!(trim_code(reader).is_err());
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn trimCode_headerGrammar_valid() {
+        let text = r#"
+// #PORT#
+// name: "seg"
+// prefix: "seg"
+// description: "Segment tree that supports"
+//     "point update and range query"
+//
+// isFileTemplate: true
+// tags: ["data structure", "tree"]
+// author:
+//     "me"
+// license: "MIT"
+
+template <class S> struct segtree {};
+// #PORT_END#"#;
+
+        let result = trim_code(MockReader::new(String::from(text))).unwrap();
+        let snippet = &result["seg"];
+        assert_eq!(
+            snippet.description,
+            "Segment tree that supports point update and range query"
+        );
+        assert_eq!(snippet.body, "\ntemplate <class S> struct segtree {};\n"); // 空行から本文
+        assert!(snippet.is_file_template);
+        assert_eq!(snippet.tags, vec!["data structure", "tree"]);
+        assert_eq!(snippet.author, Some(String::from("me")));
+
+        let json = serde_json::to_value(snippet).unwrap();
+        assert_eq!(json["isFileTemplate"], true);
+        assert_eq!(json["extra"], serde_json::json!({"license": "MIT"}));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn trimCode_bodyTag_valid() {
+        let text = r#"
+// #PORT#
+// name: "label"
+// prefix: "label"
+
+// description: "blank lines in the header are skipped"
+// #PORT_BODY#
+// note: "this line is the body"
+public:
+// #PORT_END#
+// #PORT#
+// name: "public"
+// prefix: "public"
+public:
+// #PORT_END#"#;
+
+        let result = trim_code(MockReader::new(String::from(text))).unwrap();
+        assert_eq!(
+            result["label"].description,
+            "blank lines in the header are skipped"
+        );
+        assert_eq!(
+            result["label"].body,
+            "// note: \"this line is the body\"\npublic:\n"
+        );
+        assert!(result["label"].extra.is_empty());
+        assert_eq!(result["public"].body, "public:\n"); // 値のない知らないキーは本文
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn trimCode_escapedValues_valid() {
+        let text = r#"
+// #PORT#
+// name: seg
+// prefix: 'seg'
+// description: "prints \"hello\"\tand a path C:\\tmp"
+// author: me
+// TODO: check the bounds
+fn seg() {}
+// #PORT_END#"#;
+
+        let result = trim_code(MockReader::new(String::from(text))).unwrap();
+        let snippet = &result["seg"];
+        assert_eq!(snippet.prefix, Prefix::One(String::from("seg")));
+        assert_eq!(snippet.description, "prints \"hello\"\tand a path C:\\tmp");
+        assert_eq!(snippet.author, Some(String::from("me")));
+        // 引用符のない値の知らないキーは本文のコメント
+        assert_eq!(snippet.body, "// TODO: check the bounds\nfn seg() {}\n");
+        assert!(snippet.extra.is_empty());
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn trimCode_brokenHeader_invalid() {
+        let trim = |text: &str| trim_code(MockReader::new(String::from(text)));
+        let header = "//#PORT#\n//name:\"mock\"\n//prefix:\"mock\"\n";
+
+        let text = format!(
+            "{}//isFileTemplate: \"yes\"\nfn test() {{}}\n//#PORT_END#",
+            header
+        );
+        assert_eq!(
+            trim(&text),
+            Err(TrimError::InvalidValue(String::from("isFileTemplate")))
+        );
+
+        let text = format!(
+            "{}//description: \"test\nfn test() {{}}\n//#PORT_END#",
+            header
+        );
+        assert_eq!(trim(&text), Err(TrimError::InvalidDescription));
+
+        // 本文が始まった後の #PORT_BODY#
+        let text = format!("{}fn test() {{}}\n//#PORT_BODY#\n//#PORT_END#", header);
+        assert_eq!(trim(&text), Err(TrimError::InvalidMeta));
+
+        // name / prefix より前の #PORT_BODY#
+        let text = "//#PORT#\n//name:\"mock\"\n//#PORT_BODY#\n//prefix:\"mock\"\n//#PORT_END#";
+        assert_eq!(trim(text), Err(TrimError::InvalidMeta));
+
+        // 同じファイルの中で同じname (lintと同じくエラー)
+        let text = format!(
+            "{}fn first() {{}}\n//#PORT_END#\n{}fn second() {{}}\n//#PORT_END#",
+            header, header
+        );
+        assert_eq!(
+            trim(&text),
+            Err(TrimError::DuplicateName(String::from("mock")))
+        );
+        assert!(lint(MockReader::new(text), "MOCK_PATH")
+            .iter()
+            .any(|d| d.is_error()));
+    }
+
     #[test]
     #[allow(non_snake_case)]
     fn addCode_fromScratch_valid() {
@@ -853,8 +2190,10 @@ prinln!("test2!")
             readers.snippet_json,
             &mut readers.namelist,
             mock_filename,
+            &Naming::default(),
+            &OutputStyle::default(),
         );
-        assert_ne!(result, None);
+        assert!(result.is_ok());
         let result = result.unwrap();
         let parsed = parse_json(result.json);
 
@@ -914,8 +2253,10 @@ prinln!("test2!")
             readers.snippet_json,
             &mut readers.namelist,
             mock_filename,
+            &Naming::default(),
+            &OutputStyle::default(),
         );
-        assert_ne!(result, None);
+        assert!(result.is_ok());
 
         let result = result.unwrap();
         let parsed = parse_json(result.json);
@@ -976,8 +2317,10 @@ prinln!("test2!")
             readers.snippet_json,
             &mut readers.namelist,
             mock_filename,
+            &Naming::default(),
+            &OutputStyle::default(),
         );
-        assert_ne!(result, None);
+        assert!(result.is_ok());
 
         let result = result.unwrap();
         let parsed = parse_json(result.json);
@@ -1051,8 +2394,10 @@ test()
             readers.snippet_json,
             &mut readers.namelist,
             mock_filename,
+            &Naming::default(),
+            &OutputStyle::default(),
         );
-        assert_ne!(result, None);
+        assert!(result.is_ok());
 
         let result = result.unwrap();
         let parsed = parse_json(result.json);
@@ -1125,8 +2470,10 @@ prinln!("test2!")
             readers.snippet_json,
             &mut readers.namelist,
             mock_filename,
+            &Naming::default(),
+            &OutputStyle::default(),
         );
-        assert_ne!(result, None);
+        assert!(result.is_ok());
 
         let result = result.unwrap();
         let parsed = parse_json(result.json);
@@ -1183,8 +2530,10 @@ test()
             readers.snippet_json,
             &mut readers.namelist,
             mock_filename,
+            &Naming::default(),
+            &OutputStyle::default(),
         );
-        assert_ne!(result, None);
+        assert!(result.is_ok());
         let result = result.unwrap();
         println!("result: {}", &result.json);
         let parsed = parse_json(result.json);
@@ -1261,8 +2610,10 @@ test()
             readers.snippet_json,
             &mut readers.namelist,
             mock_filename,
+            &Naming::default(),
+            &OutputStyle::default(),
         );
-        assert_ne!(result, None);
+        assert!(result.is_ok());
         let result = result.unwrap();
         println!("result: {}", &result.json);
         let parsed = parse_json(result.json);
@@ -1342,9 +2693,11 @@ test()
             readers.snippet_json,
             &mut readers.namelist,
             mock_filename,
+            &Naming::default(),
+            &OutputStyle::default(),
         );
 
-        assert_ne!(result, None);
+        assert!(result.is_ok());
         let result = result.unwrap();
         println!("result: {}", &result.json);
         let parsed = parse_json(result.json);
@@ -1383,4 +2736,916 @@ test()
         assert_eq!(result.name_list["MOCK2"].len(), 1);
         assert_eq!(result.name_list["MOCK2"][0], "mock2");
     }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn make_changes_addedUpdatedRemoved() {
+        let snippet_text = r#"
+// #PORT#
+//name:"mock2"
+//prefix:"prefix2"
+//description:"desc2"
+fn second_modified() {}
+//#PORT_END#
+// #PORT#
+//name:"mock3"
+//prefix:"prefix3"
+//description:"desc3"
+fn third() {}
+//#PORT_END#"#;
+
+        let snippet_json = r#"{
+////////// [[Generated By PortSnippet]] (DON'T REMOVE) //////////
+"mock1":{"prefix":"prefix1","body":"fn first() {}\n","description":"desc1"},"mock2":{"prefix":"prefix2","body":"fn second() {}\n","description":"desc2"},
+////////// [[PortSnippet End]] (DON'T REMOVE) //////////
+}"#;
+
+        let mut readers = prepare_reader(
+            snippet_text,
+            "{\"MOCK_PATH\":[\"mock1\",\"mock2\"]}",
+            snippet_json,
+        );
+        let result = make(
+            readers.snippet,
+            readers.snippet_json,
+            &mut readers.namelist,
+            String::from("MOCK_PATH"),
+            &Naming::default(),
+            &OutputStyle::default(),
+        )
+        .unwrap();
+
+        assert_eq!(result.changes.added, vec!["mock3"]);
+        assert_eq!(result.changes.updated, vec!["mock2"]);
+        assert_eq!(result.changes.removed, vec!["mock1"]);
+        assert_eq!(result.changes.fields["mock2"], vec!["body"]);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn make_sameSnippet_unchanged() {
+        let snippet_text = r#"
+// #PORT#
+//name:"mock1"
+//prefix:"prefix1"
+//description:"desc1"
+fn first() {}
+//#PORT_END#"#;
+
+        let snippet_json = r#"{
+////////// [[Generated By PortSnippet]] (DON'T REMOVE) //////////
+"mock1":{"prefix":"prefix1","body":"fn first() {}\n","description":"desc1"},
+////////// [[PortSnippet End]] (DON'T REMOVE) //////////
+}"#;
+
+        let mut readers = prepare_reader(snippet_text, "{\"MOCK_PATH\":[\"mock1\"]}", snippet_json);
+        let result = make(
+            readers.snippet,
+            readers.snippet_json,
+            &mut readers.namelist,
+            String::from("MOCK_PATH"),
+            &Naming::default(),
+            &OutputStyle::default(),
+        )
+        .unwrap();
+
+        assert!(result.changes.is_empty());
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn make_allSnippetsRemoved_purged() {
+        let snippet_json = r#"{
+////////// [[Generated By PortSnippet]] (DON'T REMOVE) //////////
+"mock1":{"prefix":"prefix1","body":"fn first() {}\n","description":"desc1"},"other":{"prefix":"other","body":"fn other() {}\n","description":"other"},
+////////// [[PortSnippet End]] (DON'T REMOVE) //////////
+}"#;
+
+        let mut readers = prepare_reader(
+            "fn first() {}",
+            "{\"MOCK_PATH\":[\"mock1\"],\"OTHER_PATH\":[\"other\"]}",
+            snippet_json,
+        );
+        let result = make(
+            readers.snippet,
+            readers.snippet_json,
+            &mut readers.namelist,
+            String::from("MOCK_PATH"),
+            &Naming::default(),
+            &OutputStyle::default(),
+        )
+        .unwrap();
+
+        let parsed = parse_json(result.json);
+        assert_eq!(parsed.contains_key("mock1"), false);
+        assert_eq!(parsed.contains_key("other"), true);
+        assert_eq!(result.name_list["MOCK_PATH"].len(), 0);
+        assert_eq!(result.changes.removed, vec!["mock1"]);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn make_noSnippetNoHistory_notFound() {
+        let mut readers = prepare_reader("fn first() {}", "{}", "{}");
+        let result = make(
+            readers.snippet,
+            readers.snippet_json,
+            &mut readers.namelist,
+            String::from("MOCK_PATH"),
+            &Naming::default(),
+            &OutputStyle::default(),
+        );
+
+        assert_eq!(result, Err(MakeError::NotFound));
+    }
+
+    fn lint_text(text: &str) -> Vec<Diagnostic> {
+        return lint(MockReader::new(String::from(text)), "MOCK_PATH");
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn lint_validCode_empty() {
+        let text = r#"
+// #PORT#
+// name: "just_a_mock"
+// prefix: "test_prefix"
+// description: "test_desc"
+fn test() {}
+// #PORT_END#"#;
+
+        assert_eq!(lint_text(text), vec![]);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn lint_unclosedBlock_error() {
+        let text = r#"
+fn main() {}
+  // #PORT#
+// name: "just_a_mock"
+// prefix: "test_prefix"
+fn test() {}"#;
+
+        let diagnostics = lint_text(text);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[0].path, "MOCK_PATH");
+        assert_eq!(diagnostics[0].line, 3);
+        assert_eq!(diagnostics[0].column, 6);
+        assert_eq!(diagnostics[0].tag, "#PORT#");
+        assert_eq!(diagnostics[0].source, "  // #PORT#");
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn lint_missingNameAndPrefix_error() {
+        let text = r#"// #PORT#
+// description: "test_desc"
+fn test() {}
+// #PORT_END#
+// #PORT#
+// name: "second"
+// prefix: "second"
+fn second() {}
+// #PORT_END#"#;
+
+        let diagnostics = lint_text(text);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].message, "missing `name` in the block");
+        assert_eq!(diagnostics[1].message, "missing `prefix` in the block");
+        assert_eq!(diagnostics[0].line, 1);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn lint_emptyName_error() {
+        let text = r#"// #PORT#
+// name: ""
+// prefix: "test_prefix"
+fn test() {}
+// #PORT_END#"#;
+
+        let diagnostics = lint_text(text);
+        assert_eq!(diagnostics[0].message, "`name` is empty");
+        assert_eq!(diagnostics[0].line, 2);
+        assert_eq!(diagnostics[0].column, 4);
+        assert!(diagnostics.iter().all(|d| d.is_error()));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn lint_emptyPrefixList_error() {
+        let text = r#"// #PORT#
+// name: "test"
+// prefix: []
+fn test() {}
+// #PORT_END#"#;
+
+        let diagnostics = lint_text(text);
+        assert_eq!(diagnostics[0].message, "`prefix` list is empty");
+        assert_eq!(diagnostics[0].line, 3);
+        assert!(diagnostics.iter().all(|d| d.is_error()));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn lint_duplicateName_error() {
+        let text = r#"// #PORT#
+// name: "mock"
+// prefix: "first"
+fn first() {}
+// #PORT_END#
+// #PORT#
+//   name: "mock"
+// prefix: "second"
+fn second() {}
+// #PORT_END#"#;
+
+        let diagnostics = lint_text(text);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].message,
+            "duplicate name `mock` (first defined at line 2)"
+        );
+        assert_eq!(diagnostics[0].line, 7);
+        assert_eq!(diagnostics[0].column, 6);
+        assert_eq!(diagnostics[0].source, "//   name: \"mock\"");
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn lint_unknownMetaKey_warning() {
+        let text = r#"// #PORT#
+// name: "mock"
+// descripton: "typo"
+// prefix: "mock"
+// group: "cpp"
+// author: "me"
+fn mock() {}
+// #PORT_END#"#;
+
+        // 書き間違いらしいものだけを知らせる
+        let diagnostics = lint_text(text);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(diagnostics[0].tag, "descripton");
+        assert_eq!(
+            diagnostics[0].message,
+            "unknown meta key `descripton` is kept in `extra`; did you mean `description`?"
+        );
+        assert_eq!(diagnostics[0].line, 3);
+        assert_eq!(diagnostics[0].column, 4);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn lint_brokenHeader_error() {
+        let text = r#"// #PORT#
+// name: "mock"
+// prefix: ["mock" "other"]
+// isFileTemplate: "yes"
+fn mock() {}
+// #PORT_BODY#
+// #PORT_END#"#;
+
+        let diagnostics = lint_text(text);
+        assert_eq!(diagnostics.len(), 3);
+        assert!(diagnostics.iter().all(|d| d.is_error()));
+        assert_eq!(diagnostics[0].message, "expected `,` or `]`");
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (3, 20));
+        assert_eq!(
+            diagnostics[1].message,
+            "`isFileTemplate` must be `true` or `false`"
+        );
+        assert_eq!(diagnostics[2].tag, "#PORT_BODY#");
+        assert_eq!(diagnostics[2].line, 6);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn lint_badEscape_error() {
+        let text = r#"// #PORT#
+// name: "mock"
+// prefix: "mock"
+// description: "C:\dir"
+fn mock() {}
+// #PORT_END#"#;
+
+        let diagnostics = lint_text(text);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].tag, "description");
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (4, 20));
+        assert_eq!(diagnostics[0].width, 2); // \d だけに印を付ける
+        assert!(diagnostics[0].message.contains("invalid escape"));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn lint_nestedStartTag_error() {
+        let text = r#"// #PORT#
+// name: "first"
+// prefix: "first"
+fn first() {}
+// #PORT#
+// name: "second"
+// prefix: "second"
+fn second() {}
+// #PORT_END#"#;
+
+        let diagnostics = lint_text(text);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, 5);
+        assert!(diagnostics[0].message.contains("starts at line 1"));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn lint_strayEndTag_warning() {
+        let diagnostics = lint_text("fn main() {}\n// #PORT_END#");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(diagnostics[0].tag, "#PORT_END#");
+        assert_eq!(diagnostics[0].line, 2);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn getChangedFields_prefixAndDescription_valid() {
+        let old = Snippet {
+            prefix: Prefix::One(String::from("old")),
+            body: Body::Text(String::from("fn test() {}")),
+            description: String::from("old"),
+            ..Snippet::new()
+        };
+        let mut new = old.clone();
+        new.prefix = Prefix::One(String::from("new"));
+        new.description = String::from("new");
+        new.tags = vec![String::from("new")];
+
+        assert_eq!(
+            get_changed_fields(Some(&old), &new),
+            vec!["prefix", "description", "tags"]
+        );
+        assert_eq!(get_changed_fields(Some(&old), &old), Vec::<String>::new());
+    }
+
+    // コメントを取り除いて、末尾のコンマなしの正しいJSONとして読めるか
+    fn parse_strict_json(json: &str) -> serde_json::Value {
+        let document = jsonc::Document::parse(json).unwrap();
+        let object = document.object.as_ref().unwrap();
+        let mut spans = object.comments.clone();
+        spans.push(jsonc::Span {
+            start: 0,
+            end: object.open,
+        });
+        let edits = spans
+            .iter()
+            .map(|span| jsonc::Edit {
+                span: *span,
+                text: String::new(),
+            })
+            .collect();
+        let json = document.apply(edits);
+        return serde_json::from_str(json.trim_start_matches('\u{feff}')).unwrap();
+    }
+
+    const MOCK1_TEXT: &str =
+        "//#PORT#\n//name:\"mock1\"\n//prefix:\"prefix1\"\nfn first() {}\n//#PORT_END#";
+
+    fn make_with_json(snippet_json: &str) -> Output {
+        return make_with(MOCK1_TEXT, &KeyList::new(), snippet_json);
+    }
+
+    fn make_with(snippet_text: &str, name_list: &KeyList, snippet_json: &str) -> Output {
+        return make_as(
+            "MOCK_PATH",
+            &Naming::default(),
+            snippet_text,
+            name_list,
+            snippet_json,
+        )
+        .unwrap();
+    }
+
+    fn make_as(
+        path: &str,
+        naming: &Naming,
+        snippet_text: &str,
+        name_list: &KeyList,
+        snippet_json: &str,
+    ) -> Result<Output, MakeError> {
+        let namelist = serde_json::to_string(name_list).unwrap();
+        let mut readers = prepare_reader(snippet_text, &namelist, snippet_json);
+        return make(
+            readers.snippet,
+            readers.snippet_json,
+            &mut readers.namelist,
+            String::from(path),
+            naming,
+            &OutputStyle::default(),
+        );
+    }
+
+    // A.cppとB.cppで同じ名前のスニペットを生成する
+    fn make_conflict(policy: ConflictPolicy) -> (Output, Result<Output, MakeError>) {
+        let naming = Naming {
+            policy: policy,
+            namespace: String::from("lib/B.cpp"),
+        };
+        let first = make_as("A", &naming, MOCK1_TEXT, &KeyList::new(), "{}").unwrap();
+        let snippet_text = MOCK1_TEXT.replace("prefix1", "prefix2");
+        let second = make_as("B", &naming, &snippet_text, &first.name_list, &first.json);
+        return (first, second);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn genAlljson_handWritten_keptAsIs() {
+        let handwritten = r#"// my snippets { see below
+{
+	// keep me
+	"dump": {"prefix": "dump", "body": "", "description": ""} /* , */
+}
+"#;
+        let result = make_with_json(handwritten);
+        assert!(result.json.starts_with("// my snippets { see below\n{\n"));
+        assert!(result.json.contains("\t// keep me\n\t\"dump\": {\"prefix\": \"dump\", \"body\": \"\", \"description\": \"\"} /* , */\n}\n"));
+
+        let parsed = parse_strict_json(&result.json);
+        assert_eq!(parsed["mock1"]["prefix"], "prefix1");
+        assert_eq!(parsed["dump"]["prefix"], "dump");
+
+        // 2回目は何も変わらない
+        let again = make_with(MOCK1_TEXT, &result.name_list, &result.json);
+        assert_eq!(again.json, result.json);
+        assert!(again.changes.is_empty());
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn genAlljson_trailingComma_removed() {
+        let snippet_json = r#"{
+"dump": {"prefix": "dump", "body": "", "description": ""},
+////////// [[Generated By PortSnippet]] (DON'T REMOVE) //////////
+"old":{"prefix":"old","body":"","description":""},
+////////// [[PortSnippet End]] (DON'T REMOVE) //////////
+}"#;
+        let result = make_with_json(snippet_json);
+        let parsed = parse_strict_json(&result.json);
+        assert_eq!(parsed.as_object().unwrap().len(), 3);
+        assert!(result.json.ends_with("\n}"));
+        assert!(!result
+            .json
+            .contains(&format!(",\n{}", format_tag(GEN_END_TAG))));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn genAlljson_emptyOrBom_valid() {
+        for snippet_json in ["", "\n", "// nothing yet\n", "{}", "\u{feff}{\n}\n"].iter() {
+            let result = make_with_json(snippet_json);
+            let parsed = parse_strict_json(&result.json);
+            assert_eq!(parsed.as_object().unwrap().len(), 1);
+            assert_eq!(
+                result.json.starts_with('\u{feff}'),
+                snippet_json.starts_with('\u{feff}')
+            );
+        }
+
+        let mut readers = prepare_reader(
+            "//#PORT#\n//name:\"a\"\n//prefix:\"a\"\n//#PORT_END#",
+            "{}",
+            "[]",
+        );
+        let result = make(
+            readers.snippet,
+            readers.snippet_json,
+            &mut readers.namelist,
+            String::from("MOCK_PATH"),
+            &Naming::default(),
+            &OutputStyle::default(),
+        );
+        assert_eq!(result, Err(MakeError::InvalidJson));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn nameList_legacyFormat_valid() {
+        let name_list = serde_json::from_str::<KeyList>(
+            r#"{"A":["mock1"],"B":[{"name":"mock2","hash":"0123456789abcdef"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(name_list["A"][0].hash, None);
+        assert_eq!(
+            name_list["B"][0].hash,
+            Some(String::from("0123456789abcdef"))
+        );
+
+        let result = make_with(MOCK1_TEXT, &name_list, "{}");
+        assert_eq!(result.name_list["MOCK_PATH"][0], "mock1");
+        assert!(result.name_list["MOCK_PATH"][0].hash.is_some());
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn make_editedEntry_kept() {
+        let first = make_with_json("{}");
+
+        // VSCode上でprefixを書き換える
+        let edited_json = first.json.replace("\"prefix1\"", "\"mine\"");
+        let snippet_text = MOCK1_TEXT.replace("prefix1", "prefix2");
+        let result = make_with(&snippet_text, &first.name_list, &edited_json);
+
+        assert_eq!(result.json, edited_json);
+        assert_eq!(result.changes.edited, vec!["mock1"]);
+        assert!(result.changes.updated.is_empty());
+        assert_eq!(result.name_list["MOCK_PATH"], first.name_list["MOCK_PATH"]);
+
+        // 手で消されたら作り直す
+        let removed_json = r#"{
+////////// [[Generated By PortSnippet]] (DON'T REMOVE) //////////
+////////// [[PortSnippet End]] (DON'T REMOVE) //////////
+}"#;
+        let result = make_with(&snippet_text, &first.name_list, removed_json);
+        assert_eq!(
+            parse_strict_json(&result.json)["mock1"]["prefix"],
+            "prefix2"
+        );
+        assert!(result.changes.edited.is_empty());
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn make_movedEntry_updatedInPlace() {
+        let first = make_with_json("{}");
+
+        // 生成部分の外に移動する
+        let snippet_json = r#"{
+	"mine": {"prefix": "mine", "body": "", "description": ""},
+	"mock1": {"prefix": "prefix1", "body": "fn first() {}\n", "description": ""}
+}"#;
+        let snippet_text = MOCK1_TEXT.replace("prefix1", "prefix2");
+        let result = make_with(&snippet_text, &first.name_list, snippet_json);
+
+        assert!(result.json.starts_with(
+            "{\n\t\"mine\": {\"prefix\": \"mine\", \"body\": \"\", \"description\": \"\"},\n\t\"mock1\": {\n\t  \"prefix\": \"prefix2\","
+        ));
+        assert!(!result.json.contains(GEN_START_TAG));
+        assert_eq!(result.changes.updated, vec!["mock1"]);
+
+        // ソースから消されたら、移動先から削除する
+        let result = make_with("fn first() {}", &result.name_list, &result.json);
+        assert_eq!(
+            result.json,
+            "{\n\t\"mine\": {\"prefix\": \"mine\", \"body\": \"\", \"description\": \"\"}\n}"
+        );
+        assert_eq!(result.changes.removed, vec!["mock1"]);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn make_handWrittenSameName_kept() {
+        let snippet_json = r#"{"mock1": {"prefix": "mine", "body": "", "description": ""}}"#;
+        let result = make_with_json(snippet_json);
+
+        assert_eq!(result.json, snippet_json);
+        assert_eq!(result.changes.edited, vec!["mock1"]);
+        assert!(result.name_list["MOCK_PATH"].is_empty());
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn make_nameConflict_error() {
+        let (_, second) = make_conflict(ConflictPolicy::Error);
+        assert_eq!(
+            second,
+            Err(MakeError::NameConflict(Conflict {
+                name: String::from("mock1"),
+                owner: String::from("A"),
+                key: String::from("mock1"),
+                kept: false,
+            }))
+        );
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn make_nameConflict_overwrite() {
+        let (_, second) = make_conflict(ConflictPolicy::Overwrite);
+        let second = second.unwrap();
+
+        assert_eq!(
+            parse_strict_json(&second.json)["mock1"]["prefix"],
+            "prefix2"
+        );
+        assert_eq!(second.changes.conflicts[0].owner, "A");
+        assert_eq!(second.name_list["A"][0], "mock1");
+        assert!(second.name_list["A"][0].shadowed);
+        assert_eq!(second.name_list["B"][0], "mock1");
+        assert!(!second.name_list["B"][0].shadowed);
+
+        // 元のファイルから消しても、上書きした方のスニペットは消えない
+        let result = make_as(
+            "A",
+            &Naming::default(),
+            "fn first() {}",
+            &second.name_list,
+            &second.json,
+        )
+        .unwrap();
+        assert_eq!(result.json, second.json);
+        assert!(result.changes.is_empty());
+        assert!(result.resync.is_empty());
+        assert!(result.name_list["A"].is_empty());
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn make_nameConflict_rescanKeepsOwner() {
+        let (_, second) = make_conflict(ConflictPolicy::Overwrite);
+        let second = second.unwrap();
+
+        // 変わっていないファイルを生成し直しても、持ち主は入れ替わらない
+        let rescan = make_as(
+            "A",
+            &Naming::default(),
+            MOCK1_TEXT,
+            &second.name_list,
+            &second.json,
+        )
+        .unwrap();
+        assert_eq!(rescan.json, second.json);
+        assert_eq!(rescan.name_list, second.name_list);
+        assert!(rescan.changes.conflicts[0].kept);
+
+        // 変えたら上書きする
+        let snippet_text = MOCK1_TEXT.replace("prefix1", "prefix3");
+        let edited = make_as(
+            "A",
+            &Naming::default(),
+            &snippet_text,
+            &rescan.name_list,
+            &rescan.json,
+        )
+        .unwrap();
+        assert_eq!(
+            parse_strict_json(&edited.json)["mock1"]["prefix"],
+            "prefix3"
+        );
+        assert!(!edited.changes.conflicts[0].kept);
+        assert!(edited.name_list["B"][0].shadowed);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn forget_overwritingFile_restoresShadowed() {
+        let (_, second) = make_conflict(ConflictPolicy::Overwrite);
+        let second = second.unwrap();
+
+        // 上書きしたファイルを消すと、同じ名前を定義しているファイルを作り直すように返す
+        let namelist = serde_json::to_string(&second.name_list).unwrap();
+        let mut readers = prepare_reader("", &namelist, &second.json);
+        let forgotten = forget(
+            readers.snippet_json,
+            &mut readers.namelist,
+            String::from("B"),
+        )
+        .unwrap();
+        assert!(parse_strict_json(&forgotten.json).get("mock1").is_none());
+        assert_eq!(forgotten.resync, vec!["A"]);
+
+        let resynced = make_as(
+            "A",
+            &Naming::default(),
+            MOCK1_TEXT,
+            &forgotten.name_list,
+            &forgotten.json,
+        )
+        .unwrap();
+        assert_eq!(
+            parse_strict_json(&resynced.json)["mock1"]["prefix"],
+            "prefix1"
+        );
+        assert!(resynced.changes.conflicts.is_empty());
+        assert_eq!(resynced.changes.added, vec!["mock1"]);
+        assert!(!resynced.name_list["A"][0].shadowed);
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn make_nameConflict_namespace() {
+        let (first, second) = make_conflict(ConflictPolicy::Namespace);
+        let second = second.unwrap();
+
+        let json = parse_strict_json(&second.json);
+        assert_eq!(json["mock1"]["prefix"], "prefix1");
+        assert_eq!(json["lib/B.cpp::mock1"]["prefix"], "prefix2");
+        assert_eq!(second.changes.added, vec!["lib/B.cpp::mock1"]);
+        assert_eq!(second.changes.conflicts[0].key, "lib/B.cpp::mock1");
+        assert_eq!(second.name_list["A"], first.name_list["A"]);
+        assert_eq!(second.name_list["B"][0], "lib/B.cpp::mock1");
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn locatePrefix_valid() {
+        let text = "//#PORT#\n//name:\"mock1\"\n//prefix:\"prefix1\"\nfn first() {}\n//#PORT_END#\n\n// #PORT#\n// prefix: \"prefix2\"\n// name: \"mock2\"\n// prefix: \"body\"\n// #PORT_END#";
+        assert_eq!(
+            locate_prefix(MockReader::new(String::from(text)), "mock2"),
+            Some((8, 4, String::from("// prefix: \"prefix2\"")))
+        );
+        assert_eq!(
+            locate_prefix(MockReader::new(String::from(text)), "mock1").map(|l| l.0),
+            Some(3)
+        );
+        assert_eq!(
+            locate_prefix(MockReader::new(String::from(text)), "none"),
+            None
+        );
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn forget_removesOnlyItsSnippets() {
+        let first = make_as("A", &Naming::default(), MOCK1_TEXT, &KeyList::new(), "{}").unwrap();
+        let snippet_text = MOCK1_TEXT.replace("mock1", "mock2");
+        let second = make_as(
+            "B",
+            &Naming::default(),
+            &snippet_text,
+            &first.name_list,
+            &first.json,
+        )
+        .unwrap();
+
+        let namelist = serde_json::to_string(&second.name_list).unwrap();
+        let mut readers = prepare_reader("", &namelist, &second.json);
+        let result = forget(
+            readers.snippet_json,
+            &mut readers.namelist,
+            String::from("A"),
+        )
+        .unwrap();
+
+        let json = parse_strict_json(&result.json);
+        assert!(json.get("mock1").is_none());
+        assert!(json.get("mock2").is_some());
+        assert_eq!(result.changes.removed, vec!["mock1"]);
+        assert!(!result.name_list.contains_key("A"));
+        assert_eq!(result.name_list["B"], second.name_list["B"]);
+    }
+
+    fn get_hashes(text: String, style: &OutputStyle) -> Vec<String> {
+        return get_snippet_hashes(MockReader::new(text), style).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn hasSameSnippets_valid() {
+        let first = make_with_json("{}");
+        let names = &first.name_list["MOCK_PATH"];
+
+        let moved = format!("// moved\n{}\n", MOCK1_TEXT);
+        assert!(has_same_snippets(
+            names,
+            &get_hashes(moved, &OutputStyle::default())
+        ));
+        let edited = MOCK1_TEXT.replace("prefix1", "prefix2");
+        assert!(!has_same_snippets(
+            names,
+            &get_hashes(edited, &OutputStyle::default())
+        ));
+
+        // ハッシュのない以前のnamelistでは判定できない
+        let legacy = serde_json::from_str::<KeyList>(r#"{"MOCK_PATH":["mock1"]}"#).unwrap();
+        let text = String::from(MOCK1_TEXT);
+        assert!(!has_same_snippets(
+            &legacy["MOCK_PATH"],
+            &get_hashes(text, &OutputStyle::default())
+        ));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn make_scope_valid() {
+        let snippet_text = r#"//#PORT#
+//name:"mock1"
+//prefix:"prefix1"
+//scope:"c, cpp"
+fn first() {}
+//#PORT_END#
+//#PORT#
+//name:"mock2"
+//prefix:"prefix2"
+fn second() {}
+//#PORT_END#"#;
+        let mut readers = prepare_reader(snippet_text, "", "");
+        let result = make(
+            readers.snippet,
+            readers.snippet_json,
+            &mut readers.namelist,
+            String::from("MOCK_PATH"),
+            &Naming::default(),
+            &OutputStyle {
+                scope: Some(String::from("rust")),
+                ..OutputStyle::default()
+            },
+        )
+        .unwrap();
+
+        let json = parse_strict_json(&result.json);
+        assert_eq!(json["mock1"]["scope"], "c,cpp");
+        assert_eq!(json["mock1"]["body"], "fn first() {}\n");
+        assert_eq!(json["mock2"]["scope"], "rust"); // 対象ファイルの言語
+
+        // <lang>.jsonに書き込むときは、書かれていないscopeは出力しない
+        let result = make_with(snippet_text, &KeyList::new(), "{}");
+        let json = parse_strict_json(&result.json);
+        assert_eq!(json["mock1"]["scope"], "c,cpp");
+        assert!(json["mock2"].get("scope").is_none());
+
+        let names = &result.name_list["MOCK_PATH"];
+        let text = String::from(snippet_text);
+        assert!(has_same_snippets(
+            names,
+            &get_hashes(text, &OutputStyle::default())
+        ));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn make_bodyAsLines_valid() {
+        let snippet_text =
+            "//#PORT#\n//name:\"mock\"\n//prefix:\"prefix\"\nfn a() {\n}\n//#PORT_END#";
+        let style = OutputStyle {
+            body_as_lines: true,
+            ..OutputStyle::default()
+        };
+        let mut readers = prepare_reader(snippet_text, "", "");
+        let result = make(
+            readers.snippet,
+            readers.snippet_json,
+            &mut readers.namelist,
+            String::from("MOCK_PATH"),
+            &Naming::default(),
+            &style,
+        )
+        .unwrap();
+
+        let json = parse_strict_json(&result.json);
+        assert_eq!(
+            json["mock"]["body"],
+            serde_json::json!(["fn a() {", "}", ""])
+        );
+
+        let snippet: Snippet = serde_json::from_value(json["mock"].clone()).unwrap();
+        assert_eq!(snippet.body, "fn a() {\n}\n");
+
+        let names = &result.name_list["MOCK_PATH"];
+        let text = String::from(snippet_text);
+        assert!(has_same_snippets(names, &get_hashes(text.clone(), &style)));
+        assert!(!has_same_snippets(
+            names,
+            &get_hashes(text, &OutputStyle::default())
+        ));
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn make_placeholder_valid() {
+        let snippet_text = r#"//#PORT#
+//name:"mock"
+//prefix:"prefix"
+for (int __PS1_i__ = 0; __PS1_i__ < /*${2:n}*/n; ++__PS1_i__) {
+    printf("$%d\n", __PS1_i__);__PS0__
+}
+//#PORT_END#"#;
+        let style = OutputStyle {
+            placeholder_marker: String::from("__PS"),
+            ..OutputStyle::default()
+        };
+        let mut readers = prepare_reader(snippet_text, "", "");
+        let result = make(
+            readers.snippet,
+            readers.snippet_json,
+            &mut readers.namelist,
+            String::from("MOCK_PATH"),
+            &Naming::default(),
+            &style,
+        )
+        .unwrap();
+
+        let json = parse_strict_json(&result.json);
+        assert_eq!(
+            json["mock"]["body"],
+            "for (int ${1:i} = 0; ${1:i} < ${2:n}; ++${1:i}) {\n    printf(\"\\$%d\\n\", ${1:i});$0\n}\n"
+        );
+
+        let names = &result.name_list["MOCK_PATH"];
+        assert!(has_same_snippets(
+            names,
+            &get_hashes(String::from(snippet_text), &style)
+        ));
+    }
 }
@@ -0,0 +1,391 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// 起動中のデーモンの状態を snippets_dir/.port_snippet/state.json に記録する
+// statusコマンドはこのファイルを読んで表示する
+
+const STATE_FILENAME: &str = "state.json";
+
+static STATE_LOCK: Mutex<()> = Mutex::new(()); // 監視スレッドから同時に書き込まれないようにする
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct State {
+    pub pid: u32,
+    pub started_at: u64, // unix time (sec)
+    pub dirs: Vec<String>,
+    pub files: Vec<String>,
+    pub sources: BTreeMap<String, SourceState>, // path, state
+    pub last_error: Option<ErrorState>,
+}
+
+// 対象ファイルごとの最終同期結果
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct SourceState {
+    pub lang: String,
+    pub last_sync: u64,
+    pub snippets: Vec<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ErrorState {
+    pub path: String,
+    pub message: String,
+    pub at: u64,
+}
+
+// statusコマンドの出力
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub running: bool,
+    pub pid: Option<u32>,
+    pub paused: bool,
+    pub snippets_dir: String,
+    pub state: Option<State>,
+}
+
+pub fn get_state_filepath(meta_dir: &Path) -> PathBuf {
+    return meta_dir.join(STATE_FILENAME);
+}
+
+pub fn now() -> u64 {
+    return match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    };
+}
+
+pub fn load(meta_dir: &Path) -> Option<State> {
+    let contents = std::fs::read_to_string(get_state_filepath(meta_dir)).ok()?;
+    return serde_json::from_str::<State>(&contents).ok();
+}
+
+fn save(meta_dir: &Path, state: &State) {
+    if let Ok(json) = serde_json::to_string_pretty(state) {
+        let state_filepath = get_state_filepath(meta_dir);
+        if let Err(e) = super::file::write_file(&state_filepath, json) {
+            println!("cannot write {}: {}", state_filepath.display(), e);
+        }
+    }
+}
+
+// state.jsonを読み込んで書き換える
+fn update<F: FnOnce(&mut State)>(meta_dir: &Path, f: F) {
+    let _guard = match STATE_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut state = load(meta_dir).unwrap_or_default();
+    f(&mut state);
+    save(meta_dir, &state);
+}
+
+// デーモン起動時に呼ぶ (前回の記録はリセットする)
+pub fn start(meta_dir: &Path, dirs: &[String], files: &[String]) {
+    update(meta_dir, |state| {
+        *state = State {
+            pid: std::process::id(),
+            started_at: now(),
+            dirs: dirs.to_vec(),
+            files: files.to_vec(),
+            sources: BTreeMap::new(),
+            last_error: None,
+        };
+    });
+}
+
+// configを読み直したとき
+pub fn set_watched(meta_dir: &Path, dirs: &[String], files: &[String]) {
+    update(meta_dir, |state| {
+        state.dirs = dirs.to_vec();
+        state.files = files.to_vec();
+    });
+}
+
+// 対象ファイルごとの同期結果 (Okなら生成したスニペット名、Errならエラーメッセージ)
+pub struct SyncResult {
+    pub path: String,
+    pub lang: String,
+    pub result: Result<Vec<String>, String>,
+}
+
+// 1回の走査の結果をまとめて記録する (state.jsonは1度だけ書き込む)
+pub fn record_results(meta_dir: &Path, results: Vec<SyncResult>) {
+    if results.is_empty() {
+        return;
+    }
+    update(meta_dir, |state| {
+        for result in results {
+            match result.result {
+                Ok(snippets) => record_sync(state, result.path, result.lang, snippets),
+                Err(message) => record_error(state, result.path, result.lang, message),
+            }
+        }
+    });
+}
+
+// 対象ファイルが削除された・移動されたとき (まとめて1度だけ書き込む)
+pub fn remove_sources(meta_dir: &Path, paths: &[String]) {
+    if paths.is_empty() {
+        return;
+    }
+    update(meta_dir, |state| {
+        for path in paths {
+            state.sources.remove(path);
+        }
+    });
+}
+
+// スニペットの生成に成功したとき
+fn record_sync(state: &mut State, path: String, lang: String, snippets: Vec<String>) {
+    state.sources.insert(
+        path,
+        SourceState {
+            lang: lang,
+            last_sync: now(),
+            snippets: snippets,
+            error: None,
+        },
+    );
+}
+
+// スニペットの生成に失敗したとき (前回の結果は残す)
+fn record_error(state: &mut State, path: String, lang: String, message: String) {
+    let at = now();
+    let source = state.sources.entry(path.clone()).or_default();
+    source.lang = lang;
+    source.error = Some(message.clone());
+
+    state.last_error = Some(ErrorState {
+        path: path,
+        message: message,
+        at: at,
+    });
+}
+
+///// Format
+
+// unix timeを "YYYY-MM-DD hh:mm:ss UTC" に整形する
+pub fn format_time(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rest = secs % 86400;
+
+    // days from civil (Howard Hinnant)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    return format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        rest / 3600,
+        (rest % 3600) / 60,
+        rest % 60
+    );
+}
+
+// statusを人間向けの表に整形する
+pub fn format_report(report: &Report) -> String {
+    let mut text = String::new();
+
+    if report.running {
+        let pid = match report.pid {
+            Some(pid) => pid.to_string(),
+            None => String::from("unknown"),
+        };
+        let since = match &report.state {
+            Some(state) => format!(", since {}", format_time(state.started_at)),
+            None => String::new(),
+        };
+        let paused = if report.paused { " [paused]" } else { "" };
+        text.push_str(&format!(
+            "PortSnippet: running (pid: {}{}){}\n",
+            pid, since, paused
+        ));
+    } else {
+        text.push_str("PortSnippet: not running\n");
+    }
+    text.push_str(&format!("snippets_dir: {}\n", report.snippets_dir));
+
+    let state = match &report.state {
+        Some(state) => state,
+        None => {
+            text.push_str("\nnever synced.\n"); // state.jsonがない
+            return text;
+        }
+    };
+
+    text.push_str("\nwatching:\n");
+    for dir in state.dirs.iter() {
+        text.push_str(&format!("  dir   {}\n", dir));
+    }
+    for file in state.files.iter() {
+        text.push_str(&format!("  file  {}\n", file));
+    }
+
+    text.push_str("\nsources:\n");
+    if state.sources.is_empty() {
+        text.push_str("  (no snippet file synced yet)\n");
+    } else {
+        text.push_str(&format!(
+            "  {:<23}  {:<8}  {:<8}  {}\n",
+            "LAST SYNC", "LANG", "SNIPPETS", "FILE"
+        ));
+        for (path, source) in state.sources.iter() {
+            let last_sync = if source.last_sync == 0 {
+                String::from("-")
+            } else {
+                format_time(source.last_sync)
+            };
+            let snippets = match &source.error {
+                Some(_) => String::from("error"),
+                None => source.snippets.len().to_string(),
+            };
+            text.push_str(&format!(
+                "  {:<23}  {:<8}  {:<8}  {}\n",
+                last_sync, source.lang, snippets, path
+            ));
+        }
+    }
+
+    if let Some(error) = &state.last_error {
+        text.push_str(&format!(
+            "\nlast error: {} ({})\n  {}\n",
+            error.path,
+            format_time(error.at),
+            error.message
+        ));
+    }
+
+    return text;
+}
+
+///// Unit Test
+
+#[cfg(test)]
+mod tests {
+    use crate::state::*;
+
+    fn prepare_meta_dir(name: &str) -> PathBuf {
+        let mut meta_dir = std::env::temp_dir();
+        meta_dir.push(format!(
+            "port_snippet_state_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&meta_dir);
+        std::fs::create_dir_all(&meta_dir).unwrap();
+        return meta_dir;
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn formatTime_epoch_valid() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_time(951782400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_time(1792300521), "2026-10-18 05:15:21 UTC");
+    }
+
+    fn sync_result(path: &str, result: Result<Vec<String>, String>) -> SyncResult {
+        return SyncResult {
+            path: String::from(path),
+            lang: String::from("cpp"),
+            result: result,
+        };
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn recordResults_afterStart_valid() {
+        let meta_dir = prepare_meta_dir("sync");
+        start(&meta_dir, &[String::from("/src")], &[]);
+        record_results(
+            &meta_dir,
+            vec![
+                sync_result("/src/seg.cpp", Ok(vec![String::from("segtree")])),
+                sync_result("/src/uf.cpp", Ok(vec![String::from("unionfind")])),
+            ],
+        );
+
+        let state = load(&meta_dir).unwrap();
+        assert_eq!(state.pid, std::process::id());
+        assert_eq!(state.dirs, vec![String::from("/src")]);
+        assert_eq!(state.sources.len(), 2);
+        assert_eq!(state.sources["/src/seg.cpp"].lang, "cpp");
+        assert_eq!(state.sources["/src/seg.cpp"].snippets, vec!["segtree"]);
+        assert_eq!(state.sources["/src/seg.cpp"].error, None);
+        assert_eq!(state.sources["/src/uf.cpp"].snippets, vec!["unionfind"]);
+        assert_eq!(state.last_error, None);
+
+        std::fs::remove_dir_all(&meta_dir).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn recordResults_errorAfterSync_valid() {
+        let meta_dir = prepare_meta_dir("error");
+        start(&meta_dir, &[], &[String::from("/src/seg.cpp")]);
+        record_results(
+            &meta_dir,
+            vec![sync_result(
+                "/src/seg.cpp",
+                Ok(vec![String::from("segtree")]),
+            )],
+        );
+        record_results(
+            &meta_dir,
+            vec![sync_result(
+                "/src/seg.cpp",
+                Err(String::from("invalid name")),
+            )],
+        );
+
+        let state = load(&meta_dir).unwrap();
+        let source = &state.sources["/src/seg.cpp"];
+        assert_eq!(source.snippets, vec!["segtree"]); // 前回の結果は残す
+        assert_eq!(source.error, Some(String::from("invalid name")));
+
+        let last_error = state.last_error.unwrap();
+        assert_eq!(last_error.path, "/src/seg.cpp");
+        assert_eq!(last_error.message, "invalid name");
+
+        std::fs::remove_dir_all(&meta_dir).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn recordResults_empty_noStateFile() {
+        let meta_dir = prepare_meta_dir("empty");
+        record_results(&meta_dir, vec![]);
+        assert!(!get_state_filepath(&meta_dir).exists());
+
+        std::fs::remove_dir_all(&meta_dir).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn formatReport_notRunning_valid() {
+        let report = Report {
+            running: false,
+            pid: None,
+            paused: false,
+            snippets_dir: String::from("/snippets"),
+            state: None,
+        };
+        let text = format_report(&report);
+
+        assert!(text.contains("PortSnippet: not running"));
+        assert!(text.contains("snippets_dir: /snippets"));
+        assert!(text.contains("never synced."));
+    }
+}
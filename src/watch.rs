@@ -1,23 +1,268 @@
+use super::file;
+use notify::event::{EventKind, ModifyKind, RenameMode};
 use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
 
-pub fn watch_dir<F: FnMut(String)>(paths: Vec<String>, mut f: F) -> notify::Result<()> {
-    let (tx, rx) = std::sync::mpsc::channel();
-    let mut watcher: RecommendedWatcher = Watcher::new_immediate(move |res| tx.send(res).unwrap())?;
-    for path in paths {
-        watcher.watch(path, RecursiveMode::Recursive)?;
+pub type EventReceiver = Receiver<notify::Result<notify::Event>>;
+
+// 監視しているファイルに起きたこと
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileEvent {
+    Created(PathBuf),
+    Modified(PathBuf),
+    Removed(PathBuf),
+    Renamed { from: PathBuf, to: PathBuf },
+}
+
+// 監視対象をあとから追加・削除できるwatcher
+pub struct PathWatcher {
+    watcher: RecommendedWatcher,
+    watched: HashSet<String>,
+    roots: Vec<PathBuf>, // watchedのそれぞれの書き方 (イベントのパスと比べる)
+}
+
+impl PathWatcher {
+    pub fn new() -> notify::Result<(PathWatcher, EventReceiver)> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let watcher: RecommendedWatcher = Watcher::new_immediate(move |res| {
+            let _ = tx.send(res);
+        })?;
+
+        let path_watcher = PathWatcher {
+            watcher: watcher,
+            watched: HashSet::new(),
+            roots: vec![],
+        };
+        return Ok((path_watcher, rx));
+    }
+
+    // 監視対象をpathsに合わせる
+    // 新しく監視を始めたパスを返す
+    pub fn update(&mut self, paths: &[String]) -> Vec<String> {
+        let next: HashSet<String> = paths.iter().cloned().collect();
+
+        // 消えたパス
+        let removed: Vec<String> = self.watched.difference(&next).cloned().collect();
+        for path in removed {
+            if let Err(e) = self.watcher.unwatch(&path) {
+                println!("cannot unwatch {}: {:?}", path, e);
+            }
+            self.watched.remove(&path);
+        }
+
+        // 増えたパス (configの順番を保つ)
+        let mut added = vec![];
+        for path in paths {
+            if self.watched.contains(path) {
+                continue;
+            }
+            match self.watcher.watch(path, RecursiveMode::Recursive) {
+                Ok(()) => {
+                    self.watched.insert(path.clone());
+                    added.push(path.clone());
+                }
+                Err(e) => println!("cannot watch {}: {:?}", path, e),
+            }
+        }
+
+        self.roots = self
+            .watched
+            .iter()
+            .flat_map(|path| file::path_forms(path))
+            .collect();
+        return added;
     }
 
+    // 監視しているフォルダ・ファイルの下のパスか
+    pub fn is_watched(&self, path: &Path) -> bool {
+        return self.roots.iter().any(|root| path.starts_with(root));
+    }
+
+    // フォルダ直下だけを監視する (config.json / lang.json 用)
+    pub fn watch_shallow(&mut self, dir: &Path) -> notify::Result<()> {
+        return self.watcher.watch(dir, RecursiveMode::NonRecursive);
+    }
+}
+
+// イベントを待ち受けて、ファイルごとのイベントにしてfに渡す
+pub fn listen<F: FnMut(FileEvent)>(rx: EventReceiver, mut f: F) {
     for res in rx {
         match res {
             Ok(event) => {
-                if event.paths.len() > 0 && event.kind.is_modify() {
-                    let code_filepath = event.paths[0].to_str().unwrap().to_string();
-                    f(code_filepath);
+                for file_event in to_file_events(&event) {
+                    f(file_event);
                 }
             }
             Err(e) => println!("watch error: {:?}", e),
         }
     }
+}
 
-    Ok(())
+// notifyのイベントをファイルごとのイベントに直す
+// 名前の変更は、移動元・移動先が揃っていればRenamed、片方だけならRemoved / Createdにする
+// (inotifyは片方ずつのイベントと、両方揃ったイベントの両方を送ってくる)
+pub fn to_file_events(event: &notify::Event) -> Vec<FileEvent> {
+    let paths = event.paths.iter().cloned();
+    return match &event.kind {
+        EventKind::Create(_) => paths.map(FileEvent::Created).collect(),
+        EventKind::Remove(_) => paths.map(FileEvent::Removed).collect(),
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+            vec![FileEvent::Renamed {
+                from: event.paths[0].clone(),
+                to: event.paths[1].clone(),
+            }]
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+            paths.map(FileEvent::Removed).collect()
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+            paths.map(FileEvent::Created).collect()
+        }
+        // どちら側か分からない (FSEventsなど) ので、今あるかどうかで決める
+        EventKind::Modify(ModifyKind::Name(_)) => paths
+            .map(|path| {
+                if path.exists() {
+                    FileEvent::Created(path)
+                } else {
+                    FileEvent::Removed(path)
+                }
+            })
+            .collect(),
+        EventKind::Modify(_) => paths.map(FileEvent::Modified).collect(),
+        _ => vec![], // Access / Other / Any
+    };
+}
+
+///// Unit Test
+
+#[cfg(test)]
+mod tests {
+    use crate::watch::*;
+
+    fn prepare_dirs(name: &str, count: usize) -> Vec<String> {
+        let mut root = std::env::temp_dir();
+        root.push(format!(
+            "port_snippet_watch_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+
+        let mut dirs = vec![];
+        for i in 0..count {
+            let dir = root.join(format!("dir{}", i));
+            std::fs::create_dir_all(&dir).unwrap();
+            dirs.push(dir.to_str().unwrap().to_string());
+        }
+        return dirs;
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn update_addAndRemove_valid() {
+        let dirs = prepare_dirs("update", 3);
+        let (mut watcher, _rx) = PathWatcher::new().unwrap();
+
+        let added = watcher.update(&dirs[0..2]);
+        assert_eq!(added, dirs[0..2].to_vec());
+
+        let added = watcher.update(&dirs[1..3]);
+        assert_eq!(added, vec![dirs[2].clone()]);
+        assert!(watcher.is_watched(Path::new(&dirs[1])));
+        assert!(watcher.is_watched(&Path::new(&dirs[2]).join("a.cpp")));
+        assert!(!watcher.is_watched(Path::new(&dirs[0])));
+
+        drop(watcher);
+        std::fs::remove_dir_all(Path::new(&dirs[0]).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn update_missingPath_skipped() {
+        let dirs = prepare_dirs("missing", 1);
+        let (mut watcher, _rx) = PathWatcher::new().unwrap();
+
+        let missing = format!("{}_not_found", dirs[0]);
+        let added = watcher.update(&[dirs[0].clone(), missing.clone()]);
+        assert_eq!(added, vec![dirs[0].clone()]);
+        assert!(!watcher.is_watched(Path::new(&missing)));
+
+        drop(watcher);
+        std::fs::remove_dir_all(Path::new(&dirs[0]).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn isWatched_relativeDir_valid() {
+        // cargo testはクレートのフォルダで動くので、targetの下を相対パスで指定する
+        let relative = format!("target/port_snippet_watch_relative_{}", std::process::id());
+        std::fs::create_dir_all(&relative).unwrap();
+        let (mut watcher, _rx) = PathWatcher::new().unwrap();
+
+        let added = watcher.update(&[format!("./{}", relative)]);
+        assert_eq!(added.len(), 1);
+        // イベントのパスは絶対パス
+        let root = std::fs::canonicalize(&relative).unwrap();
+        assert!(watcher.is_watched(&root.join("a.cpp")));
+        assert!(!watcher.is_watched(&root.with_extension("other").join("a.cpp")));
+
+        std::fs::remove_dir_all(&relative).unwrap();
+    }
+
+    #[test]
+    #[allow(non_snake_case)]
+    fn toFileEvents_valid() {
+        use notify::event::{CreateKind, DataChange, RemoveKind};
+        let path = |name: &str| PathBuf::from(format!("/src/{}", name));
+        let event = |kind: EventKind, names: &[&str]| {
+            let mut event = notify::Event::new(kind);
+            for name in names {
+                event = event.add_path(path(name));
+            }
+            return to_file_events(&event);
+        };
+
+        assert_eq!(
+            event(EventKind::Create(CreateKind::File), &["a.cpp"]),
+            vec![FileEvent::Created(path("a.cpp"))]
+        );
+        assert_eq!(
+            event(
+                EventKind::Modify(ModifyKind::Data(DataChange::Content)),
+                &["a.cpp"]
+            ),
+            vec![FileEvent::Modified(path("a.cpp"))]
+        );
+        assert_eq!(
+            event(EventKind::Remove(RemoveKind::File), &["a.cpp"]),
+            vec![FileEvent::Removed(path("a.cpp"))]
+        );
+        assert_eq!(
+            event(
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+                &["a.cpp", "b.cpp"]
+            ),
+            vec![FileEvent::Renamed {
+                from: path("a.cpp"),
+                to: path("b.cpp"),
+            }]
+        );
+        assert_eq!(
+            event(
+                EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+                &["a.cpp"]
+            ),
+            vec![FileEvent::Removed(path("a.cpp"))]
+        );
+        assert_eq!(
+            event(
+                EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+                &["b.cpp"]
+            ),
+            vec![FileEvent::Created(path("b.cpp"))]
+        );
+        assert!(event(EventKind::Any, &["a.cpp"]).is_empty());
+    }
 }